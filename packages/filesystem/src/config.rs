@@ -0,0 +1,370 @@
+use crate::fs::{FileDetectorConfig, PublishConfig};
+use figment::{
+    Figment,
+    providers::{Env, Format, Serialized, Toml},
+};
+use rabbit_eye::{
+    engine::EngineConfig,
+    rabbit::{ConnectionOptions, ConnectionOptionsError, read_secret_file},
+    time::{ScheduleMode, ScheduleOptions, ScheduleOptionsError},
+};
+use serde::Deserialize;
+use std::{io, path::PathBuf, time::Duration};
+
+/// The environment variable naming the TOML file the `Config` is loaded from.
+pub const CONFIG_VAR: &str = "RABBIT_EYE_CONFIG";
+
+/// Every setting of the app, loaded from a TOML file and the environment.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub connection: ConnectionConfig,
+    pub schedule: ScheduleConfig,
+    pub shutdown: ShutdownConfig,
+    pub detector: DetectorConfig,
+    pub sink: PublishConfig,
+}
+
+impl Config {
+    /// Loads the TOML file named by `RABBIT_EYE_CONFIG`, if it is set and the file exists,
+    /// then layers the environment on top. Each setting can be overridden by a variable named
+    /// for its section and key, such as `RABBIT_EYE_SCHEDULE__INTERVAL_SECS`, and the
+    /// connection is also read from the usual `RABBITMQ_*` variables, including the
+    /// `RABBITMQ_USER_FILE` and `RABBITMQ_PASS_FILE` secrets.
+    pub fn load() -> Result<Self, figment::Error> {
+        let mut figment = Figment::new();
+        if let Some(path) = std::env::var_os(CONFIG_VAR) {
+            figment = figment.merge(Toml::file(path));
+        }
+
+        // Taken verbatim, since a password of digits would otherwise parse as a number.
+        for key in ["url", "host", "user", "pass", "vhost"] {
+            let name = format!("RABBITMQ_{}", key.to_uppercase());
+            let file_name = format!("{}_FILE", name);
+            let value = match std::env::var_os(&file_name) {
+                Some(path) if key == "user" || key == "pass" => {
+                    Some(read_secret_file(path).map_err(|e| {
+                        figment::Error::from(format!("{} could not be read: {}", file_name, e))
+                    })?)
+                }
+                _ => std::env::var(&name).ok(),
+            };
+            if let Some(value) = value {
+                figment = figment.merge(Serialized::default(&format!("connection.{}", key), value));
+            }
+        }
+
+        figment
+            .merge(
+                Env::raw()
+                    .only(&["RABBITMQ_PORT"])
+                    .map(|_| "connection.port".into()),
+            )
+            .merge(Env::prefixed("RABBIT_EYE_").ignore(&["CONFIG"]).split("__"))
+            .extract()
+    }
+
+    /// The engine settings: its schedule and how long it waits for the work when it stops.
+    /// Fails if the interval is zero.
+    pub fn engine(&self) -> Result<EngineConfig, ScheduleOptionsError> {
+        let mut config = EngineConfig::default();
+        config
+            .with_schedule(self.schedule.options()?)
+            .with_grace_period(Duration::from_secs(self.shutdown.grace_period_secs))
+            .with_shutdown_timeouts(
+                Duration::from_secs(self.shutdown.natural_timeout_secs),
+                Duration::from_secs(self.shutdown.graceful_timeout_secs),
+            )
+            .with_source("filesystem");
+        Ok(config)
+    }
+
+    /// The change detector settings. Without any roots, they are read from
+    /// `RABBIT_EYE_WATCH_PATHS` as by `FileDetectorConfig::read_from_env`.
+    pub fn detector(&self) -> io::Result<FileDetectorConfig> {
+        let roots = if self.detector.roots.is_empty() {
+            FileDetectorConfig::read_from_env()?.roots
+        } else {
+            self.detector.roots.clone()
+        };
+
+        Ok(FileDetectorConfig {
+            roots,
+            recursive: self.detector.recursive,
+            include_child_changes: self.detector.include_child_changes,
+        })
+    }
+}
+
+/// How to connect to RabbitMQ, either by `url` or by its separate parts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// An AMQP URI, which takes precedence over the other settings.
+    pub url: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+    pub vhost: Option<String>,
+}
+
+impl ConnectionConfig {
+    /// Fails if there is no `url` and the host, user, or password is missing.
+    pub fn options(&self) -> Result<ConnectionOptions, ConnectionOptionsError> {
+        if let Some(url) = &self.url {
+            return ConnectionOptions::from_amqp_uri(url);
+        }
+
+        let mut builder = ConnectionOptions::builder();
+        if let Some(host) = &self.host {
+            builder.host(host);
+        }
+        if let Some(port) = self.port {
+            builder.port(port);
+        }
+        if let Some(user) = &self.user {
+            builder.user(user);
+        }
+        if let Some(pass) = &self.pass {
+            builder.pass(pass);
+        }
+        if let Some(vhost) = &self.vhost {
+            builder.vhost(vhost);
+        }
+        builder.build()
+    }
+}
+
+/// How often the change detector runs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    pub interval_secs: u64,
+    pub jitter_secs: u64,
+    pub mode: ScheduleMode,
+    pub run_immediately: bool,
+}
+
+impl ScheduleConfig {
+    /// Fails if the interval is zero.
+    pub fn options(&self) -> Result<ScheduleOptions, ScheduleOptionsError> {
+        let mut options = ScheduleOptions::builder()
+            .interval(Duration::from_secs(self.interval_secs))
+            .jitter(Duration::from_secs(self.jitter_secs))
+            .mode(self.mode)
+            .build()?;
+        options.with_run_immediately(self.run_immediately);
+        Ok(options)
+    }
+}
+
+impl Default for ScheduleConfig {
+    /// Runs every 5 seconds, starting immediately.
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            jitter_secs: 0,
+            mode: ScheduleMode::default(),
+            run_immediately: true,
+        }
+    }
+}
+
+/// How long the app waits for the work to stop at each stage of stopping. See
+/// `EngineConfig::with_shutdown_timeouts` and `EngineConfig::with_grace_period`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub natural_timeout_secs: u64,
+    pub graceful_timeout_secs: u64,
+    pub grace_period_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    /// Waits 5 seconds at each stage.
+    fn default() -> Self {
+        Self {
+            natural_timeout_secs: 5,
+            graceful_timeout_secs: 5,
+            grace_period_secs: 5,
+        }
+    }
+}
+
+/// Which paths the change detector inspects, and how it finds their changes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct DetectorConfig {
+    pub roots: Vec<PathBuf>,
+    pub recursive: bool,
+    pub include_child_changes: bool,
+    pub mode: DetectorMode,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            roots: vec![],
+            recursive: true,
+            include_child_changes: true,
+            mode: DetectorMode::default(),
+        }
+    }
+}
+
+/// How the change detector finds changes.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectorMode {
+    /// Walks the roots on the schedule.
+    #[default]
+    Poll,
+    /// Publishes changes as the operating system notifies them, after walking the roots once to
+    /// reconcile. See `FileWatchDetector`.
+    #[cfg(feature = "watch")]
+    Watch,
+}
+
+#[cfg(test)]
+mod test_config {
+    #[cfg(feature = "watch")]
+    use super::DetectorMode;
+    use super::{CONFIG_VAR, Config};
+    use figment::Jail;
+    use rabbit_eye::time::ScheduleMode;
+    use std::{path::PathBuf, time::Duration};
+
+    const SAMPLE: &str = r#"
+        [connection]
+        host = "rabbit.internal"
+        user = "watcher"
+        pass = "secret"
+
+        [schedule]
+        interval_secs = 60
+        mode = "fixed_delay"
+
+        [shutdown]
+        graceful_timeout_secs = 30
+
+        [detector]
+        roots = ["/srv/share"]
+        recursive = false
+
+        [sink]
+        exchange = "files"
+        routing_key_template = "files.{change_type}"
+    "#;
+
+    #[test]
+    fn loads_sample_file() {
+        Jail::expect_with(|jail| {
+            jail.create_file("rabbit-eye.toml", SAMPLE)?;
+            jail.set_env(CONFIG_VAR, "rabbit-eye.toml");
+
+            let config = Config::load()?;
+            assert_eq!(Some("rabbit.internal"), config.connection.host.as_deref());
+            assert_eq!(60, config.schedule.interval_secs);
+            assert_eq!(ScheduleMode::FixedDelay, config.schedule.mode);
+            assert!(config.schedule.run_immediately);
+            assert_eq!(vec![PathBuf::from("/srv/share")], config.detector.roots);
+            assert!(!config.detector.recursive);
+            assert!(config.detector.include_child_changes);
+            assert_eq!("files", config.sink.exchange);
+            assert!(config.sink.persistent);
+
+            let schedule = config.schedule.options().unwrap();
+            assert_eq!(Duration::from_secs(60), schedule.interval());
+            let engine = config.engine().unwrap();
+            assert_eq!(Duration::from_secs(60), engine.interval());
+            assert_eq!(Duration::from_secs(5), engine.natural_timeout());
+            assert_eq!(Duration::from_secs(30), engine.graceful_timeout());
+            assert_eq!(Duration::from_secs(5), engine.grace_period());
+            assert_eq!("filesystem", engine.source());
+            let connection = config.connection.options().unwrap();
+            assert_eq!("/", connection.vhost());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        Jail::expect_with(|jail| {
+            jail.create_file("rabbit-eye.toml", SAMPLE)?;
+            jail.set_env(CONFIG_VAR, "rabbit-eye.toml");
+            jail.set_env("RABBIT_EYE_SCHEDULE__INTERVAL_SECS", "15");
+            jail.set_env("RABBIT_EYE_SINK__EXCHANGE", "audit");
+            jail.set_env("RABBITMQ_HOST", "localhost");
+            jail.set_env("RABBITMQ_PASS", "1234");
+
+            let config = Config::load()?;
+            assert_eq!(15, config.schedule.interval_secs);
+            assert_eq!(ScheduleMode::FixedDelay, config.schedule.mode);
+            assert_eq!("audit", config.sink.exchange);
+            assert_eq!(Some("localhost"), config.connection.host.as_deref());
+            assert_eq!(Some("1234"), config.connection.pass.as_deref());
+            assert_eq!(Some("watcher"), config.connection.user.as_deref());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn pass_file_overrides_pass() {
+        Jail::expect_with(|jail| {
+            jail.create_file("rabbitmq-pass", "from-file\n")?;
+            jail.set_env("RABBITMQ_PASS", "inline");
+            jail.set_env("RABBITMQ_PASS_FILE", "rabbitmq-pass");
+
+            let config = Config::load()?;
+            assert_eq!(Some("from-file"), config.connection.pass.as_deref());
+
+            jail.set_env("RABBITMQ_PASS_FILE", "missing");
+            assert!(Config::load().is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn missing_file_falls_back_to_env() {
+        Jail::expect_with(|jail| {
+            jail.set_env(CONFIG_VAR, "missing.toml");
+            jail.set_env("RABBITMQ_URL", "amqp://broker/");
+
+            let config = Config::load()?;
+            assert_eq!(Some("amqp://broker/"), config.connection.url.as_deref());
+            assert_eq!(5, config.schedule.interval_secs);
+            assert!(config.detector.roots.is_empty());
+            assert!(config.connection.options().is_ok());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn zero_interval_is_rejected() {
+        Jail::expect_with(|jail| {
+            jail.set_env("RABBIT_EYE_SCHEDULE__INTERVAL_SECS", "0");
+
+            let config = Config::load()?;
+            assert!(config.schedule.options().is_err());
+            Ok(())
+        });
+    }
+
+    #[cfg(feature = "watch")]
+    #[test]
+    fn watch_mode_is_selected() {
+        Jail::expect_with(|jail| {
+            jail.create_file("rabbit-eye.toml", SAMPLE)?;
+            jail.set_env(CONFIG_VAR, "rabbit-eye.toml");
+
+            let config = Config::load()?;
+            assert_eq!(DetectorMode::Poll, config.detector.mode);
+
+            jail.set_env("RABBIT_EYE_DETECTOR__MODE", "watch");
+            let config = Config::load()?;
+            assert_eq!(DetectorMode::Watch, config.detector.mode);
+            Ok(())
+        });
+    }
+}
@@ -1,13 +1,46 @@
-use crate::sync::CancellationToken;
 use amqprs::{
     BasicProperties,
     channel::{BasicPublishArguments, Channel},
 };
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    Match,
+    gitignore::{Gitignore, GitignoreBuilder},
+};
+use rabbit_eye::rabbit::{RabbitMq, RabbitSink};
 use rabbit_eye::state::{
-    ChangeDetector, ChangeDetectorResult, DefaultTableState, StateChange, TableState,
+    ChangeDetector, ChangeDetectorResult, StateChange, TableState, fold_table_hash,
+};
+use rabbit_eye::sync::CancellationToken;
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+#[cfg(windows)]
+use std::os::windows::fs::MetadataExt;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::UNIX_EPOCH,
 };
-use std::hash::{Hash, Hasher};
-use std::{error::Error, os::windows::fs::MetadataExt, path::PathBuf};
+use tokio::{
+    fs::{DirEntry, ReadDir},
+    io::AsyncReadExt,
+    select,
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc},
+    task::JoinSet,
+};
+
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "watch")]
+pub use watch::*;
 
 pub async fn check_and_report_files(
     channel: &Channel,
@@ -15,11 +48,11 @@ pub async fn check_and_report_files(
     state: &mut impl TableState<String, u64>,
 ) -> Result<(), Box<dyn Error>> {
     tokio::time::sleep(std::time::Duration::from_secs(15)).await;
-    let root = PathBuf::from(std::env::current_dir()?);
-    let mut changedetector = FileChangeDetector::new(root)
-        .with_recursive(true)
-        .with_child_changes(true)
-        .build();
+    let Some(mut changedetector) = FileDetectorConfig::read_from_env()?.detector() else {
+        eprintln!("None of the watch paths exist.");
+        return Ok(());
+    };
+    let publish = PublishConfig::read_from_env();
 
     if let Some(former) = state.tablehash()
         && let Some(current) = changedetector.tablehash(&cancel).await
@@ -29,37 +62,49 @@ pub async fn check_and_report_files(
         return Ok(());
     }
 
-    let changes = changedetector.rowhash(&mut *state, &cancel).await;
+    let changes = changedetector.build().rowhash(&mut *state, &cancel).await;
+
+    if let ChangeDetectorResult::Faulted(e) = &changes {
+        eprintln!("The change detector faulted. {}", e);
+    }
 
-    let delete_remainder = match changes {
-        ChangeDetectorResult::Aborted => return Ok(()),
-        ChangeDetectorResult::Cancelled => false,
-        ChangeDetectorResult::DeleteRemainder => true,
-        ChangeDetectorResult::Faulted(_) => false,
+    let Some(delete_remainder) = changes.delete_remainder() else {
+        return Ok(());
     };
 
+    publish_changes(
+        channel,
+        &publish,
+        &changedetector,
+        state.drain_hashed(delete_remainder),
+    )
+    .await
+}
+
+/// Publishes each change and its row hash to the message bus as a JSON `FileChangeEvent`,
+/// described with the current metadata of its path.
+pub async fn publish_changes(
+    channel: &Channel,
+    publish: &PublishConfig,
+    detector: &FileChangeDetector,
+    changes: impl Iterator<Item = (StateChange<String>, Option<u64>)>,
+) -> Result<(), Box<dyn Error>> {
     let mut new = 0;
     let mut del = 0;
     let mut upd = 0;
-    for change in state.drain(delete_remainder) {
-        let val = match change {
-            StateChange::New(val) => {
-                new += 1;
-                val
-            }
-            StateChange::Update(val) => {
-                upd += 1;
-                val
-            }
-            StateChange::Delete(val) => {
-                del += 1;
-                val
-            }
-        };
+    for (change, hash) in changes {
+        match change {
+            StateChange::New(_) => new += 1,
+            StateChange::Update(_) => upd += 1,
+            StateChange::Delete(_) => del += 1,
+        }
 
-        let publish_args = BasicPublishArguments::new("", "rabbit-eye-dev");
+        let event = detector.describe(change, hash).await;
+        let properties = publish.properties();
+        let publish_args =
+            BasicPublishArguments::new(&publish.exchange, &publish.routing_key(&event));
         channel
-            .basic_publish(BasicProperties::default(), val.into_bytes(), publish_args)
+            .basic_publish(properties, event.to_body()?, publish_args)
             .await?;
     }
 
@@ -68,30 +113,324 @@ pub async fn check_and_report_files(
     Ok(())
 }
 
-pub struct FileChange {
+/// The kind of change to a path, from the `StateChange` variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeType {
+    New,
+    Update,
+    Delete,
+}
+
+impl ChangeType {
+    /// The change type in lowercase, as it appears in routing keys.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::New => "new",
+            ChangeType::Update => "update",
+            ChangeType::Delete => "delete",
+        }
+    }
+}
+
+/// Where each `FileChangeEvent` is published.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct PublishConfig {
+    pub exchange: String,
+    /// The routing key of each message. `{change_type}` is replaced by the change type in
+    /// lowercase, and `{file_name}` by the last segment of the changed path.
+    pub routing_key_template: String,
+    /// Whether the broker keeps messages on disk, so they survive it restarting.
+    pub persistent: bool,
+    pub content_type: String,
+    /// How the body is encoded on top of its content type, such as `gzip`.
+    pub content_encoding: Option<String>,
+}
+
+impl PublishConfig {
+    /// Reads the exchange from `RABBIT_EYE_EXCHANGE` and the routing key template from
+    /// `RABBIT_EYE_ROUTING_KEY`, using the defaults for either that is not set.
+    pub fn read_from_env() -> Self {
+        let default = Self::default();
+        Self {
+            exchange: std::env::var("RABBIT_EYE_EXCHANGE").unwrap_or(default.exchange),
+            routing_key_template: std::env::var("RABBIT_EYE_ROUTING_KEY")
+                .unwrap_or(default.routing_key_template),
+            ..default
+        }
+    }
+
+    pub fn with_persistent(&mut self, persistent: bool) -> &mut Self {
+        self.persistent = persistent;
+        self
+    }
+
+    pub fn with_content_type(&mut self, content_type: impl Into<String>) -> &mut Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    pub fn with_content_encoding(&mut self, content_encoding: impl Into<String>) -> &mut Self {
+        self.content_encoding = Some(content_encoding.into());
+        self
+    }
+
+    pub fn build(&self) -> Self {
+        self.clone()
+    }
+
+    /// The properties of each published message.
+    pub fn properties(&self) -> BasicProperties {
+        let mut properties = BasicProperties::default();
+        properties
+            .with_delivery_mode(if self.persistent { 2 } else { 1 })
+            .with_content_type(&self.content_type);
+        if let Some(content_encoding) = &self.content_encoding {
+            properties.with_content_encoding(content_encoding);
+        }
+        properties.finish()
+    }
+
+    /// Renders the routing key template for `event`.
+    pub fn routing_key(&self, event: &FileChangeEvent) -> String {
+        render_routing_key(
+            &self.routing_key_template,
+            event.change_type.as_str(),
+            &event.path,
+        )
+    }
+
+    /// A sink publishing each change through `rabbit` to the exchange, routed by the routing
+    /// key template, with the message properties.
+    pub fn sink(&self, rabbit: RabbitMq) -> RabbitSink {
+        let template = self.routing_key_template.clone();
+        let mut sink = RabbitSink::new(rabbit, &self.exchange, &template);
+        sink.with_properties(self.properties())
+            .with_route(move |change_type, path| render_routing_key(&template, change_type, path));
+        sink
+    }
+}
+
+/// Renders `template` for a change of `change_type` to `path`.
+fn render_routing_key(template: &str, change_type: &str, path: &str) -> String {
+    let file_name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    template
+        .replace("{change_type}", change_type)
+        .replace("{file_name}", &file_name)
+}
+
+impl Default for PublishConfig {
+    /// Publishes persistent JSON messages to the `rabbit-eye-dev` queue through the default
+    /// exchange.
+    fn default() -> Self {
+        Self {
+            exchange: String::new(),
+            routing_key_template: "rabbit-eye-dev".to_string(),
+            persistent: true,
+            content_type: "application/json".to_string(),
+            content_encoding: None,
+        }
+    }
+}
+
+/// The message published for each changed path.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileChangeEvent {
     pub path: String,
-    pub last_write_utc: u64,
+    pub change_type: ChangeType,
+    /// The length of the file in bytes, or `None` for directories and deleted paths.
+    pub size: Option<u64>,
+    /// The last write time in seconds since the Unix epoch.
+    pub modified_unix: Option<u64>,
+    /// The hash of the file's contents when the detector hashes content, unless it also hashes
+    /// permissions.
+    pub content_hash: Option<u64>,
+    /// The row hash recorded for the path, which for a delete is the last hash seen before the
+    /// path vanished.
+    pub hash: Option<u64>,
+}
+
+impl FileChangeEvent {
+    /// Serializes the event as the JSON body of a message.
+    pub fn to_body(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+/// Configures which directories the filesystem detector inspects.
+#[derive(Clone, Debug)]
+pub struct FileDetectorConfig {
+    pub roots: Vec<PathBuf>,
+    pub recursive: bool,
+    pub include_child_changes: bool,
+}
+
+impl FileDetectorConfig {
+    /// Reads the roots from `RABBIT_EYE_WATCH_PATHS`, which is separated like `PATH` (`;` on
+    /// Windows and `:` elsewhere). Without it, the current directory is inspected.
+    pub fn read_from_env() -> io::Result<Self> {
+        let roots = match std::env::var_os("RABBIT_EYE_WATCH_PATHS") {
+            Some(paths) => std::env::split_paths(&paths)
+                .filter(|path| !path.as_os_str().is_empty())
+                .collect(),
+            None => vec![std::env::current_dir()?],
+        };
+
+        Ok(Self {
+            roots,
+            recursive: true,
+            include_child_changes: true,
+        })
+    }
+
+    /// Resolves the roots to absolute paths, skipping any that do not exist and any that are
+    /// already inspected through another root, so no entry is reported twice.
+    pub fn resolve_roots(&self) -> Vec<PathBuf> {
+        let mut resolved = vec![];
+        for root in &self.roots {
+            let canonical = match std::fs::canonicalize(root) {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    eprintln!("The watch path {} was skipped. {}", root.display(), e);
+                    continue;
+                }
+            };
+            let absolute = std::path::absolute(root).unwrap_or_else(|_| canonical.clone());
+            resolved.push((canonical, absolute));
+        }
+
+        // A directory sorts before its descendants, so any root containing another is kept first.
+        resolved.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut roots: Vec<(PathBuf, PathBuf)> = vec![];
+        for (canonical, absolute) in resolved {
+            let covered = roots.iter().any(|(kept, _)| {
+                canonical == *kept || self.recursive && canonical.starts_with(kept)
+            });
+            if !covered {
+                roots.push((canonical, absolute));
+            }
+        }
+
+        roots.into_iter().map(|(_, absolute)| absolute).collect()
+    }
+
+    /// Creates a detector over the resolved roots, or `None` if none of them exist.
+    pub fn detector(&self) -> Option<FileChangeDetector> {
+        let mut roots = self.resolve_roots().into_iter();
+        let mut detector = FileChangeDetector::new(roots.next()?);
+        for root in roots {
+            detector.with_root(root);
+        }
+        detector
+            .with_recursive(self.recursive)
+            .with_child_changes(self.include_child_changes);
+        Some(detector)
+    }
 }
 
 #[derive(Clone)]
 pub struct FileChangeDetector {
-    /// The root directory to begin inspection.
-    root: PathBuf,
+    /// The root directories to begin inspection, whose entries are reported together.
+    roots: Vec<PathBuf>,
     /// Also check the directories within any given directory.
     recursive: bool,
     /// Consider a directory as modified if a child of the directory was modified.
     include_child_changes: bool,
+    /// The maximum number of directories that may be read at the same time.
+    max_concurrency: usize,
+    /// Hash the contents of each file rather than its last write time.
+    content_hash: bool,
+    /// Also hash the permissions of each entry.
+    permissions: bool,
+    /// Also hash the length of each file whose contents are not hashed.
+    size: bool,
+    /// When set, only entries matching these globs are reported.
+    include: Option<GlobSet>,
+    /// Entries matching these globs are neither reported nor descended into.
+    exclude: Option<GlobSet>,
+    /// Skip entries ignored by the `.gitignore` files found during traversal.
+    gitignore: bool,
+    /// The deepest level to descend to when recursive, where `0` is the root's children.
+    max_depth: Option<usize>,
+    /// How symbolic links are treated.
+    symlinks: SymlinkPolicy,
+    /// Whether hidden entries are reported and descended into.
+    hidden: bool,
+    /// What to do when a path cannot be read.
+    error_policy: ErrorPolicy,
+}
+
+/// Describes how `FileChangeDetector` handles a path that cannot be read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Log and skip the path, continuing the rest of the traversal. The traversal still faults
+    /// once it completes, so rows beneath the skipped paths are not deleted.
+    #[default]
+    SkipAndContinue,
+    /// Fault the traversal as soon as a path cannot be read.
+    FailFast,
+}
+
+/// The paths that were skipped by a traversal under `ErrorPolicy::SkipAndContinue`.
+#[derive(Debug)]
+pub struct SkippedPaths {
+    pub errors: Vec<io::Error>,
+}
+
+impl std::fmt::Display for SkippedPaths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} path(s) could not be read", self.errors.len())?;
+        if let Some(first) = self.errors.first() {
+            write!(f, ", the first: {}", first)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SkippedPaths {}
+
+/// Describes how `FileChangeDetector` treats symbolic links.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Do not report symbolic links.
+    Skip,
+    /// Report the link using the metadata of its target, but do not descend into it.
+    #[default]
+    HashTarget,
+    /// Treat the link as its target, descending into linked directories. Each directory is
+    /// only visited once, so cycles created by links terminate.
+    Follow,
 }
 
 impl FileChangeDetector {
     pub fn new(root: PathBuf) -> Self {
         Self {
-            root,
+            roots: vec![root],
             recursive: false,
             include_child_changes: false,
+            max_concurrency: 1,
+            content_hash: false,
+            permissions: false,
+            size: false,
+            include: None,
+            exclude: None,
+            gitignore: false,
+            max_depth: None,
+            symlinks: SymlinkPolicy::default(),
+            hidden: false,
+            error_policy: ErrorPolicy::default(),
         }
     }
 
+    /// Also inspects `root`, reporting its entries into the same state.
+    pub fn with_root(&mut self, root: PathBuf) -> &mut Self {
+        self.roots.push(root);
+        self
+    }
+
     pub fn with_recursive(&mut self, recursive: bool) -> &mut Self {
         self.recursive = recursive;
         self
@@ -102,6 +441,91 @@ impl FileChangeDetector {
         self
     }
 
+    /// Reads up to `max_concurrency` directories at once. A value of `1` (the default) reads
+    /// the tree serially.
+    pub fn with_max_concurrency(&mut self, max_concurrency: usize) -> &mut Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// Hashes the bytes of each file instead of using its last write time, so rewrites that
+    /// preserve the timestamp are detected and touching a file is not. Directories still use
+    /// their last write time.
+    pub fn with_content_hash(&mut self, content_hash: bool) -> &mut Self {
+        self.content_hash = content_hash;
+        self
+    }
+
+    /// Also hashes the permissions of each entry, so a change to them alone is reported as an
+    /// update: the mode on Unix, and the attributes other than archive on Windows, which does
+    /// not expose the entry's ACL. The row hash of a file is then no longer its content hash,
+    /// so change events do not carry one.
+    pub fn with_permissions(&mut self, permissions: bool) -> &mut Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Also hashes the length of each file, so a write that leaves the last write time as it
+    /// was, such as on a filesystem with coarse timestamps, is still reported when it changes
+    /// the length. Files whose contents are hashed already reflect their length, so this only
+    /// applies without `with_content_hash`.
+    pub fn with_size(&mut self, size: bool) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    /// Only reports entries whose path relative to the root matches one of `globs`.
+    /// Directories that do not match are still descended into so their children can match.
+    pub fn with_include<I, G>(&mut self, globs: I) -> Result<&mut Self, globset::Error>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<str>,
+    {
+        self.include = Some(build_globset(globs)?);
+        Ok(self)
+    }
+
+    /// Skips entries whose path relative to the root matches one of `globs`. Excluded
+    /// directories are not descended into. Excludes take precedence over includes.
+    pub fn with_exclude<I, G>(&mut self, globs: I) -> Result<&mut Self, globset::Error>
+    where
+        I: IntoIterator<Item = G>,
+        G: AsRef<str>,
+    {
+        self.exclude = Some(build_globset(globs)?);
+        Ok(self)
+    }
+
+    /// Honors the `.gitignore` files found during traversal, as git would: ignored entries are
+    /// neither reported nor descended into, and a nested `.gitignore` takes precedence over
+    /// those above it. The `.git` directory itself is also skipped.
+    pub fn with_gitignore(&mut self, gitignore: bool) -> &mut Self {
+        self.gitignore = gitignore;
+        self
+    }
+
+    /// Limits how deep a recursive traversal descends. A depth of `0` lists only the root's
+    /// direct children; directories at the limit are still listed but not descended into.
+    pub fn with_max_depth(&mut self, max_depth: usize) -> &mut Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn with_symlinks(&mut self, symlinks: SymlinkPolicy) -> &mut Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn with_hidden(&mut self, hidden: bool) -> &mut Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub fn with_error_policy(&mut self, error_policy: ErrorPolicy) -> &mut Self {
+        self.error_policy = error_policy;
+        self
+    }
+
     pub fn build(&self) -> Self {
         self.clone()
     }
@@ -111,47 +535,1725 @@ impl ChangeDetector for FileChangeDetector {
     type Key = String;
     type Hash = u64;
 
-    async fn tablehash(&mut self, _cancel: &CancellationToken) -> Option<u64> {
-        None
-    }
-
     async fn rowhash(
         self,
         state: &mut impl TableState<Self::Key, Self::Hash>,
         cancel: &CancellationToken,
     ) -> ChangeDetectorResult {
-        let mut dir = vec![self.root.clone()];
+        let this = Arc::new(self);
+        let pending = this
+            .roots
+            .iter()
+            .map(|root| PendingDir {
+                path: root.clone(),
+                depth: 0,
+                gitignores: vec![],
+            })
+            .collect();
         let mut i = 0;
 
-        while let Some(root) = dir.pop() {
-            if cancel.is_cancelled() {
-                eprintln!("The row hash was cancelled.");
-                return ChangeDetectorResult::Cancelled;
+        let result = if this.include_child_changes {
+            // A directory's row can only be set once all of its descendants have been listed.
+            let mut tree = ChildHashes::default();
+            let result = this
+                .traverse(pending, cancel, |entry| tree.push(entry))
+                .await
+                .result;
+            if let ChangeDetectorResult::Cancelled = result {
+                // Directories whose descendants were not all listed would be falsely updated.
+                return result;
             }
 
-            let mut dir_files = tokio::fs::read_dir(&root).await.unwrap();
-            while let Some(file) = dir_files.next_entry().await.unwrap() {
-                if cancel.is_cancelled() {
+            for (path, hash, included) in tree.finish() {
+                if included {
+                    state.set_row(path.display().to_string(), hash);
+                    i += 1;
+                }
+            }
+            result
+        } else {
+            this.traverse(pending, cancel, |entry| {
+                if entry.included {
+                    state.set_row(entry.path.display().to_string(), entry.hash);
+                    i += 1;
+                }
+            })
+            .await
+            .result
+        };
+
+        println!("{} file(s) scanned.", i);
+
+        result
+    }
+}
+
+/// A directory waiting to be read.
+struct PendingDir {
+    path: PathBuf,
+    /// The depth of the directory's entries, where `0` is the root's children.
+    depth: usize,
+    /// The `.gitignore` matchers of the directory's ancestors, outermost first.
+    gitignores: Vec<Arc<Gitignore>>,
+}
+
+/// The most directories a single walker keeps open at once. Past this, the remaining entries
+/// of its shallowest open directory are buffered so that directory's handle can be closed.
+const MAX_OPEN_DIRS: usize = 32;
+
+/// The most messages buffered between the walkers and the traversal before the walkers wait.
+const MAX_BUFFERED_ENTRIES: usize = 1024;
+
+/// The outcome of a traversal.
+struct Traversal {
+    result: ChangeDetectorResult,
+    /// The most directories that were open at once, which bounds the traversal's memory.
+    peak_open_dirs: usize,
+}
+
+/// State shared by the walkers of a single traversal.
+struct Walk {
+    sender: mpsc::Sender<Walked>,
+    /// Limits how many walkers may run at once.
+    walkers: Arc<Semaphore>,
+    /// The directories already descended into when following links.
+    visited: std::sync::Mutex<HashSet<DirId>>,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+    open_dirs: AtomicUsize,
+    peak_open_dirs: Arc<AtomicUsize>,
+}
+
+/// A message from a walker to its traversal.
+enum Walked {
+    Entry(Entry),
+    /// A directory for a new walker, which has already been given its permit.
+    Walker(Arc<Walk>, PendingDir, OwnedSemaphorePermit),
+    /// A path that could not be read under `ErrorPolicy::SkipAndContinue`.
+    Skipped(io::Error),
+    /// A path that could not be read under `ErrorPolicy::FailFast`.
+    Failed(io::Error),
+}
+
+impl Walk {
+    /// Reports that a path could not be read, returning whether walking should continue.
+    async fn report(&self, e: io::Error) -> bool {
+        match self.error_policy {
+            ErrorPolicy::SkipAndContinue => self.sender.send(Walked::Skipped(e)).await.is_ok(),
+            ErrorPolicy::FailFast => {
+                _ = self.sender.send(Walked::Failed(e)).await;
+                false
+            }
+        }
+    }
+
+    /// Whether the entry has not been descended into before, recording that it now has.
+    fn first_visit(&self, entry: &Entry) -> bool {
+        match &entry.dir_id {
+            Some(id) => self.visited.lock().unwrap().insert(id.clone()),
+            None => true,
+        }
+    }
+
+    fn opened_dir(&self) {
+        let open = self.open_dirs.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_open_dirs.fetch_max(open, Ordering::Relaxed);
+    }
+
+    fn closed_dir(&self) {
+        self.open_dirs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A directory being walked.
+struct Frame {
+    path: PathBuf,
+    /// The depth of the directory's entries, where `0` is the root's children.
+    depth: usize,
+    /// The `.gitignore` matchers that apply to the entries, outermost first.
+    gitignores: Vec<Arc<Gitignore>>,
+    entries: FrameEntries,
+}
+
+enum FrameEntries {
+    Open(ReadDir),
+    Buffered(std::vec::IntoIter<DirEntry>),
+}
+
+impl Frame {
+    async fn next_entry(&mut self) -> io::Result<Option<DirEntry>> {
+        match &mut self.entries {
+            FrameEntries::Open(entries) => entries.next_entry().await,
+            FrameEntries::Buffered(entries) => Ok(entries.next()),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        matches!(self.entries, FrameEntries::Open(_))
+    }
+
+    /// Reads the remaining entries into memory so the directory's handle can be closed.
+    async fn buffer(&mut self) -> io::Result<()> {
+        let FrameEntries::Open(entries) = &mut self.entries else {
+            return Ok(());
+        };
+
+        let mut rest = vec![];
+        let result = loop {
+            match entries.next_entry().await {
+                Ok(Some(entry)) => rest.push(entry),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(with_path(&self.path, e)),
+            }
+        };
+        self.entries = FrameEntries::Buffered(rest.into_iter());
+        result
+    }
+}
+
+/// A directory entry found during traversal, along with its row hash.
+struct Entry {
+    path: PathBuf,
+    hash: u64,
+    /// Whether the entry should be reported, or is only listed so it can be descended into.
+    included: bool,
+    /// Whether the entry is a directory that may be descended into.
+    descend: bool,
+    /// Identifies the directory when following links, so each is only visited once.
+    dir_id: Option<DirId>,
+}
+
+/// Combines the hash of each directory with the hashes of its descendants, so a change to any
+/// descendant also updates the directory.
+#[derive(Default)]
+struct ChildHashes {
+    /// The path, hash, and whether to report each entry, in the order they were listed.
+    entries: Vec<(PathBuf, u64, bool)>,
+    /// The index of each directory in `entries`.
+    dirs: HashMap<PathBuf, usize>,
+}
+
+impl ChildHashes {
+    fn push(&mut self, entry: Entry) {
+        if entry.descend {
+            self.dirs.insert(entry.path.clone(), self.entries.len());
+        }
+        self.entries.push((entry.path, entry.hash, entry.included));
+    }
+
+    /// Produces the entries in the order they were listed, with the hash of each directory
+    /// combined with those of its descendants.
+    fn finish(self) -> impl Iterator<Item = (PathBuf, u64, bool)> {
+        let Self { mut entries, dirs } = self;
+        let mut children = vec![0; entries.len()];
+
+        // A directory is always listed before its entries, so walking backwards combines every
+        // descendant before the directory itself.
+        for i in (0..entries.len()).rev() {
+            let (path, hash, _) = &mut entries[i];
+            if dirs.contains_key(path.as_path()) {
+                let mut hasher = DefaultHasher::new();
+                (*hash, children[i]).hash(&mut hasher);
+                *hash = hasher.finish();
+            }
+
+            if let Some(parent) = path.parent().and_then(|parent| dirs.get(parent)) {
+                children[*parent] ^= fold_table_hash([(&*path, *hash)]);
+            }
+        }
+
+        entries.into_iter()
+    }
+}
+
+impl FileChangeDetector {
+    /// Lists each directory in `dir` and, when recursive, their descendants, passing every
+    /// entry to `visit`. A directory is always listed before its entries.
+    async fn traverse(
+        self: &Arc<Self>,
+        dir: Vec<PendingDir>,
+        cancel: &CancellationToken,
+        mut visit: impl FnMut(Entry),
+    ) -> Traversal {
+        let (sender, mut receiver) = mpsc::channel(MAX_BUFFERED_ENTRIES);
+        let peak_open_dirs = Arc::new(AtomicUsize::new(0));
+        let walk = Walk {
+            sender,
+            walkers: Arc::new(Semaphore::new(self.max_concurrency)),
+            visited: std::sync::Mutex::new(HashSet::new()),
+            error_policy: self.error_policy,
+            cancel: cancel.clone(),
+            open_dirs: AtomicUsize::new(0),
+            peak_open_dirs: peak_open_dirs.clone(),
+        };
+        let traversal = |result| Traversal {
+            result,
+            peak_open_dirs: peak_open_dirs.load(Ordering::Relaxed),
+        };
+
+        if self.symlinks == SymlinkPolicy::Follow {
+            for pending in &dir {
+                match dir_id(&pending.path).await {
+                    Ok(id) => walk.visited.lock().unwrap().insert(id),
+                    Err(e) => {
+                        let e = with_path(&pending.path, e);
+                        return traversal(ChangeDetectorResult::Faulted(Box::new(e)));
+                    }
+                };
+            }
+        }
+
+        // The walkers hold the only senders, so the channel closes once every walker is done.
+        // Dropping `walkers` aborts any still in flight.
+        let mut walkers = JoinSet::new();
+        let permit = walk.walkers.clone().try_acquire_owned().unwrap();
+        walkers.spawn(self.clone().walk(Arc::new(walk), dir, permit));
+        let mut skipped = vec![];
+
+        loop {
+            let walked = select! {
+                _ = cancel.cancelled() => {
                     eprintln!("The row hash was cancelled.");
-                    return ChangeDetectorResult::Cancelled;
+                    return traversal(ChangeDetectorResult::Cancelled);
+                }
+                walked = receiver.recv() => match walked {
+                    Some(walked) => walked,
+                    None => break,
+                }
+            };
+
+            match walked {
+                Walked::Entry(entry) => visit(entry),
+                Walked::Walker(walk, pending, permit) => {
+                    walkers.spawn(self.clone().walk(walk, vec![pending], permit));
                 }
+                Walked::Skipped(e) => {
+                    eprintln!("Skipped an unreadable path. {}", e);
+                    skipped.push(e);
+                }
+                Walked::Failed(e) => return traversal(ChangeDetectorResult::Faulted(Box::new(e))),
+            }
+        }
+
+        // A walker that panicked closed its sender without reporting why.
+        while let Some(joined) = walkers.join_next().await {
+            if let Err(e) = joined {
+                return traversal(ChangeDetectorResult::Faulted(Box::new(e)));
+            }
+        }
+
+        if cancel.is_cancelled() {
+            // Reads interrupted by the cancellation may have been skipped rather than reported.
+            return traversal(ChangeDetectorResult::Cancelled);
+        }
+
+        if !skipped.is_empty() {
+            let e = SkippedPaths { errors: skipped };
+            return traversal(ChangeDetectorResult::Faulted(Box::new(e)));
+        }
+
+        traversal(ChangeDetectorResult::DeleteRemainder)
+    }
 
-                let metadata = file.metadata().await.unwrap();
+    /// Whether the traversal descends into directories found at `depth`.
+    fn descends_from(&self, depth: usize) -> bool {
+        self.recursive && self.max_depth.is_none_or(|max| depth < max)
+    }
+
+    /// Walks each directory in `dirs` depth first, streaming its entries to the traversal.
+    /// Subdirectories are handed to a new walker whenever there is room for one.
+    async fn walk(
+        self: Arc<Self>,
+        walk: Arc<Walk>,
+        dirs: Vec<PendingDir>,
+        _permit: OwnedSemaphorePermit,
+    ) {
+        let mut stack = vec![];
+        for pending in dirs {
+            if !self.open_dir(&walk, pending, &mut stack).await {
+                return;
+            }
+
+            while let Some(frame) = stack.last_mut() {
+                let file = match frame.next_entry().await {
+                    Ok(Some(file)) => file,
+                    Ok(None) => {
+                        if frame.is_open() {
+                            walk.closed_dir();
+                        }
+                        stack.pop();
+                        continue;
+                    }
+                    Err(e) => {
+                        let e = with_path(&frame.path, e);
+                        if frame.is_open() {
+                            walk.closed_dir();
+                        }
+                        stack.pop();
+                        if !walk.report(e).await {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                let path = frame.path.join(file.file_name());
+                let entry = match file.metadata().await {
+                    Ok(metadata) => {
+                        self.read_entry(path, metadata, &frame.gitignores, &walk.cancel)
+                            .await
+                    }
+                    Err(e) => Err(with_path(&path, e)),
+                };
+                let entry = match entry {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        if !walk.report(e).await {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+
+                // Only directories need their path kept once the entry is sent.
+                let child =
+                    (self.descends_from(frame.depth) && entry.descend && walk.first_visit(&entry))
+                        .then(|| PendingDir {
+                            path: entry.path.clone(),
+                            depth: frame.depth + 1,
+                            gitignores: frame.gitignores.clone(),
+                        });
+
+                // The entry is sent before its own entries are read, so a directory is always
+                // listed first.
+                if walk.sender.send(Walked::Entry(entry)).await.is_err() {
+                    return;
+                }
 
-                let full_name = root.join(file.file_name());
-                if self.recursive && metadata.is_dir() {
-                    dir.push(full_name.clone());
+                if let Some(child) = child {
+                    match walk.walkers.clone().try_acquire_owned() {
+                        Ok(permit) => {
+                            let walker = Walked::Walker(walk.clone(), child, permit);
+                            if walk.sender.send(walker).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => {
+                            if !self.open_dir(&walk, child, &mut stack).await {
+                                return;
+                            }
+                        }
+                    }
                 }
+            }
+        }
+    }
 
-                let change_hash = metadata.last_write_time();
+    /// Opens a directory onto the walker's stack, returning whether walking should continue.
+    async fn open_dir(&self, walk: &Walk, pending: PendingDir, stack: &mut Vec<Frame>) -> bool {
+        let PendingDir {
+            path,
+            depth,
+            mut gitignores,
+        } = pending;
 
-                state.set_row(full_name.display().to_string(), change_hash);
-                i += 1;
+        if self.gitignore {
+            match read_gitignore(&path).await {
+                Ok(Some(gitignore)) => gitignores.push(Arc::new(gitignore)),
+                Ok(None) => {}
+                Err(e) => return walk.report(e).await,
             }
         }
 
-        println!("{} file(s) scanned.", i);
+        let entries = match tokio::fs::read_dir(extended(&path)).await {
+            Ok(entries) => entries,
+            Err(e) => return walk.report(with_path(&path, e)).await,
+        };
+
+        if stack.iter().filter(|frame| frame.is_open()).count() >= MAX_OPEN_DIRS
+            && let Some(shallowest) = stack.iter_mut().find(|frame| frame.is_open())
+        {
+            let buffered = shallowest.buffer().await;
+            walk.closed_dir();
+            if let Err(e) = buffered
+                && !walk.report(e).await
+            {
+                return false;
+            }
+        }
+
+        walk.opened_dir();
+        stack.push(Frame {
+            path,
+            depth,
+            gitignores,
+            entries: FrameEntries::Open(entries),
+        });
+        true
+    }
+
+    /// Reads a single directory entry given its own (not its target's) metadata, or `None` if
+    /// it is filtered out of the traversal.
+    async fn read_entry(
+        &self,
+        path: PathBuf,
+        mut metadata: Metadata,
+        gitignores: &[Arc<Gitignore>],
+        cancel: &CancellationToken,
+    ) -> io::Result<Option<Entry>> {
+        let relative = self
+            .roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(&path);
+        if let Some(exclude) = &self.exclude
+            && exclude.is_match(relative)
+        {
+            return Ok(None);
+        }
+
+        let included = match &self.include {
+            Some(include) => include.is_match(relative),
+            None => true,
+        };
+
+        if !self.hidden && is_hidden(&path, &metadata) {
+            return Ok(None);
+        }
+
+        let is_symlink = metadata.file_type().is_symlink();
+        if is_symlink {
+            if self.symlinks == SymlinkPolicy::Skip {
+                return Ok(None);
+            }
+
+            // A dangling link keeps its own metadata.
+            match tokio::fs::metadata(extended(&path)).await {
+                Ok(target) => metadata = target,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(with_path(&path, e)),
+            }
+        }
+
+        let descend = metadata.is_dir() && (!is_symlink || self.symlinks == SymlinkPolicy::Follow);
+        let dir_id = if descend && self.symlinks == SymlinkPolicy::Follow {
+            Some(dir_id(&path).await.map_err(|e| with_path(&path, e))?)
+        } else {
+            None
+        };
+
+        if self.gitignore && is_gitignored(gitignores, &path, metadata.is_dir()) {
+            return Ok(None);
+        }
+
+        if !included && !metadata.is_dir() {
+            return Ok(None);
+        }
+
+        let hash = self
+            .hash_entry(&path, &metadata, cancel)
+            .await
+            .map_err(|e| with_path(&path, e))?;
+        Ok(Some(Entry {
+            path,
+            hash,
+            included,
+            descend,
+            dir_id,
+        }))
+    }
+
+    /// Reads a single path beneath a root as the traversal would, or `None` if the traversal
+    /// would not reach or report it. The entry is paired with how its own entries would be
+    /// listed. A path that no longer exists is a `NotFound` error.
+    async fn read_path(
+        &self,
+        path: &Path,
+        cancel: &CancellationToken,
+    ) -> io::Result<Option<(Entry, PendingDir)>> {
+        let Some((root, relative)) = self
+            .roots
+            .iter()
+            .find_map(|root| Some((root, path.strip_prefix(root).ok()?)))
+        else {
+            return Ok(None);
+        };
+        let components: Vec<_> = relative.components().collect();
+        let Some((_, parents)) = components.split_last() else {
+            return Ok(None);
+        };
+        if !parents.is_empty() && !self.recursive
+            || self.max_depth.is_some_and(|max| parents.len() > max)
+        {
+            return Ok(None);
+        }
+
+        // Each parent must be a directory that the traversal would descend into.
+        let mut dir = root.clone();
+        let mut gitignores = vec![];
+        for parent in parents {
+            if self.gitignore
+                && let Some(gitignore) = read_gitignore(&dir).await?
+            {
+                gitignores.push(Arc::new(gitignore));
+            }
+
+            dir.push(parent);
+            let metadata = tokio::fs::symlink_metadata(extended(&dir))
+                .await
+                .map_err(|e| with_path(&dir, e))?;
+            match self
+                .read_entry(dir.clone(), metadata, &gitignores, cancel)
+                .await?
+            {
+                Some(entry) if entry.descend => {}
+                _ => return Ok(None),
+            }
+        }
+
+        if self.gitignore
+            && let Some(gitignore) = read_gitignore(&dir).await?
+        {
+            gitignores.push(Arc::new(gitignore));
+        }
+
+        let metadata = tokio::fs::symlink_metadata(extended(path))
+            .await
+            .map_err(|e| with_path(path, e))?;
+        let entry = self
+            .read_entry(path.to_path_buf(), metadata, &gitignores, cancel)
+            .await?;
+        Ok(entry.map(|entry| {
+            let children = PendingDir {
+                path: entry.path.clone(),
+                depth: parents.len() + 1,
+                gitignores,
+            };
+            (entry, children)
+        }))
+    }
+
+    /// Combines the hash of a directory with those of its descendants, as `rowhash` does when
+    /// including child changes.
+    async fn subtree_hash(
+        self: &Arc<Self>,
+        entry: Entry,
+        children: PendingDir,
+        cancel: &CancellationToken,
+    ) -> io::Result<u64> {
+        let descend = entry.descend && self.descends_from(children.depth - 1);
+        let mut tree = ChildHashes::default();
+        tree.push(entry);
+
+        if descend {
+            match self
+                .traverse(vec![children], cancel, |entry| tree.push(entry))
+                .await
+                .result
+            {
+                ChangeDetectorResult::DeleteRemainder => {}
+                ChangeDetectorResult::Faulted(e) => return Err(io::Error::other(e)),
+                _ => return Err(io::ErrorKind::Interrupted.into()),
+            }
+        }
+
+        // The directory was listed first.
+        Ok(tree.finish().next().map_or(0, |(_, hash, _)| hash))
+    }
+
+    /// Records a single path beneath a root to `state` as the traversal would, removing its
+    /// row if the path no longer exists. When including child changes, a directory's
+    /// descendants are listed again to combine their hashes.
+    async fn record_path(
+        self: &Arc<Self>,
+        path: &Path,
+        state: &mut impl TableState<String, u64>,
+        cancel: &CancellationToken,
+    ) -> io::Result<()> {
+        match self.read_path(path, cancel).await {
+            Ok(Some((entry, children))) if entry.included => {
+                let key = entry.path.display().to_string();
+                let hash = if self.include_child_changes {
+                    self.subtree_hash(entry, children, cancel).await?
+                } else {
+                    entry.hash
+                };
+                state.set_row(key, hash)
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                state.remove_row(path.display().to_string())
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Describes a change with its row hash and the current metadata of its path. A deleted
+    /// path has no metadata left to read, so only its last known hash is described.
+    async fn describe(&self, change: StateChange<String>, hash: Option<u64>) -> FileChangeEvent {
+        let (change_type, path) = match change {
+            StateChange::New(path) => (ChangeType::New, path),
+            StateChange::Update(path) => (ChangeType::Update, path),
+            StateChange::Delete(path) => (ChangeType::Delete, path),
+        };
+        let mut event = FileChangeEvent {
+            path,
+            change_type,
+            size: None,
+            modified_unix: None,
+            content_hash: hash.filter(|_| self.content_hash && !self.permissions),
+            hash,
+        };
+
+        // If the path changed again since it was hashed, the next pass reports it again.
+        if change_type != ChangeType::Delete
+            && let Ok(metadata) = tokio::fs::metadata(extended(Path::new(&event.path))).await
+        {
+            event.modified_unix = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs());
+            if metadata.is_file() {
+                event.size = Some(metadata.len());
+            } else {
+                event.content_hash = None;
+            }
+        }
+
+        event
+    }
+
+    /// Computes the row hash of a single entry.
+    async fn hash_entry(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        cancel: &CancellationToken,
+    ) -> io::Result<u64> {
+        let content_hash = self.content_hash && metadata.is_file();
+        let hash = if content_hash {
+            hash_content(path, cancel).await?
+        } else {
+            last_write_time(metadata)
+        };
+        let size = (self.size && metadata.is_file() && !content_hash).then_some(metadata.len());
+        if !self.permissions && size.is_none() {
+            return Ok(hash);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        hash.hash(&mut hasher);
+        if self.permissions {
+            permissions(metadata).hash(&mut hasher);
+        }
+        if let Some(size) = size {
+            size.hash(&mut hasher);
+        }
+        Ok(hasher.finish())
+    }
+}
+
+/// The last write time of the entry in nanoseconds since the Unix epoch, or zero if the
+/// platform does not record it.
+fn last_write_time(metadata: &Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Hashes the bytes of a file, reading it in fixed-size chunks so memory stays bounded.
+async fn hash_content(path: &Path, cancel: &CancellationToken) -> io::Result<u64> {
+    let mut file = tokio::fs::File::open(extended(path)).await?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut hasher = DefaultHasher::new();
+
+    loop {
+        if cancel.is_cancelled() {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buffer[..read]);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Identifies a directory regardless of the path used to reach it.
+#[cfg(unix)]
+type DirId = (u64, u64);
+
+/// Identifies a directory regardless of the path used to reach it. The volume and file index
+/// are not available from the standard library on Windows, so the canonical path is used.
+#[cfg(windows)]
+type DirId = PathBuf;
+
+/// Identifies the directory at `path` by device and inode, following links.
+#[cfg(unix)]
+async fn dir_id(path: &Path) -> io::Result<DirId> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = tokio::fs::metadata(path).await?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Identifies the directory at `path` by its canonical path, which resolves links and
+/// junctions.
+#[cfg(windows)]
+async fn dir_id(path: &Path) -> io::Result<DirId> {
+    tokio::fs::canonicalize(extended(path)).await
+}
+
+/// Reads the `.gitignore` file in `dir`, if there is one.
+async fn read_gitignore(dir: &Path) -> io::Result<Option<Gitignore>> {
+    let path = dir.join(".gitignore");
+    let contents = match tokio::fs::read_to_string(extended(&path)).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(with_path(&path, e)),
+    };
+
+    let mut builder = GitignoreBuilder::new(dir);
+    for line in contents.lines() {
+        builder
+            .add_line(Some(path.clone()), line)
+            .map_err(|e| with_path(&path, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+    }
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| with_path(&path, io::Error::new(io::ErrorKind::InvalidData, e)))
+}
+
+/// Whether `path` is a dotfile.
+#[cfg(unix)]
+fn is_hidden(path: &Path, _metadata: &Metadata) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+/// Whether `path` carries the hidden or system attribute.
+#[cfg(windows)]
+fn is_hidden(_path: &Path, metadata: &Metadata) -> bool {
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+    metadata.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0
+}
+
+/// The mode of the entry, including its permission bits.
+#[cfg(unix)]
+fn permissions(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode()
+}
+
+/// The attributes of the entry, such as read-only or hidden. The archive attribute is left out,
+/// since backups clear it without the entry changing.
+#[cfg(windows)]
+fn permissions(metadata: &Metadata) -> u32 {
+    const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+
+    metadata.file_attributes() & !FILE_ATTRIBUTE_ARCHIVE
+}
+
+/// Whether `path` is ignored, giving precedence to the innermost `.gitignore` that matches.
+fn is_gitignored(gitignores: &[Arc<Gitignore>], path: &Path, is_dir: bool) -> bool {
+    if is_dir && path.file_name().is_some_and(|name| name == ".git") {
+        return true;
+    }
+
+    for gitignore in gitignores.iter().rev() {
+        match gitignore.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+fn build_globset<I, G>(globs: I) -> Result<GlobSet, globset::Error>
+where
+    I: IntoIterator<Item = G>,
+    G: AsRef<str>,
+{
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob.as_ref())?);
+    }
+    builder.build()
+}
+
+/// The extended-length form of the absolute `path`, like `\\?\C:\dir` or
+/// `\\?\UNC\server\share\dir`, which Windows reads beyond its 260 character path limit. The
+/// extended form is taken literally, so `.` and `..` are resolved and `/` is replaced first.
+/// Relative paths and paths already in an extended form are unchanged.
+#[cfg(windows)]
+fn extended(path: &Path) -> Cow<'_, Path> {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
+
+    let mut components = path.components();
+    let mut extended = match components.next() {
+        Some(Component::Prefix(prefix)) if path.has_root() => match prefix.kind() {
+            Prefix::Disk(drive) => PathBuf::from(format!(r"\\?\{}:\", drive as char)),
+            Prefix::UNC(server, share) => {
+                let mut unc = OsString::from(r"\\?\UNC\");
+                unc.push(server);
+                unc.push(r"\");
+                unc.push(share);
+                unc.push(r"\");
+                PathBuf::from(unc)
+            }
+            _ => return Cow::Borrowed(path),
+        },
+        _ => return Cow::Borrowed(path),
+    };
+    for component in components {
+        match component {
+            Component::Normal(name) => extended.push(name),
+            Component::ParentDir => {
+                extended.pop();
+            }
+            _ => {}
+        }
+    }
+    Cow::Owned(extended)
+}
+
+/// Paths are only limited in length on Windows.
+#[cfg(not(windows))]
+fn extended(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Prefixes an IO error with the path it occurred on, which the bare OS error omits.
+fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test_fs {
+    use super::{
+        ChangeType, ErrorPolicy, FileChangeDetector, FileChangeEvent, FileDetectorConfig,
+        MAX_OPEN_DIRS, PendingDir, PublishConfig, SkippedPaths, SymlinkPolicy,
+    };
+    use rabbit_eye::state::{
+        ChangeDetector, ChangeDetectorResult, DefaultTableState, StateChange, TableState,
+    };
+    use rabbit_eye::sync::CancellationToken;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// Creates an empty directory unique to this test process.
+    pub(super) fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rabbit-eye-{}-{}", name, std::process::id()));
+        _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    /// Scans `root` with `detector` into a fresh state and returns the sorted keys reported new.
+    async fn scan(detector: &FileChangeDetector) -> Vec<String> {
+        let mut state = DefaultTableState::default();
+        detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        let mut keys: Vec<_> = state
+            .drain(true)
+            .map(|change| match change {
+                StateChange::New(key) => key,
+                or => panic!("Expected only new rows but got {:?}", or),
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// The detector must be driven through the core `ChangeDetector` trait, not a local copy.
+    #[test]
+    fn implements_core_change_detector() {
+        fn assert_core<D: ChangeDetector<Key = String, Hash = u64>>() {}
+
+        assert_core::<FileChangeDetector>();
+    }
+
+    #[tokio::test]
+    async fn missing_root_faulted() {
+        let root = temp_root("missing").join("does-not-exist");
+        let mut state = DefaultTableState::default();
+
+        let result = FileChangeDetector::new(root.clone())
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        match result {
+            ChangeDetectorResult::Faulted(e) => {
+                assert!(e.to_string().contains(&root.display().to_string()))
+            }
+            _ => panic!("Expected the detector to fault."),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unreadable_directory_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_root("unreadable");
+        touch(&root, "a.txt");
+        touch(&root, "locked/b.txt");
+        touch(&root, "open/c.txt");
+        let locked = root.join("locked");
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::read_dir(&locked).is_ok() {
+            // Permissions are not enforced for this user, e.g. root.
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+            _ = std::fs::remove_dir_all(root);
+            return;
+        }
+
+        let mut state = DefaultTableState::default();
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true);
+        let result = detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        match result {
+            ChangeDetectorResult::Faulted(e) => {
+                assert_eq!(1, e.downcast_ref::<SkippedPaths>().unwrap().errors.len())
+            }
+            _ => panic!("Expected the detector to fault."),
+        }
+        let mut keys: Vec<_> = state
+            .drain(false)
+            .map(|change| match change {
+                StateChange::New(key) => key,
+                or => panic!("Expected only new rows but got {:?}", or),
+            })
+            .collect();
+        keys.sort();
+        assert_eq!(
+            vec!["a.txt", "locked", "open", "open/c.txt"],
+            relative(&root, keys)
+        );
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn error_policy_controls_unreadable_entry() {
+        let root = temp_root("error-policy");
+        touch(&root, "a.txt");
+        // Reading the target of a self-referential link fails even when permissions are not
+        // enforced.
+        std::os::unix::fs::symlink(root.join("loop"), root.join("loop")).unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        let mut state = DefaultTableState::default();
+        let result = detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        assert_eq!(Some(false), result.delete_remainder());
+        assert_eq!(
+            vec![StateChange::New(root.join("a.txt").display().to_string())],
+            state.drain(false).collect::<Vec<_>>()
+        );
+
+        detector.with_error_policy(ErrorPolicy::FailFast);
+        let mut state = DefaultTableState::default();
+        let result = detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        match result {
+            ChangeDetectorResult::Faulted(e) => {
+                assert!(e.downcast_ref::<SkippedPaths>().is_none())
+            }
+            _ => panic!("Expected the detector to fault."),
+        }
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    /// Scans `detector` into `state`, returning the changes keyed by file name.
+    async fn rescan(
+        detector: &FileChangeDetector,
+        state: &mut DefaultTableState<String, u64>,
+    ) -> Vec<StateChange<String>> {
+        detector
+            .build()
+            .rowhash(&mut *state, &CancellationToken::new())
+            .await;
+        state.drain(true).collect()
+    }
+
+    #[tokio::test]
+    async fn change_event_round_trips_json() {
+        let root = temp_root("change-event");
+        let file = root.join("a.txt");
+        let key = file.display().to_string();
+        std::fs::write(&file, "hello").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut state = DefaultTableState::default();
+        detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        let (change, hash) = state.drain_hashed(true).next().unwrap();
+
+        let event = detector.describe(change, hash).await;
+        assert_eq!(key, event.path);
+        assert_eq!(ChangeType::New, event.change_type);
+        assert_eq!(Some(5), event.size);
+        assert!(event.modified_unix.is_some());
+        assert_eq!(hash, event.content_hash);
+
+        let decoded: FileChangeEvent = serde_json::from_slice(&event.to_body().unwrap()).unwrap();
+        assert_eq!(event, decoded);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn routing_key_renders_change_type() {
+        let publish = PublishConfig {
+            exchange: "changes".to_string(),
+            routing_key_template: "files.{change_type}.{file_name}".to_string(),
+            ..Default::default()
+        };
+        let mut event = FileChangeEvent {
+            path: PathBuf::from("watched").join("a.txt").display().to_string(),
+            change_type: ChangeType::New,
+            size: Some(5),
+            modified_unix: None,
+            content_hash: None,
+            hash: None,
+        };
+        assert_eq!("files.new.a.txt", publish.routing_key(&event));
+
+        event.change_type = ChangeType::Delete;
+        assert_eq!("files.delete.a.txt", publish.routing_key(&event));
+        assert_eq!(
+            "rabbit-eye-dev",
+            PublishConfig::default().routing_key(&event)
+        );
+    }
+
+    #[test]
+    fn published_messages_are_persistent_json() {
+        let properties = PublishConfig::default().properties();
+        assert_eq!(Some(2), properties.delivery_mode());
+        assert_eq!(
+            Some("application/json"),
+            properties.content_type().map(String::as_str)
+        );
+        assert_eq!(None, properties.content_encoding());
+
+        let properties = PublishConfig::default()
+            .with_persistent(false)
+            .with_content_encoding("gzip")
+            .build()
+            .properties();
+        assert_eq!(Some(1), properties.delivery_mode());
+        assert_eq!(
+            Some("gzip"),
+            properties.content_encoding().map(String::as_str)
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_event_has_last_known_hash() {
+        let root = temp_root("delete-event");
+        let file = root.join("a.txt");
+        let key = file.display().to_string();
+        std::fs::write(&file, "hello").unwrap();
+
+        let detector = FileChangeDetector::new(root.clone());
+        let mut state = DefaultTableState::default();
+        detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        let (_, recorded) = state.drain_hashed(true).next().unwrap();
+
+        std::fs::remove_file(&file).unwrap();
+        detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        let (change, hash) = state.drain_hashed(true).next().unwrap();
+        let event = detector.describe(change, hash).await;
+
+        let decoded: FileChangeEvent = serde_json::from_slice(&event.to_body().unwrap()).unwrap();
+        assert_eq!(
+            FileChangeEvent {
+                path: key,
+                change_type: ChangeType::Delete,
+                size: None,
+                modified_unix: None,
+                content_hash: None,
+                hash: recorded,
+            },
+            decoded
+        );
+        assert!(recorded.is_some());
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn multiple_roots_share_state() {
+        let root = temp_root("multiple-roots");
+        touch(&root, "a/1.txt");
+        touch(&root, "a/nested/2.txt");
+        touch(&root, "b/3.txt");
+
+        let config = FileDetectorConfig {
+            roots: vec![
+                root.join("a"),
+                root.join("b"),
+                root.join("a/nested"),
+                root.join("a"),
+            ],
+            recursive: true,
+            include_child_changes: false,
+        };
+        assert_eq!(vec![root.join("a"), root.join("b")], config.resolve_roots());
+
+        let keys = relative(&root, scan(&config.detector().unwrap()).await);
+        assert_eq!(
+            vec!["a/1.txt", "a/nested", "a/nested/2.txt", "b/3.txt"],
+            keys
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn nonexistent_root_skipped() {
+        let root = temp_root("nonexistent-root");
+        let config = FileDetectorConfig {
+            roots: vec![root.join("missing"), root.clone()],
+            recursive: true,
+            include_child_changes: false,
+        };
+        assert_eq!(vec![root.clone()], config.resolve_roots());
+
+        let config = FileDetectorConfig {
+            roots: vec![root.join("missing")],
+            ..config
+        };
+        assert!(config.detector().is_none());
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn content_hash_detects_rewrite_with_same_mtime() {
+        let root = temp_root("content-rewrite");
+        let file = root.join("a.txt");
+        std::fs::write(&file, "one").unwrap();
+        let mtime = std::fs::metadata(&file).unwrap().modified().unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut state = DefaultTableState::default();
+        rescan(&detector, &mut state).await;
+
+        std::fs::write(&file, "two").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let changes = rescan(&detector, &mut state).await;
+        assert_eq!(
+            vec![StateChange::Update(file.display().to_string())],
+            changes
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn permissions_change_is_update() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = temp_root("permissions");
+        let file = root.join("a.txt");
+        std::fs::write(&file, "one").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        let mut state = DefaultTableState::default();
+        rescan(&detector, &mut state).await;
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(rescan(&detector, &mut state).await.is_empty());
+
+        detector.with_permissions(true);
+        rescan(&detector, &mut state).await;
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+        assert_eq!(
+            vec![StateChange::Update(file.display().to_string())],
+            rescan(&detector, &mut state).await
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn read_only_change_is_update() {
+        let root = temp_root("permissions");
+        let file = root.join("a.txt");
+        std::fs::write(&file, "one").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_permissions(true);
+        let mut state = DefaultTableState::default();
+        rescan(&detector, &mut state).await;
+
+        let mut permissions = std::fs::metadata(&file).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&file, permissions.clone()).unwrap();
+        assert_eq!(
+            vec![StateChange::Update(file.display().to_string())],
+            rescan(&detector, &mut state).await
+        );
+
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&file, permissions).unwrap();
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn size_detects_append_with_same_mtime() {
+        use std::io::Write;
+
+        let root = temp_root("size-append");
+        let file = root.join("a.txt");
+        std::fs::write(&file, "one").unwrap();
+        let mtime = std::fs::metadata(&file).unwrap().modified().unwrap();
+        let append = |text: &str| {
+            let mut handle = std::fs::File::options().append(true).open(&file).unwrap();
+            handle.write_all(text.as_bytes()).unwrap();
+            handle.set_modified(mtime).unwrap();
+        };
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        let mut state = DefaultTableState::default();
+        rescan(&detector, &mut state).await;
+        append("two");
+        assert!(rescan(&detector, &mut state).await.is_empty());
+
+        detector.with_size(true);
+        rescan(&detector, &mut state).await;
+        append("three");
+        assert_eq!(
+            vec![StateChange::Update(file.display().to_string())],
+            rescan(&detector, &mut state).await
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn content_hash_ignores_touch() {
+        let root = temp_root("content-touch");
+        let file = root.join("a.txt");
+        std::fs::write(&file, "one").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut state = DefaultTableState::default();
+        rescan(&detector, &mut state).await;
+
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let changes = rescan(&detector, &mut state).await;
+        assert!(changes.is_empty());
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn child_changes_update_ancestors() {
+        let root = temp_root("child-changes");
+        touch(&root, "a/b/c.txt");
+        touch(&root, "d/e.txt");
+
+        for (child_changes, expected) in [
+            (false, vec!["a/b/c.txt"]),
+            (true, vec!["a", "a/b", "a/b/c.txt"]),
+        ] {
+            let mut detector = FileChangeDetector::new(root.clone());
+            detector
+                .with_recursive(true)
+                .with_content_hash(true)
+                .with_child_changes(child_changes);
+            let mut state = DefaultTableState::default();
+            rescan(&detector, &mut state).await;
+
+            // Rewriting a file in place does not change the last write time of its directory.
+            std::fs::write(root.join("a/b/c.txt"), child_changes.to_string()).unwrap();
+            let mut updated: Vec<_> = rescan(&detector, &mut state)
+                .await
+                .into_iter()
+                .map(|change| match change {
+                    StateChange::Update(key) => key,
+                    or => panic!("Expected only updated rows but got {:?}", or),
+                })
+                .collect();
+            updated.sort();
+
+            assert_eq!(expected, relative(&root, updated));
+        }
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    /// Creates `path` (and its parents) under `root` as a file containing its own name.
+    fn touch(root: &std::path::Path, path: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, path.display().to_string()).unwrap();
+    }
+
+    /// Converts the keys produced by `scan` back to paths relative to `root`.
+    fn relative(root: &std::path::Path, keys: Vec<String>) -> Vec<String> {
+        keys.into_iter()
+            .map(|key| {
+                PathBuf::from(key)
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn exclude_prunes_directory() {
+        let root = temp_root("exclude");
+        touch(&root, "src/main.rs");
+        touch(&root, "target/debug/main.exe");
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_exclude(["target"])
+            .unwrap();
+        let keys = relative(&root, scan(&detector).await);
+
+        assert_eq!(vec!["src", "src/main.rs"], keys);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn include_matches_files() {
+        let root = temp_root("include");
+        touch(&root, "src/main.rs");
+        touch(&root, "src/readme.md");
+        touch(&root, "src/nested/lib.rs");
+        touch(&root, "src/nested/lib.rs.bak");
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_include(["**/*.rs", "**/*.bak"])
+            .unwrap()
+            .with_exclude(["**/*.bak"])
+            .unwrap();
+        let keys = relative(&root, scan(&detector).await);
+
+        assert_eq!(vec!["src/main.rs", "src/nested/lib.rs"], keys);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn gitignore_layers_nested_files() {
+        let root = temp_root("gitignore");
+        std::fs::write(root.join(".gitignore"), "ignored/\n*.log\n").unwrap();
+        touch(&root, "ignored/deep/a.txt");
+        touch(&root, "src/a.log");
+        touch(&root, "src/keep.log");
+        std::fs::write(root.join("src/.gitignore"), "!keep.log\n").unwrap();
+        touch(&root, ".git/HEAD");
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_gitignore(true)
+            .with_hidden(true);
+        let keys = relative(&root, scan(&detector).await);
+
+        assert_eq!(
+            vec![".gitignore", "src", "src/.gitignore", "src/keep.log"],
+            keys
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hidden_dotfiles_skipped_by_default() {
+        let root = temp_root("hidden");
+        touch(&root, ".hidden.txt");
+        touch(&root, ".config/a.txt");
+        touch(&root, "visible.txt");
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true);
+        assert_eq!(vec!["visible.txt"], relative(&root, scan(&detector).await));
+
+        detector.with_hidden(true);
+        assert_eq!(
+            vec![".config", ".config/a.txt", ".hidden.txt", "visible.txt"],
+            relative(&root, scan(&detector).await)
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extended_paths_are_verbatim() {
+        use super::extended;
+        use std::path::Path;
+
+        assert_eq!(
+            Path::new(r"\\?\C:\data\b"),
+            extended(Path::new(r"C:/data\a\..\.\b"))
+        );
+        assert_eq!(
+            Path::new(r"\\?\UNC\server\share\dir"),
+            extended(Path::new(r"\\server\share\dir"))
+        );
+        assert_eq!(
+            Path::new(r"\\?\UNC\server\share\"),
+            extended(Path::new(r"\\server\share"))
+        );
+        assert_eq!(
+            Path::new(r"\\?\C:\data"),
+            extended(Path::new(r"\\?\C:\data"))
+        );
+        assert_eq!(Path::new(r"data\b"), extended(Path::new(r"data\b")));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn deep_paths_scanned() {
+        use super::extended;
+
+        let root = temp_root("deep");
+        let mut deep = root.clone();
+        while deep.as_os_str().len() <= 300 {
+            deep.push("a-directory-with-a-long-name");
+        }
+        std::fs::create_dir_all(extended(&deep)).unwrap();
+        std::fs::write(extended(&deep.join("file.txt")), "deep").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true).with_content_hash(true);
+        let keys = scan(&detector).await;
+        assert!(keys.contains(&deep.join("file.txt").display().to_string()));
+
+        _ = std::fs::remove_dir_all(extended(&root));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn hidden_attribute_skipped_by_default() {
+        let root = temp_root("hidden");
+        touch(&root, "secret/a.txt");
+        touch(&root, "visible.txt");
+        let status = std::process::Command::new("attrib")
+            .arg("+h")
+            .arg(root.join("secret"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true);
+        assert_eq!(vec!["visible.txt"], relative(&root, scan(&detector).await));
+
+        detector.with_hidden(true);
+        assert_eq!(
+            vec!["secret", "secret/a.txt", "visible.txt"],
+            relative(&root, scan(&detector).await)
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn max_depth_limits_descent() {
+        let root = temp_root("max-depth");
+        touch(&root, "a.txt");
+        touch(&root, "a/b/c/d.txt");
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true).with_max_depth(0);
+        let keys = relative(&root, scan(&detector).await);
+        assert_eq!(vec!["a", "a.txt"], keys);
+
+        detector.with_max_depth(1);
+        let keys = relative(&root, scan(&detector).await);
+        assert_eq!(vec!["a", "a.txt", "a/b"], keys);
+
+        detector.with_max_depth(3);
+        let keys = relative(&root, scan(&detector).await);
+        assert_eq!(vec!["a", "a.txt", "a/b", "a/b/c", "a/b/c/d.txt"], keys);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn follow_symlink_cycle_terminates() {
+        let root = temp_root("symlink-follow");
+        touch(&root, "a/b.txt");
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("a"), root.join("c")).unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_symlinks(SymlinkPolicy::Follow);
+        let keys = relative(&root, scan(&detector).await);
+
+        // `a` and `c` are the same directory, so whichever is listed first is descended
+        assert_eq!(4, keys.len());
+        assert!(keys.contains(&"a".to_string()));
+        assert!(keys.contains(&"c".to_string()));
+        assert!(!keys.iter().any(|key| key.contains("loop/")));
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn skip_symlinks_omits_link() {
+        let root = temp_root("symlink-skip");
+        touch(&root, "a/b.txt");
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_symlinks(SymlinkPolicy::Skip);
+        let keys = relative(&root, scan(&detector).await);
+
+        assert_eq!(vec!["a", "a/b.txt"], keys);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn concurrent_matches_serial() {
+        let root = temp_root("concurrent");
+        for a in 0..4 {
+            for b in 0..3 {
+                let dir = root.join(format!("a{}", a)).join(format!("b{}", b));
+                std::fs::create_dir_all(&dir).unwrap();
+                for f in 0..5 {
+                    std::fs::write(dir.join(format!("f{}.txt", f)), "x").unwrap();
+                }
+            }
+        }
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_recursive(true);
+        let serial = scan(&detector).await;
+        let concurrent = scan(detector.with_max_concurrency(8)).await;
+
+        assert_eq!(4 + 4 * 3 + 4 * 3 * 5, serial.len());
+        assert_eq!(serial, concurrent);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn traversal_memory_bounded() {
+        const WIDE: usize = 2000;
+        const DEEP: usize = 100;
+
+        let root = temp_root("bounded");
+        for w in 0..WIDE {
+            let dir = root.join("wide").join(format!("d{}", w));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("f.txt"), "x").unwrap();
+        }
+        let mut deep = root.join("deep");
+        for d in 0..DEEP {
+            deep.push(format!("d{}", d));
+        }
+        std::fs::create_dir_all(&deep).unwrap();
+        std::fs::write(deep.join("f.txt"), "x").unwrap();
+
+        let concurrency = 4;
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_max_concurrency(concurrency);
+        let pending = vec![PendingDir {
+            path: root.clone(),
+            depth: 0,
+            gitignores: vec![],
+        }];
+        let mut visited = 0;
+        let traversal = Arc::new(detector.build())
+            .traverse(pending, &CancellationToken::new(), |_| visited += 1)
+            .await;
+
+        assert!(matches!(
+            traversal.result,
+            ChangeDetectorResult::DeleteRemainder
+        ));
+        // wide, its directories and their files; deep, its chain and the file at the bottom.
+        assert_eq!(1 + WIDE * 2 + 1 + DEEP + 1, visited);
+        assert!(
+            traversal.peak_open_dirs <= concurrency * MAX_OPEN_DIRS,
+            "{} directories were open at once",
+            traversal.peak_open_dirs
+        );
 
-        ChangeDetectorResult::DeleteRemainder
+        _ = std::fs::remove_dir_all(root);
     }
 }
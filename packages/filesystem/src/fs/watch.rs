@@ -0,0 +1,535 @@
+use super::{ErrorPolicy, FileChangeDetector};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use rabbit_eye::{
+    engine::EngineConfig,
+    envelope::ChangeEnvelope,
+    sink::ChangeSink,
+    state::{ChangeDetector, ChangeDetectorResult, StateChange, TableState},
+    sync::{CancellationToken, RaceOutcome, run_until_cancelled_or_timeout},
+};
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::mpsc,
+    time::{Instant, sleep},
+};
+
+/// Watches the roots of a `FileChangeDetector` using the operating system's notifications
+/// (inotify, FSEvents, ReadDirectoryChangesW) rather than walking the tree on a schedule.
+/// Changes are recorded to the same `TableState` as the polling detector, so running it
+/// periodically still reconciles any events that were missed, such as the children of a
+/// directory that was moved away.
+pub struct FileWatchDetector {
+    detector: Arc<FileChangeDetector>,
+    /// Each root as reported by notifications, which may be resolved from the configured root,
+    /// paired with the configured root.
+    notified_roots: Vec<(PathBuf, PathBuf)>,
+    events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    /// How long notifications must be quiet before a batch is recorded.
+    debounce: Option<Duration>,
+    /// How long a batch may keep collecting notifications after its first one.
+    max_batch_delay: Duration,
+    /// Notifications stop when the watcher is dropped.
+    _watcher: RecommendedWatcher,
+}
+
+impl FileWatchDetector {
+    /// Begins watching the roots of `detector`, applying the same filters as its traversal.
+    pub fn new(detector: FileChangeDetector) -> notify::Result<Self> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            // The receiver is only gone once the detector is dropped.
+            _ = sender.send(event);
+        })?;
+
+        let mode = if detector.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        let mut notified_roots = vec![];
+        for root in &detector.roots {
+            watcher.watch(root, mode)?;
+            let notified = std::fs::canonicalize(root).unwrap_or_else(|_| root.clone());
+            notified_roots.push((notified, root.clone()));
+        }
+
+        Ok(Self {
+            detector: Arc::new(detector),
+            notified_roots,
+            events,
+            debounce: None,
+            max_batch_delay: Duration::from_secs(10),
+            _watcher: watcher,
+        })
+    }
+
+    /// Waits until no notification has arrived for `debounce` before recording a batch, so a
+    /// burst of writes to the same file (e.g. an editor's temp file, rename, and rewrite) is
+    /// recorded as a single change with the final state.
+    pub fn with_debounce(&mut self, debounce: Duration) -> &mut Self {
+        self.debounce = Some(debounce);
+        self
+    }
+
+    /// Records a batch once this long has passed since its first notification, even if
+    /// notifications have not been quiet for the debounce, so a file that is written
+    /// continuously (e.g. a log or a growing download) is still reported.
+    pub fn with_max_batch_delay(&mut self, max_batch_delay: Duration) -> &mut Self {
+        self.max_batch_delay = max_batch_delay;
+        self
+    }
+
+    /// Waits for the next batch of notifications and records the affected paths to `state`.
+    /// Deleted paths are recorded with `remove_row`, so `state` should be drained without
+    /// deleting the remainder. Returns without recording anything if `cancel` is cancelled.
+    pub async fn next_changes(
+        &mut self,
+        state: &mut impl TableState<String, u64>,
+        cancel: &CancellationToken,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let first = select! {
+            _ = cancel.cancelled() => return Ok(()),
+            event = self.events.recv() => match event {
+                Some(event) => event,
+                None => return Err("The filesystem watcher stopped.".into()),
+            },
+        };
+
+        // Each path is only read once per batch, so repeated notifications are coalesced.
+        let deadline = Instant::now() + self.max_batch_delay;
+        let mut paths = BTreeSet::new();
+        self.collect(first, &mut paths)?;
+        loop {
+            while let Ok(event) = self.events.try_recv() {
+                self.collect(event, &mut paths)?;
+            }
+
+            let Some(debounce) = self.debounce else {
+                break;
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+
+            // Cancelling ends the quiet period early, but what was collected is still recorded.
+            let quiet = debounce.min(remaining);
+            match run_until_cancelled_or_timeout(cancel, quiet, self.events.recv()).await {
+                RaceOutcome::Completed(Some(event)) => self.collect(event, &mut paths)?,
+                RaceOutcome::Completed(None) | RaceOutcome::Cancelled | RaceOutcome::TimedOut => {
+                    break;
+                }
+            }
+        }
+
+        for path in paths {
+            match self.detector.record_path(&path, state, cancel).await {
+                Ok(()) => {}
+                // Content hashes interrupted by the cancellation are left for reconciliation.
+                Err(_) if cancel.is_cancelled() => {}
+                Err(e) => self.skip_or_fail(Box::new(e))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the paths affected by a notification to `paths`.
+    fn collect(
+        &self,
+        event: notify::Result<Event>,
+        paths: &mut BTreeSet<PathBuf>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match event {
+            // Reading a file to hash it would otherwise notify again.
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) => {
+                for path in event.paths {
+                    let path = self.resolve(&path);
+                    // A directory's last write time changes with its entries, and when including
+                    // child changes so does the hash of every ancestor.
+                    let ancestors = if self.detector.include_child_changes {
+                        usize::MAX
+                    } else {
+                        1
+                    };
+                    for ancestor in path.ancestors().skip(1).take(ancestors) {
+                        if self.detector.roots.iter().any(|root| root == ancestor) {
+                            break;
+                        }
+                        paths.insert(ancestor.to_path_buf());
+                    }
+                    paths.insert(path);
+                }
+            }
+            Err(e) => self.skip_or_fail(Box::new(e))?,
+        }
+        Ok(())
+    }
+
+    /// Maps a notified path onto its configured root, so keys match the polling detector.
+    fn resolve(&self, path: &Path) -> PathBuf {
+        for (notified, root) in &self.notified_roots {
+            match path.strip_prefix(notified) {
+                Ok(relative) if relative.as_os_str().is_empty() => return root.clone(),
+                Ok(relative) => return root.join(relative),
+                Err(_) => {}
+            }
+        }
+        path.to_path_buf()
+    }
+
+    fn skip_or_fail(
+        &self,
+        e: Box<dyn Error + Send + Sync>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self.detector.error_policy {
+            ErrorPolicy::SkipAndContinue => {
+                eprintln!("Skipped an unreadable path. {}", e);
+                Ok(())
+            }
+            ErrorPolicy::FailFast => Err(e),
+        }
+    }
+}
+
+/// Reconciles `state` with a full traversal of `detector`'s roots, then publishes each change to
+/// `sink` as it is notified until `cancel` is cancelled. Each change is published with the same
+/// envelope as the engine, under the config's source. A change that cannot be published is
+/// settled back out of the state, and the roots are traversed again after the config's backoff,
+/// so it is published again.
+pub async fn watch_and_report_files(
+    detector: FileChangeDetector,
+    sink: &impl ChangeSink<String>,
+    config: &EngineConfig,
+    state: &mut impl TableState<String, u64>,
+    cancel: &CancellationToken,
+) -> Result<(), Box<dyn Error>> {
+    // Watch before reconciling, so nothing changed during the traversal is missed.
+    let mut watcher = FileWatchDetector::new(detector.build())?;
+
+    // The first pass traverses the roots to reconcile the state.
+    let mut reconcile = true;
+    let mut failures = 0;
+    while !cancel.is_cancelled() {
+        let delete_remainder = if reconcile {
+            let changes = detector.build().rowhash(&mut *state, cancel).await;
+            if let ChangeDetectorResult::Faulted(e) = &changes {
+                eprintln!("The change detector faulted. {}", e);
+            }
+            let Some(delete_remainder) = changes.delete_remainder() else {
+                break;
+            };
+            delete_remainder
+        } else {
+            watcher
+                .next_changes(state, cancel)
+                .await
+                .map_err(|e| e as Box<dyn Error>)?;
+            false
+        };
+
+        let changes: Vec<_> = state.drain_hashed(delete_remainder).collect();
+        let failed = publish_each(sink, config.source(), detector.name(), changes).await;
+        // The rows of failed changes are only found again by traversing the roots.
+        reconcile = !failed.is_empty();
+        state.settle(failed);
+        if !reconcile {
+            failures = 0;
+            continue;
+        }
+
+        failures += 1;
+        let backoff = config.backoff(failures);
+        eprintln!(
+            "Some changes could not be published. Retrying in {:?}.",
+            backoff
+        );
+        if cancel.run_until_cancelled(sleep(backoff)).await.is_none() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Publishes each change to `sink`, returning the keys of those that could not be published.
+async fn publish_each(
+    sink: &impl ChangeSink<String>,
+    source: &str,
+    detector: &str,
+    changes: Vec<(StateChange<String>, Option<u64>)>,
+) -> Vec<String> {
+    let mut failed = vec![];
+    for (change, hash) in changes {
+        let published = match ChangeEnvelope::new(source, detector, change.clone()).to_json() {
+            Ok(payload) => sink
+                .publish(&change, hash, &payload)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = published {
+            eprintln!("{} could not be published. {}", change, e);
+            failed.push(change.into_key());
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod test_watch {
+    use super::{FileWatchDetector, watch_and_report_files};
+    use crate::fs::{FileChangeDetector, test_fs::temp_root};
+    use rabbit_eye::{
+        engine::EngineConfig,
+        envelope::ChangeEnvelope,
+        sink::{ChangeSink, SinkError},
+        state::{ChangeDetector, DefaultTableState, StateChange, TableState},
+        sync::CancellationToken,
+    };
+    use std::{cell::RefCell, time::Duration};
+
+    /// Records the published changes, failing the first `failures` publishes.
+    #[derive(Default)]
+    struct FlakySink {
+        failures: RefCell<usize>,
+        published: RefCell<Vec<(StateChange<String>, Vec<u8>)>>,
+    }
+
+    impl ChangeSink<String> for FlakySink {
+        async fn publish(
+            &self,
+            change: &StateChange<String>,
+            _row_hash: Option<u64>,
+            payload: &[u8],
+        ) -> Result<(), SinkError> {
+            let mut failures = self.failures.borrow_mut();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(SinkError::Other("the broker is down".into()));
+            }
+            self.published
+                .borrow_mut()
+                .push((change.clone(), payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Records notifications until `expected` is drained, or gives up after a few seconds.
+    async fn wait_for(
+        watcher: &mut FileWatchDetector,
+        state: &mut DefaultTableState<String, u64>,
+        expected: StateChange<String>,
+    ) -> bool {
+        let cancel = CancellationToken::new();
+        let found = async {
+            loop {
+                watcher.next_changes(state, &cancel).await.unwrap();
+                if state.drain(false).any(|change| change == expected) {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), found)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn reports_create_modify_delete() {
+        let root = temp_root("watch");
+        let file = root.join("a.txt");
+        let key = file.display().to_string();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut watcher = FileWatchDetector::new(detector.build()).unwrap();
+        let mut state = DefaultTableState::default();
+
+        std::fs::write(&file, "one").unwrap();
+        assert!(wait_for(&mut watcher, &mut state, StateChange::New(key.clone())).await);
+
+        std::fs::write(&file, "two").unwrap();
+        assert!(wait_for(&mut watcher, &mut state, StateChange::Update(key.clone())).await);
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(wait_for(&mut watcher, &mut state, StateChange::Delete(key)).await);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn debounce_coalesces_burst() {
+        let root = temp_root("watch-debounce");
+        let file = root.join("a.txt");
+        let key = file.display().to_string();
+        std::fs::write(&file, "zero").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut watcher = FileWatchDetector::new(detector.build()).unwrap();
+        watcher.with_debounce(Duration::from_millis(250));
+        let cancel = CancellationToken::new();
+        let mut state = DefaultTableState::default();
+        // The file is already known, so the burst is reported as an update.
+        state.set_row(key.clone(), 0);
+        state.drain(false).for_each(drop);
+
+        let burst = async {
+            for i in 0..5 {
+                std::fs::write(&file, i.to_string()).unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let (result, ()) = tokio::join!(watcher.next_changes(&mut state, &cancel), burst);
+        result.unwrap();
+
+        assert_eq!(
+            vec![StateChange::Update(key)],
+            state.drain(false).collect::<Vec<_>>()
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn max_batch_delay_flushes_continuous_writes() {
+        let root = temp_root("watch-max-batch-delay");
+        let file = root.join("a.log");
+        let key = file.display().to_string();
+        std::fs::write(&file, "").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector.with_content_hash(true);
+        let mut watcher = FileWatchDetector::new(detector.build()).unwrap();
+        watcher
+            .with_debounce(Duration::from_millis(250))
+            .with_max_batch_delay(Duration::from_millis(500));
+        let cancel = CancellationToken::new();
+        let mut state = DefaultTableState::default();
+        state.set_row(key.clone(), 0);
+        state.drain(false).for_each(drop);
+
+        // The writes never pause for the debounce, and go on well past the max batch delay.
+        let writing = CancellationToken::new();
+        let writes = async {
+            let mut i = 0;
+            while !writing.is_cancelled() {
+                std::fs::write(&file, i.to_string()).unwrap();
+                i += 1;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let batch = async {
+            let batch = tokio::time::timeout(
+                Duration::from_secs(3),
+                watcher.next_changes(&mut state, &cancel),
+            )
+            .await;
+            writing.cancel();
+            batch
+        };
+        let (batch, ()) = tokio::join!(batch, writes);
+        batch
+            .expect("the batch was not recorded while writes continued")
+            .unwrap();
+
+        assert_eq!(
+            vec![StateChange::Update(key)],
+            state.drain(false).collect::<Vec<_>>()
+        );
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn child_changes_match_polling() {
+        let root = temp_root("watch-child-changes");
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        let file = root.join("a/b/c.txt");
+        std::fs::write(&file, "one").unwrap();
+
+        let mut detector = FileChangeDetector::new(root.clone());
+        detector
+            .with_recursive(true)
+            .with_content_hash(true)
+            .with_child_changes(true);
+        let mut watcher = FileWatchDetector::new(detector.build()).unwrap();
+        let mut state = DefaultTableState::default();
+        let cancel = CancellationToken::new();
+        detector.build().rowhash(&mut state, &cancel).await;
+        state.drain(true).for_each(drop);
+
+        std::fs::write(&file, "two").unwrap();
+        let key = root.join("a").display().to_string();
+        assert!(wait_for(&mut watcher, &mut state, StateChange::Update(key)).await);
+        state.drain(false).for_each(drop);
+
+        // The hashes recorded from notifications agree with a full traversal.
+        detector.build().rowhash(&mut state, &cancel).await;
+        assert_eq!(0, state.drain(true).count());
+
+        _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn watch_republishes_failed_changes() {
+        let root = temp_root("watch-report");
+        let a = root.join("a.txt");
+        let b = root.join("b.txt");
+        std::fs::write(&a, "a").unwrap();
+
+        let detector = FileChangeDetector::new(root.clone());
+        let sink = FlakySink {
+            failures: RefCell::new(1),
+            ..Default::default()
+        };
+        let config = EngineConfig::new(Duration::from_millis(20))
+            .unwrap()
+            .with_source("files")
+            .build();
+        let mut state = DefaultTableState::default();
+        let cancel = CancellationToken::new();
+
+        let published = |path: &std::path::Path| {
+            let change = StateChange::New(path.display().to_string());
+            sink.published.borrow().iter().any(|(c, _)| *c == change)
+        };
+        let check = async {
+            // The first publish fails, so the reconciling traversal is retried.
+            while !published(&a) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            std::fs::write(&b, "b").unwrap();
+            while !published(&b) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            cancel.cancel();
+        };
+        let watch = watch_and_report_files(detector, &sink, &config, &mut state, &cancel);
+        let (result, ()) =
+            tokio::time::timeout(Duration::from_secs(5), async { tokio::join!(watch, check) })
+                .await
+                .expect("the changes were not published");
+        result.unwrap();
+
+        let published = sink.published.borrow();
+        let envelope: ChangeEnvelope<String> = serde_json::from_slice(&published[0].1).unwrap();
+        assert_eq!(
+            Some(StateChange::New(&a.display().to_string())),
+            envelope.change()
+        );
+        assert_eq!("files", envelope.source);
+        assert_eq!("FileChangeDetector", envelope.detector);
+
+        _ = std::fs::remove_dir_all(root);
+    }
+}
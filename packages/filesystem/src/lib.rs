@@ -0,0 +1,119 @@
+//! Watches paths on the filesystem and publishes their changes to RabbitMQ.
+
+use config::{Config, DetectorMode};
+#[cfg(feature = "watch")]
+use rabbit_eye::lifetime::AppLifetime;
+use rabbit_eye::{
+    engine::EngineConfig,
+    rabbit::RabbitMq,
+    sink::{ChangeSink, StdoutSink},
+    state::{DefaultTableState, InMemoryPersistence},
+};
+use std::error::Error;
+
+pub mod config;
+pub mod fs;
+
+/// Watches the paths of `settings` with the engine configured by `config` until the app is
+/// stopped or the config's max runs have finished, publishing the changes to RabbitMQ as set by
+/// `settings`. A dry run does not connect to RabbitMQ.
+pub async fn run(settings: &Config, config: EngineConfig) -> Result<(), Box<dyn Error>> {
+    if config.dry_run() {
+        return run_with(settings, StdoutSink, config).await;
+    }
+
+    let rabbit = RabbitMq::connect(settings.connection.options()?).await?;
+    run_with(settings, settings.sink.sink(rabbit), config).await
+}
+
+/// Watches the paths of `settings` until the app is stopped, publishing the changes to `sink`.
+/// In the poll mode the paths are walked by the engine configured by `config`, until its max
+/// runs have finished. In the watch mode they are watched as by `watch_and_report_files`.
+pub async fn run_with(
+    settings: &Config,
+    sink: impl ChangeSink<String> + 'static,
+    config: EngineConfig,
+) -> Result<(), Box<dyn Error>> {
+    let Some(detector) = settings.detector()?.detector() else {
+        eprintln!("None of the watch paths exist.");
+        return Ok(());
+    };
+
+    match settings.detector.mode {
+        DetectorMode::Poll => {
+            let persistence = InMemoryPersistence::<DefaultTableState<String, u64>>::default();
+            let (_status, engine) = rabbit_eye::engine::run(detector, sink, persistence, config);
+            // The engine's future is large, so it is kept off the stack.
+            Box::pin(engine).await
+        }
+        #[cfg(feature = "watch")]
+        DetectorMode::Watch => {
+            let life =
+                AppLifetime::with_timeouts(config.natural_timeout(), config.graceful_timeout())?;
+            let mut state = DefaultTableState::default();
+            let stop = life.natural();
+            let watch = fs::watch_and_report_files(detector, &sink, &config, &mut state, &stop);
+            life.run_until_abort(Box::pin(watch))
+                .await
+                .unwrap_or(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_lib {
+    use super::{config::Config, run, run_with};
+    use rabbit_eye::{engine::EngineConfig, sink::StdoutSink};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn run_with_starts_and_stops() {
+        let root = std::env::temp_dir().join(format!("rabbit-eye-run-with-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "a").unwrap();
+        let mut settings = Config::default();
+        settings.detector.roots = vec![root];
+
+        let config = EngineConfig::new(Duration::from_millis(10))
+            .unwrap()
+            .with_run_immediately(true)
+            .with_max_runs(2)
+            .with_grace_period(Duration::from_millis(100))
+            .build();
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            run_with(&settings, StdoutSink, config),
+        )
+        .await
+        .expect("the engine did not stop after its max runs");
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_connect() {
+        let mut settings = Config::default();
+        settings.detector.roots = vec![std::env::temp_dir()];
+        settings.detector.recursive = false;
+
+        // The default connection has no host, so connecting would fail.
+        let config = EngineConfig::new(Duration::from_millis(10))
+            .unwrap()
+            .with_max_runs(1)
+            .with_dry_run(true)
+            .build();
+        let result = run(&settings, config).await;
+        assert!(result.is_ok(), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn zero_shutdown_timeout_fails() {
+        let root = std::env::temp_dir();
+        let mut settings = Config::default();
+        settings.detector.roots = vec![root];
+
+        let config = EngineConfig::default()
+            .with_shutdown_timeouts(Duration::ZERO, Duration::from_secs(1))
+            .build();
+        assert!(run_with(&settings, StdoutSink, config).await.is_err());
+    }
+}
@@ -1,17 +1,24 @@
-use amqprs::channel::{self, Channel};
-use rabbit_eye::{
-    lifetime::{self, CtrlC},
-    rabbit,
-    state::TableState,
-    sync, time,
-};
-use std::{error::Error, sync::Arc};
-use tokio::{sync::Mutex, task::JoinHandle};
-use tokio_util::sync::CancellationToken;
-
-mod fs;
+use filesystem::config::Config;
+use std::error::Error;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    rabbit_eye::engine::run().await
+    #[cfg(feature = "tracing")]
+    rabbit_eye::log::init_subscriber();
+
+    let settings = Config::load()?;
+    let mut config = settings.engine()?;
+    if std::env::args().skip(1).any(|arg| arg == "--dry-run") {
+        config.with_dry_run(true);
+    }
+    #[cfg(feature = "health")]
+    if let Some(addr) = rabbit_eye::health::read_addr_from_env()? {
+        config.with_health_addr(addr);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = rabbit_eye::metrics::read_addr_from_env()? {
+        config.with_metrics_addr(addr);
+    }
+
+    filesystem::run(&settings, config).await
 }
@@ -1,34 +1,76 @@
 use amqprs::{
-    Ack, BasicProperties, Cancel, CloseChannel, Deliver, Nack, Return,
+    Ack, BasicProperties, Cancel, CloseChannel, FieldValue, Nack, Return,
     callbacks::ChannelCallback,
-    channel::{
-        BasicAckArguments, BasicConsumeArguments, BasicQosArguments, Channel, QueueDeclareArguments,
-    },
+    channel::{Channel, QueueDeclareArguments},
     connection::{Connection, OpenConnectionArguments},
-    consumer::AsyncConsumer,
 };
 use async_trait::async_trait;
 use chrono::{DateTime, Local, Utc};
+use rabbit_eye::{
+    consume::{
+        AckMode, ConsumerOptions, DEFAULT_PREFETCH, Delivery, HandlerConsumer, MessageHandler,
+        Settlement,
+    },
+    lifetime::{AppLifetime, race_sigterm},
+    sync::CancellationToken,
+};
+use serde_json::{Map, Value};
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     env,
     error::Error,
-    time::{Instant, SystemTime},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How long shutting down waits for the message being handled to be acked.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    let lifetime = AppLifetime::with_timeouts(SHUTDOWN_TIMEOUT, SHUTDOWN_TIMEOUT)?;
+    let format = OutputFormat::read_from_env()?;
+    let max_redeliveries = match env::var("CONSOLE_MAX_REDELIVERIES") {
+        Ok(max) => max.parse()?,
+        Err(_) => 3,
+    };
+    let prefetch = match env::var("CONSOLE_PREFETCH") {
+        Ok(prefetch) => prefetch.parse()?,
+        Err(_) => DEFAULT_PREFETCH,
+    };
+    let ack_mode = read_ack_mode()?;
+    let filter = env::var("CONSOLE_ROUTING_FILTER").ok();
+    let requeue_filtered = match env::var("CONSOLE_REQUEUE_FILTERED") {
+        Ok(requeue) => requeue.parse()?,
+        Err(_) => false,
+    };
+    let summary_interval = match env::var("CONSOLE_SUMMARY_INTERVAL") {
+        Ok(seconds) => Some(parse_summary_interval(&seconds)?),
+        Err(_) => None,
+    };
+    let summary_only = match env::var("CONSOLE_SUMMARY_ONLY") {
+        Ok(only) => only.parse()?,
+        Err(_) => false,
+    };
+    let output = Output::read_from_env()?;
+    let port = match env::var("RABBITMQ_PORT") {
+        Ok(port) => port.parse()?,
+        Err(_) => 5672,
+    };
     let args = OpenConnectionArguments::new(
         &env::var("RABBITMQ_HOST").unwrap(),
-        5672,
+        port,
         &env::var("RABBITMQ_USER").unwrap(),
         &env::var("RABBITMQ_PASS").unwrap(),
     );
     let connection = Connection::open(&args).await?;
 
     let channel = connection.open_channel(None).await?;
-    channel
-        .basic_qos(BasicQosArguments::new(0, 1_000, false))
-        .await?;
 
     eprintln!("Channel open. Declaring queue...");
 
@@ -53,10 +95,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     eprintln!("Callback registered. Consuming...");
 
-    let consume_args = BasicConsumeArguments::new(&queue, "");
-    // let consumer = DefaultConsumer::new(false);
-    let consumer = PrintlnConsumer;
-    channel.basic_consume(consumer, consume_args).await?;
+    let opts = ConsumerOptions::new(&queue)
+        .with_prefetch(prefetch)
+        .with_ack_mode(ack_mode)
+        .build();
+    let summary = summary_interval.map(|_| Summary::default());
+    let handler = PrintlnHandler {
+        format,
+        filter,
+        summary: summary.clone(),
+        print_messages: summary.is_none() || !summary_only,
+        output: output.clone(),
+    };
+    let mut consumer = HandlerConsumer::new(handler, max_redeliveries);
+    if requeue_filtered {
+        consumer.with_declined(Settlement::Requeue);
+    }
+    let consumer = consumer.start(&channel, &opts).await?;
 
     eprintln!("Consumer registered. Activating...");
 
@@ -64,9 +119,28 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     eprintln!("Flow active. Waiting...");
 
-    tokio::signal::ctrl_c().await?;
+    let stop_summaries = CancellationToken::new();
+    let summaries = summary.zip(summary_interval).map(|(summary, interval)| {
+        tokio::spawn(print_summaries(summary, interval, stop_summaries.clone()))
+    });
+
+    _ = race_sigterm(std::future::pending::<()>(), &lifetime).await;
+
+    eprintln!(
+        "Shutting down. Cancelling consumer {}...",
+        consumer.consumer_tag()
+    );
+
+    consumer.shutdown(SHUTDOWN_TIMEOUT).await?;
+    connection.close().await?;
+
+    stop_summaries.cancel();
+    if let Some(summaries) = summaries {
+        summaries.await?;
+    }
+    output.close()?;
 
-    eprintln!("Ctrl+C received. Shutting down.");
+    eprintln!("Channel and connection closed.");
 
     Ok(())
 }
@@ -139,32 +213,695 @@ impl ChannelCallback for EprintlnChannelCallback {
     }
 }
 
-struct PrintlnConsumer;
+/// How `PrintlnConsumer` prints each message, set by the `CONSOLE_FORMAT` environment variable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum OutputFormat {
+    /// The body, decoded as UTF-8.
+    #[default]
+    Text,
+    /// A JSON object with the delivery tag, routing key, and body of the message.
+    Json,
+}
+
+impl OutputFormat {
+    fn read_from_env() -> Result<Self, InvalidFormatError> {
+        match env::var("CONSOLE_FORMAT") {
+            Ok(format) => format.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Renders a message as a single line, unless the text body has line breaks. Text is only
+    /// the body, while JSON also has the message's properties.
+    fn format(&self, delivery: &Delivery) -> String {
+        match self {
+            Self::Text => decode_body(&delivery.content).into_owned(),
+            Self::Json => serde_json::json!({
+                "delivery_tag": delivery.delivery_tag,
+                "routing_key": delivery.routing_key,
+                "properties": properties_json(&delivery.properties),
+                "body": decode_body(&delivery.content),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = InvalidFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(InvalidFormatError(s.to_string())),
+        }
+    }
+}
+
+/// `CONSOLE_FORMAT` was neither `text` nor `json`.
+#[derive(Debug)]
+struct InvalidFormatError(String);
+
+impl Display for InvalidFormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CONSOLE_FORMAT must be text or json, but was {:?}",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidFormatError {}
+
+/// Reads how messages are acked from `CONSOLE_ACK_MODE`, which is `manual` unless set to
+/// `auto`. Manual acking only removes a message from the queue once it is printed, so none are
+/// missed. Auto acking is faster for a viewer that does not mind losing messages that arrive
+/// as it stops.
+fn read_ack_mode() -> Result<AckMode, Box<dyn Error>> {
+    match env::var("CONSOLE_ACK_MODE") {
+        Ok(mode) => parse_ack_mode(&mode),
+        Err(_) => Ok(AckMode::Manual),
+    }
+}
+
+fn parse_ack_mode(mode: &str) -> Result<AckMode, Box<dyn Error>> {
+    match mode.to_ascii_lowercase().as_str() {
+        "manual" => Ok(AckMode::Manual),
+        "auto" => Ok(AckMode::Auto),
+        _ => Err(format!(
+            "CONSOLE_ACK_MODE must be auto or manual, but was {:?}",
+            mode
+        )
+        .into()),
+    }
+}
+
+/// The properties of a message that help when debugging, with `null` for those not set. The
+/// timestamp, which is in seconds since the Unix epoch, is written in RFC 3339.
+fn properties_json(properties: &BasicProperties) -> Value {
+    let timestamp = properties.timestamp().map(|seconds| {
+        i64::try_from(seconds)
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .map_or_else(|| seconds.to_string(), |time| time.to_rfc3339())
+    });
+    let headers = properties.headers().map(|headers| {
+        headers
+            .as_ref()
+            .iter()
+            .map(|(name, value)| (name.to_string(), header_json(value)))
+            .collect::<Map<_, _>>()
+    });
+    serde_json::json!({
+        "content_type": properties.content_type(),
+        "timestamp": timestamp,
+        "message_id": properties.message_id(),
+        "correlation_id": properties.correlation_id(),
+        "headers": headers,
+    })
+}
+
+/// A header value as JSON. Values with no JSON equivalent are written as text.
+fn header_json(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::t(b) => Value::from(*b),
+        FieldValue::b(n) => Value::from(*n),
+        FieldValue::B(n) => Value::from(*n),
+        FieldValue::s(n) => Value::from(*n),
+        FieldValue::u(n) => Value::from(*n),
+        FieldValue::I(n) => Value::from(*n),
+        FieldValue::i(n) => Value::from(*n),
+        FieldValue::l(n) => Value::from(*n),
+        FieldValue::T(n) => Value::from(*n),
+        FieldValue::f(n) => Value::from(*n),
+        FieldValue::d(n) => Value::from(*n),
+        FieldValue::S(s) => Value::from(s.to_string()),
+        FieldValue::V => Value::Null,
+        other => Value::from(other.to_string()),
+    }
+}
+
+/// The properties that are set, like `content_type=application/json message_id=7`.
+fn describe_properties(properties: &BasicProperties) -> String {
+    let Value::Object(properties) = properties_json(properties) else {
+        unreachable!("the properties are written as an object");
+    };
+    properties
+        .into_iter()
+        .filter_map(|(name, value)| match value {
+            Value::Null => None,
+            Value::String(s) => Some(format!("{}={}", name, s)),
+            value => Some(format!("{}={}", name, value)),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decodes a message body as UTF-8, replacing invalid sequences so binary bodies still print.
+fn decode_body(content: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(content)
+}
+
+/// Reads the summary interval, a whole number of seconds of at least `1`.
+fn parse_summary_interval(seconds: &str) -> Result<Duration, Box<dyn Error>> {
+    match seconds.parse()? {
+        0 => Err("CONSOLE_SUMMARY_INTERVAL must be at least 1 second".into()),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+/// The messages handled since the consumer started.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Counts {
+    total: u64,
+    bytes: u64,
+    by_routing_key: BTreeMap<String, u64>,
+}
+
+impl Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Consumed {} messages ({} bytes)", self.total, self.bytes)?;
+        for (i, (routing_key, count)) in self.by_routing_key.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{}={}", separator, routing_key, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the messages `PrintlnHandler` handles for the summaries printed every
+/// `CONSOLE_SUMMARY_INTERVAL` seconds. Clones share the same counts.
+#[derive(Clone, Debug, Default)]
+struct Summary(Arc<std::sync::Mutex<Counts>>);
+
+impl Summary {
+    fn record(&self, delivery: &Delivery) {
+        let mut counts = self.0.lock().unwrap();
+        counts.total += 1;
+        counts.bytes += delivery.content.len() as u64;
+        *counts
+            .by_routing_key
+            .entry(delivery.routing_key.clone())
+            .or_default() += 1;
+    }
+
+    fn counts(&self) -> Counts {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Prints `summary` every `interval` until `stop` is cancelled, then once more.
+async fn print_summaries(summary: Summary, interval: Duration, stop: CancellationToken) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, before anything was consumed.
+    ticker.tick().await;
+    while stop.run_until_cancelled(ticker.tick()).await.is_some() {
+        eprintln!("{}", summary.counts());
+    }
+    eprintln!("{}", summary.counts());
+}
+
+/// Where `PrintlnHandler` writes messages. Clones write to the same place.
+#[derive(Clone, Debug)]
+enum Output {
+    Stdout,
+    /// Appends to the file set by `CONSOLE_OUTPUT_FILE`.
+    File(Arc<std::sync::Mutex<RotatingFile>>),
+}
+
+impl Output {
+    /// Writes to `CONSOLE_OUTPUT_FILE` if it is set, rotating it once it reaches
+    /// `CONSOLE_OUTPUT_MAX_BYTES` and keeping `CONSOLE_OUTPUT_MAX_FILES` rotated files (5 by
+    /// default). Otherwise writes to stdout.
+    fn read_from_env() -> Result<Self, Box<dyn Error>> {
+        let Ok(path) = env::var("CONSOLE_OUTPUT_FILE") else {
+            return Ok(Self::Stdout);
+        };
+        let max_bytes = match env::var("CONSOLE_OUTPUT_MAX_BYTES") {
+            Ok(max) => Some(max.parse()?),
+            Err(_) => None,
+        };
+        let max_files = match env::var("CONSOLE_OUTPUT_MAX_FILES") {
+            Ok(max) => max.parse()?,
+            Err(_) => 5,
+        };
+        let file = RotatingFile::open(path.into(), max_bytes, max_files)?;
+        Ok(Self::File(Arc::new(std::sync::Mutex::new(file))))
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Self::File(file) => file.lock().unwrap().write_line(line),
+        }
+    }
+
+    /// Flushes the output and, for a file, waits for it to reach the disk.
+    fn close(&self) -> io::Result<()> {
+        match self {
+            Self::Stdout => io::stdout().flush(),
+            Self::File(file) => file.lock().unwrap().close(),
+        }
+    }
+}
+
+/// A file written one line at a time. Once writing a line would make it larger than
+/// `max_bytes`, it is renamed to `path.1`, older files move up to `path.2` and so on, and a new
+/// file is started. Only `max_files` rotated files are kept.
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_files: usize,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl RotatingFile {
+    /// Opens `path` to append to it, creating it if it does not exist.
+    fn open(path: PathBuf, max_bytes: Option<u64>, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file: BufWriter::new(file),
+            written,
+        })
+    }
+
+    /// Writes `line` and a line break, flushing it so the file can be followed as it grows.
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if let Some(max_bytes) = self.max_bytes
+            && self.written > 0
+            && self.written + len > max_bytes
+        {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.close()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.written = 0;
+        Ok(())
+    }
+
+    /// The path of the `n`th most recently rotated file.
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()
+    }
+}
+
+/// Whether `routing_key` matches the AMQP topic `pattern`, whose words are separated by `.`.
+/// A `*` word matches exactly one word and a `#` word matches zero or more.
+fn topic_matches(pattern: &str, routing_key: &str) -> bool {
+    fn matches(pattern: &[&str], key: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => key.is_empty(),
+            Some((&"#", rest)) => (0..=key.len()).any(|skip| matches(rest, &key[skip..])),
+            Some((&"*", rest)) => !key.is_empty() && matches(rest, &key[1..]),
+            Some((word, rest)) => key.first() == Some(word) && matches(rest, &key[1..]),
+        }
+    }
+
+    let pattern: Vec<_> = pattern.split('.').collect();
+    let key: Vec<_> = routing_key.split('.').collect();
+    matches(&pattern, &key)
+}
+
+/// Prints each message in its output format. With a `filter`, set by the
+/// `CONSOLE_ROUTING_FILTER` environment variable, only messages whose routing key matches the
+/// topic pattern are printed. The others are acked without printing, or requeued if
+/// `CONSOLE_REQUEUE_FILTERED` is `true`. Each message handled is counted in the `summary`, if
+/// any, and `CONSOLE_SUMMARY_ONLY` stops the messages themselves from being printed.
+struct PrintlnHandler {
+    format: OutputFormat,
+    filter: Option<String>,
+    summary: Option<Summary>,
+    print_messages: bool,
+    output: Output,
+}
 
 #[async_trait]
-impl AsyncConsumer for PrintlnConsumer {
-    async fn consume(
-        &mut self,
-        channel: &Channel,
-        deliver: Deliver,
-        _basic_properties: BasicProperties,
-        content: Vec<u8>,
-    ) {
-        println!(
-            "{} (#{} on channel {}) content size={}\n{:?}",
+impl MessageHandler for PrintlnHandler {
+    async fn handle(&mut self, delivery: &Delivery) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(summary) = &self.summary {
+            summary.record(delivery);
+        }
+        if !self.print_messages {
+            return Ok(());
+        }
+
+        eprintln!(
+            "{} (#{}) content size={} {}",
             Into::<DateTime<Local>>::into(SystemTime::now()).format("%H:%M:%S.%f"),
-            deliver.delivery_tag(),
-            channel,
-            content.len(),
-            String::from_utf8(content)
+            delivery.delivery_tag,
+            delivery.content.len(),
+            describe_properties(&delivery.properties),
+        );
+        self.output.write_line(&self.format.format(delivery))?;
+        Ok(())
+    }
+
+    fn accepts(&self, delivery: &Delivery) -> bool {
+        self.filter
+            .as_deref()
+            .is_none_or(|pattern| topic_matches(pattern, &delivery.routing_key))
+    }
+}
+
+#[cfg(test)]
+mod test_format {
+    use super::{OutputFormat, decode_body, describe_properties, parse_ack_mode};
+    use amqprs::{BasicProperties, FieldTable, FieldValue};
+    use rabbit_eye::consume::{AckMode, Delivery};
+    use serde_json::json;
+
+    fn delivery(delivery_tag: u64, routing_key: &str, content: &[u8]) -> Delivery {
+        Delivery {
+            delivery_tag,
+            routing_key: routing_key.to_string(),
+            content: content.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn with_properties() -> BasicProperties {
+        let mut headers = FieldTable::new();
+        headers.insert("x-delivery-count".try_into().unwrap(), FieldValue::l(2));
+        headers.insert(
+            "x-source".try_into().unwrap(),
+            FieldValue::S("filesystem".try_into().unwrap()),
+        );
+        BasicProperties::default()
+            .with_content_type("application/json")
+            .with_timestamp(1_769_860_800)
+            .with_message_id("42")
+            .with_headers(headers)
+            .finish()
+    }
+
+    #[test]
+    fn json_line_round_trips() {
+        let line =
+            OutputFormat::Json.format(&delivery(7, "rabbit-eye.new", br#"{"path":"a.txt"}"#));
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(7, value["delivery_tag"]);
+        assert_eq!("rabbit-eye.new", value["routing_key"]);
+        assert_eq!(r#"{"path":"a.txt"}"#, value["body"]);
+    }
+
+    #[test]
+    fn json_includes_properties() {
+        let mut message = delivery(7, "rabbit-eye.new", b"{}");
+        message.properties = with_properties();
+        let value: serde_json::Value =
+            serde_json::from_str(&OutputFormat::Json.format(&message)).unwrap();
+        assert_eq!(
+            json!({
+                "content_type": "application/json",
+                "timestamp": "2026-01-31T12:00:00+00:00",
+                "message_id": "42",
+                "correlation_id": null,
+                "headers": { "x-delivery-count": 2, "x-source": "filesystem" },
+            }),
+            value["properties"]
+        );
+    }
+
+    #[test]
+    fn missing_properties_are_null_or_omitted() {
+        let value: serde_json::Value =
+            serde_json::from_str(&OutputFormat::Json.format(&delivery(1, "key", b""))).unwrap();
+        assert!(value["properties"]["content_type"].is_null());
+        assert!(value["properties"]["headers"].is_null());
+        assert_eq!("", describe_properties(&BasicProperties::default()));
+    }
+
+    #[test]
+    fn properties_described_readably() {
+        assert_eq!(
+            concat!(
+                "content_type=application/json ",
+                r#"headers={"x-delivery-count":2,"x-source":"filesystem"} "#,
+                "message_id=42 ",
+                "timestamp=2026-01-31T12:00:00+00:00"
+            ),
+            describe_properties(&with_properties())
+        );
+    }
+
+    #[test]
+    fn text_prints_body() {
+        assert_eq!(
+            "hello",
+            OutputFormat::Text.format(&delivery(1, "key", b"hello"))
+        );
+    }
+
+    #[test]
+    fn binary_body_decoded_lossily() {
+        assert_eq!("a\u{FFFD}b", decode_body(&[b'a', 0xff, b'b']));
+    }
+
+    #[test]
+    fn ack_mode_parsed_case_insensitively() {
+        assert_eq!(AckMode::Auto, parse_ack_mode("Auto").unwrap());
+        assert_eq!(AckMode::Manual, parse_ack_mode("manual").unwrap());
+        assert!(parse_ack_mode("none").is_err());
+    }
+
+    #[test]
+    fn format_parsed_case_insensitively() {
+        assert_eq!(OutputFormat::Json, "JSON".parse().unwrap());
+        assert_eq!(OutputFormat::Text, "text".parse().unwrap());
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_routing_filter {
+    use super::{Output, OutputFormat, PrintlnHandler, topic_matches};
+    use rabbit_eye::consume::{Delivery, MessageHandler};
+
+    #[test]
+    fn literal_pattern_matches_exactly() {
+        assert!(topic_matches("files.Delete", "files.Delete"));
+        assert!(!topic_matches("files.Delete", "files.New"));
+        assert!(!topic_matches("files.Delete", "files.Delete.report_csv"));
+        assert!(!topic_matches("files.Delete", "files"));
+    }
+
+    #[test]
+    fn wildcards_match_words() {
+        assert!(topic_matches(
+            "rabbit-eye.*.orders",
+            "rabbit-eye.update.orders"
+        ));
+        assert!(!topic_matches("rabbit-eye.*.orders", "rabbit-eye.orders"));
+        assert!(topic_matches("rabbit-eye.delete.#", "rabbit-eye.delete"));
+        assert!(topic_matches(
+            "rabbit-eye.delete.#",
+            "rabbit-eye.delete.orders.42"
+        ));
+        assert!(topic_matches("#.42", "rabbit-eye.new.orders.42"));
+        assert!(!topic_matches("#.42", "rabbit-eye.new.orders.43"));
+        assert!(topic_matches("#", "anything.at.all"));
+    }
+
+    #[test]
+    fn handler_accepts_matching_keys() {
+        let delivery = |routing_key: &str| Delivery {
+            routing_key: routing_key.to_string(),
+            ..Default::default()
+        };
+        let unfiltered = PrintlnHandler {
+            format: OutputFormat::Text,
+            filter: None,
+            summary: None,
+            print_messages: true,
+            output: Output::Stdout,
+        };
+        assert!(unfiltered.accepts(&delivery("files.New")));
+
+        let filtered = PrintlnHandler {
+            format: OutputFormat::Text,
+            filter: Some("files.Delete".to_string()),
+            summary: None,
+            print_messages: true,
+            output: Output::Stdout,
+        };
+        assert!(filtered.accepts(&delivery("files.Delete")));
+        assert!(!filtered.accepts(&delivery("files.New")));
+    }
+}
+
+#[cfg(test)]
+mod test_summary {
+    use super::{Output, OutputFormat, PrintlnHandler, Summary, parse_summary_interval};
+    use rabbit_eye::consume::{Delivery, MessageHandler};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn counts_messages_by_routing_key() {
+        let summary = Summary::default();
+        let mut handler = PrintlnHandler {
+            format: OutputFormat::Text,
+            filter: None,
+            summary: Some(summary.clone()),
+            print_messages: false,
+            output: Output::Stdout,
+        };
+        for (routing_key, content) in [
+            ("files.new", "a"),
+            ("files.delete", "bc"),
+            ("files.new", "def"),
+        ] {
+            let delivery = Delivery {
+                routing_key: routing_key.to_string(),
+                content: content.as_bytes().to_vec(),
+                ..Default::default()
+            };
+            handler.handle(&delivery).await.unwrap();
+        }
+
+        let counts = summary.counts();
+        assert_eq!(3, counts.total);
+        assert_eq!(6, counts.bytes);
+        assert_eq!(Some(&2), counts.by_routing_key.get("files.new"));
+        assert_eq!(Some(&1), counts.by_routing_key.get("files.delete"));
+        assert_eq!(
+            "Consumed 3 messages (6 bytes): files.delete=1, files.new=2",
+            counts.to_string()
         );
+    }
+
+    #[test]
+    fn interval_is_whole_seconds() {
+        assert_eq!(
+            Duration::from_secs(10),
+            parse_summary_interval("10").unwrap()
+        );
+        assert!(parse_summary_interval("0").is_err());
+        assert!(parse_summary_interval("1.5").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_output {
+    use super::{Output, OutputFormat, PrintlnHandler, RotatingFile};
+    use rabbit_eye::consume::{Delivery, MessageHandler};
+    use std::{
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
 
-        let result = channel
-            .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
-            .await;
+    /// A path in a new, empty temporary directory.
+    fn output_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "message-to-console-{}-{}",
+            name,
+            std::process::id()
+        ));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("messages.log")
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
 
-        if let Err(e) = result {
-            eprintln!("Error sending basic ack. {}", e);
+    #[tokio::test]
+    async fn messages_written_to_file() {
+        let path = output_path("file");
+        let file = RotatingFile::open(path.clone(), None, 5).unwrap();
+        let output = Output::File(Arc::new(Mutex::new(file)));
+        let mut handler = PrintlnHandler {
+            format: OutputFormat::Text,
+            filter: None,
+            summary: None,
+            print_messages: true,
+            output: output.clone(),
+        };
+        for body in ["new a", "update a", "delete a"] {
+            let delivery = Delivery {
+                content: body.as_bytes().to_vec(),
+                ..Default::default()
+            };
+            handler.handle(&delivery).await.unwrap();
         }
+        output.close().unwrap();
+
+        assert_eq!("new a\nupdate a\ndelete a\n", read(path));
+    }
+
+    #[test]
+    fn file_appended_when_reopened() {
+        let path = output_path("append");
+        RotatingFile::open(path.clone(), None, 5)
+            .unwrap()
+            .write_line("first")
+            .unwrap();
+        RotatingFile::open(path.clone(), None, 5)
+            .unwrap()
+            .write_line("second")
+            .unwrap();
+
+        assert_eq!("first\nsecond\n", read(path));
+    }
+
+    #[test]
+    fn rotates_by_size_keeping_max_files() {
+        let path = output_path("rotate");
+        let mut file = RotatingFile::open(path.clone(), Some(8), 2).unwrap();
+        for line in ["one", "two", "three", "four", "five"] {
+            file.write_line(line).unwrap();
+        }
+        file.close().unwrap();
+
+        assert_eq!("five\n", read(path.clone()));
+        assert_eq!("four\n", read(file.rotated(1)));
+        assert_eq!("three\n", read(file.rotated(2)));
+        assert!(!file.rotated(3).exists());
+        assert_eq!(path.with_file_name("messages.log.1"), file.rotated(1));
     }
 }
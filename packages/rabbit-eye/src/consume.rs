@@ -0,0 +1,620 @@
+//! Consuming messages with a handler that can fail. Messages the handler accepts are acked.
+//! Messages it fails are requeued until they reach the redelivery limit, then rejected so the
+//! broker dead-letters them if the queue has a dead letter exchange. A consumer is shut down
+//! by cancelling it and waiting for the messages it is handling to be settled.
+
+use amqprs::{
+    BasicProperties, Deliver, FieldValue,
+    channel::{
+        BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicNackArguments,
+        BasicQosArguments, Channel,
+    },
+    consumer::AsyncConsumer,
+};
+use async_trait::async_trait;
+use std::{error::Error, sync::Arc, time::Duration};
+use tokio::{sync::watch, time::timeout};
+
+/// The number of unacked messages the broker sends a consumer at once, unless configured.
+pub const DEFAULT_PREFETCH: u16 = 10;
+
+/// The header quorum queues use to count how many times a message has been delivered before.
+const DELIVERY_COUNT_HEADER: &str = "x-delivery-count";
+
+/// A message delivered to a `HandlerConsumer`.
+#[derive(Clone, Debug, Default)]
+pub struct Delivery {
+    pub delivery_tag: u64,
+    pub exchange: String,
+    pub routing_key: String,
+    /// Whether the broker delivered this message before without it being acked.
+    pub redelivered: bool,
+    pub properties: BasicProperties,
+    pub content: Vec<u8>,
+}
+
+impl Delivery {
+    fn new(deliver: &Deliver, properties: BasicProperties, content: Vec<u8>) -> Self {
+        Self {
+            delivery_tag: deliver.delivery_tag(),
+            exchange: deliver.exchange().to_string(),
+            routing_key: deliver.routing_key().to_string(),
+            redelivered: deliver.redelivered(),
+            properties,
+            content,
+        }
+    }
+
+    /// How many times the message was delivered before this delivery. Quorum queues count this
+    /// in the `x-delivery-count` header. Other queues only flag a redelivery, so this is at most
+    /// `1` for them.
+    pub fn redeliveries(&self) -> u32 {
+        let count = self
+            .properties
+            .headers()
+            .and_then(|headers| {
+                headers
+                    .get(&DELIVERY_COUNT_HEADER.try_into().ok()?)
+                    .cloned()
+            })
+            .and_then(|count| match count {
+                FieldValue::b(n) => u32::try_from(n).ok(),
+                FieldValue::B(n) => Some(n.into()),
+                FieldValue::s(n) => u32::try_from(n).ok(),
+                FieldValue::u(n) => Some(n.into()),
+                FieldValue::I(n) => u32::try_from(n).ok(),
+                FieldValue::i(n) => Some(n),
+                FieldValue::l(n) => u32::try_from(n).ok(),
+                FieldValue::T(n) => u32::try_from(n).ok(),
+                _ => None,
+            });
+        count.unwrap_or(self.redelivered.into())
+    }
+}
+
+/// Does the work for each message a `HandlerConsumer` receives.
+#[async_trait]
+pub trait MessageHandler {
+    /// Fails if the message was not processed and should be delivered again.
+    async fn handle(&mut self, delivery: &Delivery) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Whether to handle `delivery`. A message that is not accepted is settled without being
+    /// handled. See `HandlerConsumer::with_declined`.
+    fn accepts(&self, _delivery: &Delivery) -> bool {
+        true
+    }
+}
+
+/// What a `HandlerConsumer` tells the broker about a message after handling it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Settlement {
+    /// The message was processed.
+    Ack,
+    /// The message failed and will be delivered again.
+    Requeue,
+    /// The message failed too many times and is rejected without requeueing.
+    DeadLetter,
+}
+
+/// Whether the messages a consumer receives are acked by the broker or by the consumer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AckMode {
+    /// The consumer settles each message after handling it, so a message it fails or does not
+    /// finish is delivered again: at-least-once delivery.
+    #[default]
+    Manual,
+    /// The broker treats each message as acked once it is sent, so a message the consumer
+    /// fails or does not finish is lost: at-most-once delivery. This is simpler and faster for
+    /// consumers that only observe messages.
+    Auto,
+}
+
+/// Consumes messages with a `MessageHandler`, acking each message it processes and nacking
+/// each it fails. A failed message is requeued until it has been redelivered
+/// `max_redeliveries` times.
+pub struct HandlerConsumer<H> {
+    handler: H,
+    max_redeliveries: u32,
+    declined: Settlement,
+    ack_mode: AckMode,
+    in_flight: InFlight,
+}
+
+impl<H> HandlerConsumer<H>
+where
+    H: MessageHandler,
+{
+    pub fn new(handler: H, max_redeliveries: u32) -> Self {
+        Self {
+            handler,
+            max_redeliveries,
+            declined: Settlement::Ack,
+            ack_mode: AckMode::Manual,
+            in_flight: InFlight::default(),
+        }
+    }
+
+    /// How to settle messages the handler does not accept. They are acked by default, which
+    /// discards them. Requeueing them instead leaves them for other consumers of the queue.
+    pub fn with_declined(&mut self, settlement: Settlement) -> &mut Self {
+        self.declined = settlement;
+        self
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// The deliveries this consumer has received and not yet settled.
+    pub fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+
+    /// Starts consuming with `opts` on `channel`, settling messages as its ack mode requires.
+    /// See `start_consumer`.
+    pub async fn start(
+        mut self,
+        channel: &Channel,
+        opts: &ConsumerOptions,
+    ) -> Result<ConsumerHandle, amqprs::error::Error>
+    where
+        H: Send + 'static,
+    {
+        let in_flight = self.in_flight();
+        self.ack_mode = opts.ack_mode;
+        let consumer_tag = start_consumer(channel, opts, self).await?;
+        Ok(ConsumerHandle {
+            channel: channel.clone(),
+            consumer_tag,
+            in_flight,
+        })
+    }
+
+    /// Handles `delivery` and decides how to settle it, without telling the broker.
+    pub async fn process(&mut self, delivery: &Delivery) -> Settlement {
+        if !self.handler.accepts(delivery) {
+            return self.declined;
+        }
+
+        match self.handler.handle(delivery).await {
+            Ok(()) => Settlement::Ack,
+            Err(e) if delivery.redeliveries() < self.max_redeliveries => {
+                warn!(
+                    "Message #{} could not be handled and will be requeued. {}",
+                    delivery.delivery_tag, e
+                );
+                Settlement::Requeue
+            }
+            Err(e) => {
+                error!(
+                    "Message #{} could not be handled after {} redeliveries and was rejected. {}",
+                    delivery.delivery_tag,
+                    delivery.redeliveries(),
+                    e
+                );
+                Settlement::DeadLetter
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<H> AsyncConsumer for HandlerConsumer<H>
+where
+    H: MessageHandler + Send,
+{
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let _in_flight = self.in_flight.enter();
+        let delivery = Delivery::new(&deliver, basic_properties, content);
+        let tag = delivery.delivery_tag;
+        let settlement = self.process(&delivery).await;
+        if self.ack_mode == AckMode::Auto {
+            if settlement != Settlement::Ack {
+                warn!(
+                    "Message #{} was already acked by the broker and is lost.",
+                    tag
+                );
+            }
+            return;
+        }
+
+        let result = match settlement {
+            Settlement::Ack => channel.basic_ack(BasicAckArguments::new(tag, false)).await,
+            Settlement::Requeue => {
+                channel
+                    .basic_nack(BasicNackArguments::new(tag, false, true))
+                    .await
+            }
+            Settlement::DeadLetter => {
+                channel
+                    .basic_nack(BasicNackArguments::new(tag, false, false))
+                    .await
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Message #{} could not be settled. {}", tag, e);
+        }
+    }
+}
+
+/// The queue a consumer reads from and how many messages it may hold unacked.
+#[derive(Clone, Debug)]
+pub struct ConsumerOptions {
+    queue: String,
+    consumer_tag: String,
+    prefetch: u16,
+    ack_mode: AckMode,
+}
+
+impl ConsumerOptions {
+    /// Consumes `queue` with a consumer tag chosen by the broker and the default prefetch.
+    pub fn new(queue: &str) -> Self {
+        Self {
+            queue: queue.to_string(),
+            consumer_tag: String::new(),
+            prefetch: DEFAULT_PREFETCH,
+            ack_mode: AckMode::Manual,
+        }
+    }
+
+    /// The number of messages the broker delivers before waiting for acks. `0` is unlimited.
+    pub fn with_prefetch(&mut self, prefetch: u16) -> &mut Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Whether the broker acks each message as it is sent. Manual acking is the default.
+    pub fn with_ack_mode(&mut self, ack_mode: AckMode) -> &mut Self {
+        self.ack_mode = ack_mode;
+        self
+    }
+
+    pub fn with_consumer_tag(&mut self, consumer_tag: &str) -> &mut Self {
+        self.consumer_tag = consumer_tag.to_string();
+        self
+    }
+
+    pub fn build(&self) -> Self {
+        self.clone()
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn prefetch(&self) -> u16 {
+        self.prefetch
+    }
+
+    pub fn ack_mode(&self) -> AckMode {
+        self.ack_mode
+    }
+
+    fn qos_arguments(&self) -> BasicQosArguments {
+        BasicQosArguments::new(0, self.prefetch, false)
+    }
+
+    fn consume_arguments(&self) -> BasicConsumeArguments {
+        let mut args = BasicConsumeArguments::new(&self.queue, &self.consumer_tag);
+        args.no_ack = self.ack_mode == AckMode::Auto;
+        args
+    }
+}
+
+/// Limits the channel's unacked messages to the prefetch of `opts`, then starts `consumer` on
+/// its queue. Returns the consumer tag, which cancels the consumer.
+pub async fn start_consumer<C>(
+    channel: &Channel,
+    opts: &ConsumerOptions,
+    consumer: C,
+) -> Result<String, amqprs::error::Error>
+where
+    C: AsyncConsumer + Send + 'static,
+{
+    channel.basic_qos(opts.qos_arguments()).await?;
+    channel
+        .basic_consume(consumer, opts.consume_arguments())
+        .await
+}
+
+/// Counts the deliveries a consumer has received and not yet settled.
+#[derive(Clone, Debug)]
+pub struct InFlight {
+    count: Arc<watch::Sender<usize>>,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self {
+            count: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+impl InFlight {
+    /// Counts a delivery until the returned guard is dropped.
+    fn enter(&self) -> InFlightGuard {
+        self.count.send_modify(|count| *count += 1);
+        InFlightGuard(self.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.borrow()
+    }
+
+    /// Waits until every delivery has been settled.
+    pub async fn settled(&self) {
+        _ = self.count.subscribe().wait_for(|count| *count == 0).await;
+    }
+}
+
+struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.count.send_modify(|count| *count -= 1);
+    }
+}
+
+/// A consumer started by `HandlerConsumer::start`.
+pub struct ConsumerHandle {
+    channel: Channel,
+    consumer_tag: String,
+    in_flight: InFlight,
+}
+
+impl ConsumerHandle {
+    pub fn consumer_tag(&self) -> &str {
+        &self.consumer_tag
+    }
+
+    /// Cancels the consumer so the broker stops delivering to it, waits up to `timeout` for
+    /// the deliveries it is handling to be settled, then closes its channel. Deliveries still
+    /// unsettled when the channel closes are requeued by the broker.
+    pub async fn shutdown(self, timeout: Duration) -> Result<(), amqprs::error::Error> {
+        let cancel = self
+            .channel
+            .basic_cancel(BasicCancelArguments::new(&self.consumer_tag));
+        let close = self.channel.clone().close();
+        drain_and_close(cancel, &self.in_flight, timeout, close).await
+    }
+}
+
+/// Runs `cancel`, waits up to `wait` for `in_flight` to be settled, then runs `close`.
+async fn drain_and_close<T, E>(
+    cancel: impl Future<Output = Result<T, E>>,
+    in_flight: &InFlight,
+    wait: Duration,
+    close: impl Future<Output = Result<(), E>>,
+) -> Result<(), E> {
+    cancel.await?;
+    if timeout(wait, in_flight.settled()).await.is_err() {
+        warn!(
+            "{} deliveries were not settled before the consumer shut down.",
+            in_flight.count()
+        );
+    }
+    close.await
+}
+
+#[cfg(test)]
+mod test_consume {
+    use super::{
+        AckMode, ConsumerOptions, DEFAULT_PREFETCH, Delivery, HandlerConsumer, InFlight,
+        MessageHandler, Settlement, drain_and_close,
+    };
+    use amqprs::{BasicProperties, FieldTable, FieldValue};
+    use async_trait::async_trait;
+    use std::{cell::RefCell, error::Error, time::Duration};
+    use tokio::time::{Instant, sleep};
+
+    /// Fails its first `failures` messages, counting every message it is given.
+    #[derive(Default)]
+    struct FlakyHandler {
+        failures: usize,
+        handled: usize,
+    }
+
+    #[async_trait]
+    impl MessageHandler for FlakyHandler {
+        async fn handle(
+            &mut self,
+            _delivery: &Delivery,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.handled += 1;
+            if self.handled <= self.failures {
+                Err("the database is down".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn with_delivery_count(count: i64) -> Delivery {
+        let mut headers = FieldTable::new();
+        headers.insert("x-delivery-count".try_into().unwrap(), FieldValue::l(count));
+        Delivery {
+            redelivered: count > 0,
+            properties: BasicProperties::default().with_headers(headers).finish(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn handled_message_is_acked() {
+        let mut consumer = HandlerConsumer::new(FlakyHandler::default(), 3);
+        assert_eq!(
+            Settlement::Ack,
+            consumer.process(&Delivery::default()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_message_is_requeued_then_dead_lettered() {
+        let handler = FlakyHandler {
+            failures: 3,
+            ..Default::default()
+        };
+        let mut consumer = HandlerConsumer::new(handler, 2);
+
+        let settlements = [
+            consumer.process(&with_delivery_count(0)).await,
+            consumer.process(&with_delivery_count(1)).await,
+            consumer.process(&with_delivery_count(2)).await,
+        ];
+        assert_eq!(
+            [
+                Settlement::Requeue,
+                Settlement::Requeue,
+                Settlement::DeadLetter
+            ],
+            settlements
+        );
+        assert_eq!(3, consumer.handler().handled);
+    }
+
+    /// Accepts only messages routed with `wanted`.
+    struct RoutingKeyHandler {
+        wanted: &'static str,
+        handled: usize,
+    }
+
+    #[async_trait]
+    impl MessageHandler for RoutingKeyHandler {
+        async fn handle(
+            &mut self,
+            _delivery: &Delivery,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.handled += 1;
+            Ok(())
+        }
+
+        fn accepts(&self, delivery: &Delivery) -> bool {
+            delivery.routing_key == self.wanted
+        }
+    }
+
+    #[tokio::test]
+    async fn declined_message_is_settled_without_handling() {
+        let handler = RoutingKeyHandler {
+            wanted: "files.delete",
+            handled: 0,
+        };
+        let mut consumer = HandlerConsumer::new(handler, 3);
+        let other = Delivery {
+            routing_key: "files.new".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(Settlement::Ack, consumer.process(&other).await);
+
+        consumer.with_declined(Settlement::Requeue);
+        assert_eq!(Settlement::Requeue, consumer.process(&other).await);
+        assert_eq!(0, consumer.handler().handled);
+
+        let wanted = Delivery {
+            routing_key: "files.delete".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(Settlement::Ack, consumer.process(&wanted).await);
+        assert_eq!(1, consumer.handler().handled);
+    }
+
+    #[test]
+    fn redeliveries_fall_back_to_flag() {
+        let first = Delivery::default();
+        let again = Delivery {
+            redelivered: true,
+            ..Default::default()
+        };
+        assert_eq!(0, first.redeliveries());
+        assert_eq!(1, again.redeliveries());
+        assert_eq!(4, with_delivery_count(4).redeliveries());
+    }
+
+    #[test]
+    fn consumer_options_arguments() {
+        let opts = ConsumerOptions::new("files").with_prefetch(25).build();
+        let qos = opts.qos_arguments();
+        assert_eq!(25, qos.prefetch_count);
+        assert_eq!(0, qos.prefetch_size);
+        assert!(!qos.global);
+        assert_eq!("files", opts.consume_arguments().queue);
+
+        assert_eq!(DEFAULT_PREFETCH, ConsumerOptions::new("files").prefetch());
+    }
+
+    #[test]
+    fn consume_arguments_follow_ack_mode() {
+        let manual = ConsumerOptions::new("files");
+        assert_eq!(AckMode::Manual, manual.ack_mode());
+        assert!(!manual.consume_arguments().no_ack);
+
+        let auto = ConsumerOptions::new("files")
+            .with_ack_mode(AckMode::Auto)
+            .build();
+        assert!(auto.consume_arguments().no_ack);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_then_closes_after_deliveries_settle() {
+        let in_flight = InFlight::default();
+        let delivery = in_flight.enter();
+        let events = RefCell::new(vec![]);
+        let start = Instant::now();
+
+        let shutdown = drain_and_close(
+            async {
+                events.borrow_mut().push("cancel");
+                Ok::<_, ()>(())
+            },
+            &in_flight,
+            Duration::from_secs(5),
+            async {
+                events.borrow_mut().push("close");
+                Ok(())
+            },
+        );
+        let handle = async {
+            sleep(Duration::from_secs(1)).await;
+            events.borrow_mut().push("settle");
+            drop(delivery);
+        };
+        let (result, ()) = tokio::join!(shutdown, handle);
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["cancel", "settle", "close"], *events.borrow());
+        assert_eq!(Duration::from_secs(1), start.elapsed());
+        assert_eq!(0, in_flight.count());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_closes_after_timeout() {
+        let in_flight = InFlight::default();
+        let _stuck = in_flight.enter();
+        let closed = RefCell::new(false);
+        let start = Instant::now();
+
+        drain_and_close(
+            async { Ok::<_, ()>(()) },
+            &in_flight,
+            Duration::from_secs(5),
+            async {
+                *closed.borrow_mut() = true;
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(*closed.borrow());
+        assert_eq!(Duration::from_secs(5), start.elapsed());
+        assert_eq!(1, in_flight.count());
+    }
+}
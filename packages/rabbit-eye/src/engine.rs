@@ -1,69 +1,849 @@
 use std::{
+    cell::Cell,
+    collections::VecDeque,
     error::Error,
-    fmt::Display,
-    io::Write,
-    time::{Duration, Instant},
+    fmt::{Debug, Display},
+    future::poll_fn,
+    hash::{DefaultHasher, Hasher},
+    pin::pin,
+    rc::Rc,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant, SystemTime},
 };
+#[cfg(any(feature = "health", feature = "metrics"))]
+use tokio::spawn;
 use tokio::{
     select,
-    signal::ctrl_c,
-    spawn,
-    task::{JoinError, JoinHandle},
-    time::{Interval, interval, sleep, timeout},
+    sync::{Mutex, mpsc},
+    task::{JoinError, JoinHandle, LocalSet, spawn_local},
+    time::{sleep, timeout, timeout_at},
 };
 use tokio_util::sync::CancellationToken;
 
-pub async fn run() -> Result<(), Box<dyn Error>> {
-    let life = AppLifetime::start();
+use futures::{FutureExt, Stream, StreamExt};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 
-    let loop_worker = loop_until_cancel(life.natural(), life.graceful());
-    life.run_until_abort(loop_worker).await;
+use crate::{
+    key::PublishKey,
+    lifetime::AppLifetime,
+    sink::ChangeSink,
+    state::{
+        ChangeDetector, ChangeDetectorResult, DetectorEvent, StateChange, StatePersistence,
+        TableState,
+    },
+    sync::{RaceOutcome, run_until_cancelled_or_timeout},
+    time::{ScheduleHooks, ScheduleMode, ScheduleOptions, ScheduleOverlap, Scheduler},
+};
 
-    Ok(())
+/// Settings for the engine loop.
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    schedule: ScheduleOptions,
+    max_backoff: Duration,
+    backoff_factor: f64,
+    max_run_time: Option<Duration>,
+    max_runs: Option<usize>,
+    grace_period: Duration,
+    natural_timeout: Duration,
+    graceful_timeout: Duration,
+    dry_run: bool,
+    source: String,
+    channel_capacity: usize,
+    #[cfg(feature = "health")]
+    health_addr: Option<std::net::SocketAddr>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<std::net::SocketAddr>,
 }
 
-async fn loop_until_cancel(stop_loop: CancellationToken, stop_work: CancellationToken) {
-    let dur = Duration::from_secs(5);
-    let mut interval = interval(dur);
+impl EngineConfig {
+    /// Fails if `interval` is zero, which would run the work back to back.
+    pub fn new(interval: Duration) -> Result<Self, ZeroIntervalError> {
+        if interval.is_zero() {
+            return Err(ZeroIntervalError);
+        }
+
+        let schedule = ScheduleOptions::new(interval, ScheduleOverlap::default());
+        Ok(Self {
+            schedule,
+            ..Default::default()
+        })
+    }
+
+    /// How to handle an interval that is reached while the previous work is still running.
+    pub fn with_overlap(&mut self, overlap: ScheduleOverlap) -> &mut Self {
+        self.schedule.with_overlap(overlap);
+        self
+    }
+
+    /// Whether the interval is measured from the start or the end of each run. See
+    /// `ScheduleMode`.
+    pub fn with_mode(&mut self, mode: ScheduleMode) -> &mut Self {
+        self.schedule.with_mode(mode);
+        self
+    }
 
-    let mut work = RenewableWorker::new();
-    while !stop_loop.is_cancelled() {
-        println!("Waiting for next interval...");
-        if let None = stop_loop.run_until_cancelled(interval.tick()).await {
-            break;
+    /// Whether the first run starts as soon as the engine starts, rather than after the first
+    /// interval. This is the default.
+    pub fn with_run_immediately(&mut self, run_immediately: bool) -> &mut Self {
+        self.schedule.with_run_immediately(run_immediately);
+        self
+    }
+
+    /// Delays each tick by a random offset less than `jitter`. See `ScheduleOptions::with_jitter`.
+    pub fn with_jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.schedule.with_jitter(jitter);
+        self
+    }
+
+    /// The longest wait between runs while the work keeps failing.
+    pub fn with_max_backoff(&mut self, max_backoff: Duration) -> &mut Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// How much longer each wait is than the previous one while the work keeps failing.
+    /// Factors below `1.0` are treated as `1.0`.
+    pub fn with_backoff_factor(&mut self, backoff_factor: f64) -> &mut Self {
+        self.backoff_factor = backoff_factor;
+        self
+    }
+
+    /// The longest a single run may take before it is cancelled. A run that does not stop
+    /// within the grace period after being cancelled is aborted and recorded as failed.
+    pub fn with_max_run_time(&mut self, max_run_time: Duration) -> &mut Self {
+        self.max_run_time = Some(max_run_time);
+        self
+    }
+
+    /// Stops after starting `max_runs` runs, once they finish, instead of running until the app
+    /// is stopped.
+    pub fn with_max_runs(&mut self, max_runs: usize) -> &mut Self {
+        self.max_runs = Some(max_runs);
+        self
+    }
+
+    /// How long a run has to stop after it is cancelled before it is aborted, whether it ran
+    /// past its max run time or was overlapped by the next run. It is 5 seconds unless set.
+    pub fn with_grace_period(&mut self, grace_period: Duration) -> &mut Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// How long the app waits at each stage of stopping before escalating to the next: for the
+    /// running work to finish after the natural stop, and then for it to publish the changes it
+    /// found after being cancelled by the graceful stop. Both are 5 seconds unless set, and the
+    /// engine fails to start if either is zero. See `AppLifetime`.
+    pub fn with_shutdown_timeouts(&mut self, natural: Duration, graceful: Duration) -> &mut Self {
+        self.natural_timeout = natural;
+        self.graceful_timeout = graceful;
+        self
+    }
+
+    /// Logs the changes each run finds instead of publishing them, and never saves the state.
+    /// The state is loaded at the start of every run, so each run reports the changes since the
+    /// last saved state.
+    pub fn with_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// The name of the system the changes come from, like `filesystem`, which is `default`
+    /// unless set. It is the `source` of each change's envelope and labels the engine's metrics.
+    pub fn with_source(&mut self, source: impl Into<String>) -> &mut Self {
+        self.source = source.into();
+        self
+    }
+
+    /// How many changes a run may find ahead of those its sink has taken to publish, which is
+    /// 64 unless set. Detection waits while that many are waiting, so a detector that streams
+    /// its changes never holds many more in memory than this. The sink receives the changes in
+    /// batches of up to this many. Capacities below `1` are treated as `1`.
+    pub fn with_channel_capacity(&mut self, channel_capacity: usize) -> &mut Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    /// Serves the engine's health on `addr` while it runs. See the `health` module.
+    #[cfg(feature = "health")]
+    pub fn with_health_addr(&mut self, addr: std::net::SocketAddr) -> &mut Self {
+        self.health_addr = Some(addr);
+        self
+    }
+
+    /// Serves the engine's metrics on `addr` while it runs. See the `metrics` module.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics_addr(&mut self, addr: std::net::SocketAddr) -> &mut Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Runs on `schedule` instead of a fixed interval, such as a cron schedule. This replaces
+    /// the overlap behavior and jitter set before it.
+    pub fn with_schedule(&mut self, schedule: ScheduleOptions) -> &mut Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn build(&self) -> Self {
+        self.clone()
+    }
+
+    /// The time between the start of each run of the work while it is succeeding.
+    pub fn interval(&self) -> Duration {
+        self.schedule.interval()
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+
+    pub fn backoff_factor(&self) -> f64 {
+        self.backoff_factor
+    }
+
+    pub fn max_run_time(&self) -> Option<Duration> {
+        self.max_run_time
+    }
+
+    pub fn max_runs(&self) -> Option<usize> {
+        self.max_runs
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn natural_timeout(&self) -> Duration {
+        self.natural_timeout
+    }
+
+    pub fn graceful_timeout(&self) -> Duration {
+        self.graceful_timeout
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity.max(1)
+    }
+
+    /// The wait before the next run after `failures` consecutive failed runs. This is the
+    /// interval multiplied by the backoff factor once per failure, up to the max backoff.
+    pub fn backoff(&self, failures: usize) -> Duration {
+        let factor = self
+            .backoff_factor
+            .max(1.0)
+            .powi(failures.min(i32::MAX as usize) as i32);
+        Duration::try_from_secs_f64(self.interval().as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff.max(self.interval()))
+    }
+
+    pub fn schedule(&self) -> &ScheduleOptions {
+        &self.schedule
+    }
+
+    #[cfg(feature = "health")]
+    pub fn health_addr(&self) -> Option<std::net::SocketAddr> {
+        self.health_addr
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(&self) -> Option<std::net::SocketAddr> {
+        self.metrics_addr
+    }
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            schedule: ScheduleOptions::default(),
+            max_backoff: Duration::from_secs(300),
+            backoff_factor: 2.0,
+            max_run_time: None,
+            max_runs: None,
+            grace_period: Duration::from_secs(5),
+            natural_timeout: Duration::from_secs(5),
+            graceful_timeout: Duration::from_secs(5),
+            dry_run: false,
+            source: "default".to_string(),
+            channel_capacity: 64,
+            #[cfg(feature = "health")]
+            health_addr: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
         }
+    }
+}
+
+/// The engine interval was zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroIntervalError;
+
+impl Display for ZeroIntervalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the engine interval must be greater than zero")
+    }
+}
+
+impl Error for ZeroIntervalError {}
+
+/// A snapshot of the engine's progress, for health checks.
+#[derive(Clone, Debug, Default)]
+pub struct EngineStatus {
+    /// Whether the engine loop is running.
+    pub running: bool,
+    /// When the engine loop started.
+    pub started: Option<SystemTime>,
+    /// When the last run finished, whether or not it failed.
+    pub last_tick: Option<SystemTime>,
+    /// When the last run that did not fail finished.
+    pub last_success: Option<SystemTime>,
+    /// Why the most recent failed run failed, even if runs have succeeded since.
+    pub last_error: Option<String>,
+    /// The number of changes published by the last run, or zero if it failed.
+    pub last_change_count: usize,
+    /// The number of runs in a row that have failed.
+    pub consecutive_failures: usize,
+    /// Whether the sink's message broker is connected, or `None` if the sink does not report
+    /// one.
+    pub broker_connected: Option<bool>,
+}
+
+/// Reads the status of a running engine from any task.
+#[derive(Clone, Debug, Default)]
+pub struct StatusHandle {
+    status: Arc<std::sync::Mutex<EngineStatus>>,
+}
+
+impl StatusHandle {
+    pub fn status(&self) -> EngineStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Reports whether the sink's message broker is connected.
+    pub fn set_broker_connected(&self, connected: bool) {
+        self.update(|status| status.broker_connected = Some(connected));
+    }
 
-        let token = stop_work.child_token();
-        let work_token = token.clone();
-        print!("Next interval reached. Work is running... ");
-        work.finish_and_renew(
-            async move {
-                for i in 1..15 {
-                    work_token
-                        .run_until_cancelled(sleep(Duration::from_secs(1)))
-                        .await;
-                    print!("{} ", i);
-                    _ = std::io::stdout().flush();
+    pub(crate) fn update(&self, f: impl FnOnce(&mut EngineStatus)) {
+        f(&mut self.status.lock().unwrap())
+    }
+
+    /// Records a finished run that published `published` changes, and failed if there is an
+    /// `error`.
+    pub(crate) fn record_tick(&self, published: usize, error: Option<String>) {
+        let now = SystemTime::now();
+        self.update(|status| {
+            status.last_tick = Some(now);
+            status.last_change_count = published;
+            match error {
+                None => {
+                    status.last_success = Some(now);
+                    status.consecutive_failures = 0;
                 }
-                println!("!");
-            },
+                Some(e) => {
+                    status.last_error = Some(e);
+                    status.consecutive_failures += 1;
+                }
+            }
+        });
+    }
+}
+
+/// Runs `detector` once per interval until the app is stopped or the config's max runs have
+/// finished, publishing the changes it finds
+/// to `sink` and saving the state with `persistence` after each run. The state is kept in memory
+/// between runs if the persistence retains it, and loaded before each run otherwise. Each change
+/// is published with its `ChangeEnvelope` as JSON as the payload, or without the `serde` feature,
+/// its display form, like `new (orders, 42)`.
+///
+/// The returned handle reads the engine's status while the returned future runs it.
+pub fn run<D, S, P>(
+    detector: D,
+    sink: S,
+    persistence: P,
+    config: EngineConfig,
+) -> (
+    StatusHandle,
+    impl Future<Output = Result<(), Box<dyn Error>>>,
+)
+where
+    D: ChangeDetector + Clone + 'static,
+    D::Key: PublishKey + Debug,
+    D::Hash: std::hash::Hash,
+    S: ChangeSink<D::Key> + 'static,
+    P: StatePersistence + 'static,
+    P::State: TableState<D::Key, D::Hash> + 'static,
+{
+    let status = StatusHandle::default();
+    let engine_status = status.clone();
+
+    let run = async move {
+        let life = AppLifetime::with_timeouts(config.natural_timeout(), config.graceful_timeout())?;
+
+        #[cfg(feature = "health")]
+        let health = match config.health_addr() {
+            Some(addr) => {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let stop = life.graceful().child_token();
+                let server = crate::health::serve(
+                    listener,
+                    engine_status.clone(),
+                    config.clone(),
+                    stop.clone(),
+                );
+                Some((spawn(server), stop))
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "metrics")]
+        let metrics = crate::metrics::Metrics::new(config.source());
+        #[cfg(feature = "metrics")]
+        let metrics_server = match config.metrics_addr() {
+            Some(addr) => {
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let stop = life.graceful().child_token();
+                let server = crate::metrics::serve(listener, metrics.clone(), stop.clone());
+                Some((spawn(server), stop))
+            }
+            None => None,
+        };
+
+        let engine = Rc::new(Engine {
+            detector,
+            sink,
+            persistence,
+            // State that is not retained is loaded at the start of each run instead.
+            state: Mutex::new(if P::retain() {
+                P::load().await?
+            } else {
+                Default::default()
+            }),
+            status: engine_status,
+            runs: Cell::new(0),
+            dry_run: config.dry_run(),
+            channel_capacity: config.channel_capacity(),
+            #[cfg(feature = "serde")]
+            source: config.source().to_string(),
+            #[cfg(feature = "metrics")]
+            metrics,
+        });
+
+        // The detector's futures are not required to be `Send`, so the work runs on this thread.
+        let loop_worker = loop_until_cancel(
+            config,
+            StdRng::from_os_rng(),
+            &engine.status,
+            life.natural(),
+            life.graceful(),
+            |token| engine.clone().tick(token),
+        );
+        LocalSet::new()
+            .run_until(life.run_until_abort(loop_worker))
+            .await;
+
+        #[cfg(feature = "health")]
+        if let Some((server, stop)) = health {
+            stop.cancel();
+            server.await??;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some((server, stop)) = metrics_server {
+            stop.cancel();
+            server.await??;
+        }
+
+        Ok(())
+    };
+
+    (status, run)
+}
+
+/// The parts of the engine shared by every run of the work.
+struct Engine<D, S, P>
+where
+    P: StatePersistence,
+{
+    detector: D,
+    sink: S,
+    persistence: P,
+    /// Runs that overlap wait for the previous one to release the state.
+    state: Mutex<P::State>,
+    status: StatusHandle,
+    /// The number of runs started.
+    runs: Cell<usize>,
+    /// Whether changes are logged instead of published. See `EngineConfig::with_dry_run`.
+    dry_run: bool,
+    /// See `EngineConfig::with_channel_capacity`.
+    channel_capacity: usize,
+    /// The `source` of each change's envelope. See `EngineConfig::with_source`.
+    #[cfg(feature = "serde")]
+    source: String,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::Metrics,
+}
+
+impl<D, S, P> Engine<D, S, P>
+where
+    D: ChangeDetector + Clone,
+    D::Key: PublishKey + Debug,
+    D::Hash: std::hash::Hash,
+    S: ChangeSink<D::Key>,
+    P: StatePersistence,
+    P::State: TableState<D::Key, D::Hash>,
+{
+    /// Detects the changes since the previous run, publishes them, and saves the state.
+    /// Runs the work once, returning the number of changes published, or why the run failed.
+    async fn tick(self: Rc<Self>, cancel: CancellationToken) -> Result<usize, String> {
+        let run = self.runs.get() + 1;
+        self.runs.set(run);
+        let started = Instant::now();
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "tick",
+            run,
+            changes = tracing::field::Empty,
+            duration_ms = tracing::field::Empty
+        );
+        let detect = self.detect_and_publish(&cancel);
+        #[cfg(feature = "tracing")]
+        let detect = tracing::Instrument::instrument(detect, span.clone());
+        let (published, error) = detect.await;
+
+        let duration = started.elapsed();
+        #[cfg(feature = "tracing")]
+        span.in_scope(|| {
+            span.record("changes", published);
+            span.record("duration_ms", duration.as_millis() as u64);
+            tracing::info!(failed = error.is_some(), "The run finished.");
+        });
+        #[cfg(not(feature = "tracing"))]
+        println!(
+            "Run {} finished in {:?}. {} change(s) published.",
+            run, duration, published
+        );
+
+        let result = match error {
+            None => Ok(published),
+            Some(e) => Err(e),
+        };
+        #[cfg(feature = "metrics")]
+        self.metrics.record_tick(duration, &result);
+        result
+    }
+
+    /// Returns the number of changes published, and why the run failed if it did. A run that
+    /// faulted still publishes the changes it found. A dry run returns the number of changes it
+    /// logged. The changes are published while the detector runs. A change that could not be
+    /// published is settled back out of the state, so the next run publishes it again, and the
+    /// run fails. None are counted if the detector aborts, since the state is not saved and the
+    /// next run publishes them again.
+    async fn detect_and_publish(&self, cancel: &CancellationToken) -> (usize, Option<String>) {
+        let mut state = self.state.lock().await;
+        if !P::retain() || self.dry_run {
+            match P::load().await {
+                Ok(loaded) => *state = loaded,
+                Err(e) => {
+                    let e = format!("The state could not be loaded. {}", e);
+                    error!("{}", e);
+                    return (0, Some(e));
+                }
+            }
+        }
+
+        // The detector is not polled while the channel is full, so it finds changes only as
+        // fast as the sink publishes them.
+        let (sender, receiver) = mpsc::channel(self.channel_capacity);
+        let events = self.detector.clone().stream_changes(&mut *state, cancel);
+        let (result, (published, failed)) =
+            tokio::join!(forward(events, sender), self.publish(receiver));
+        let failures = failed.len();
+        state.settle(failed);
+
+        let mut error = None;
+        match &result {
+            Some(ChangeDetectorResult::Faulted(e)) => {
+                let e = format!("The change detector faulted. {}", e);
+                error!("{}", e);
+                error = Some(e);
+            }
+            Some(ChangeDetectorResult::Aborted) => {
+                let e = "The change detector aborted. The state was not saved.".to_string();
+                error!("{}", e);
+                return (0, Some(e));
+            }
+            Some(_) => {}
+            None => {
+                let e = "The change detector stopped without finishing. The state was not saved."
+                    .to_string();
+                error!("{}", e);
+                return (0, Some(e));
+            }
+        }
+        if failures > 0 {
+            let e = format!(
+                "{} change(s) could not be published and will be published by the next run.",
+                failures
+            );
+            error!("{}", e);
+            error = error.or(Some(e));
+        }
+        if self.dry_run {
+            return (published, error);
+        }
+        if let Err(e) = self.persistence.save(&state).await {
+            let e = format!("The state could not be saved. {}", e);
+            error!("{}", e);
+            error = error.or(Some(e));
+        }
+
+        (published, error)
+    }
+
+    /// Publishes the changes received until the detector finishes, in batches of what has
+    /// arrived, up to the channel capacity. Returns the number of changes published, and the keys
+    /// of the changes that could not be. The changes of a batch that fails are published one at
+    /// a time, so only those that fail again are not published. A dry run logs the changes
+    /// instead.
+    async fn publish(
+        &self,
+        mut receiver: mpsc::Receiver<(StateChange<D::Key>, Option<u64>)>,
+    ) -> (usize, Vec<D::Key>) {
+        let mut published = 0;
+        let mut failed = vec![];
+        let capacity = self.channel_capacity;
+        let mut changes = Vec::with_capacity(capacity);
+        while receiver.recv_many(&mut changes, capacity).await > 0 {
+            if self.dry_run {
+                for (change, _) in changes.drain(..) {
+                    info!("Dry run: {:?}", change);
+                    published += 1;
+                }
+                continue;
+            }
+            let mut batch = Vec::with_capacity(changes.len());
+            for (change, row_hash) in changes.drain(..) {
+                match self.payload(&change) {
+                    Ok(payload) => batch.push((change, row_hash, payload)),
+                    Err(e) => {
+                        error!("The change {:?} could not be written. {}", change, e);
+                        failed.push(change.into_key());
+                    }
+                }
+            }
+            let sent = match self.sink.publish_batch(&batch).await {
+                Ok(()) => batch,
+                Err(e) => {
+                    #[cfg(feature = "metrics")]
+                    self.metrics.record_publish_error();
+                    error!("The changes could not be published. {}", e);
+                    self.publish_each(batch, &mut failed).await
+                }
+            };
+            #[cfg(feature = "metrics")]
+            for (change, _, _) in &sent {
+                self.metrics.record_change(change);
+            }
+            published += sent.len();
+        }
+        (published, failed)
+    }
+
+    /// Publishes each change of `batch` on its own, adding the keys of those that fail to
+    /// `failed`, and returns those that were published.
+    async fn publish_each(
+        &self,
+        batch: Vec<Message<D::Key>>,
+        failed: &mut Vec<D::Key>,
+    ) -> Vec<Message<D::Key>> {
+        let mut sent = Vec::with_capacity(batch.len());
+        for (change, row_hash, payload) in batch {
+            match self.sink.publish(&change, row_hash, &payload).await {
+                Ok(()) => sent.push((change, row_hash, payload)),
+                Err(e) => {
+                    error!("The change {:?} could not be published. {}", change, e);
+                    failed.push(change.into_key());
+                }
+            }
+        }
+        sent
+    }
+
+    /// The message body of `change`, which is its envelope as JSON.
+    #[cfg(feature = "serde")]
+    fn payload(&self, change: &StateChange<D::Key>) -> Result<Vec<u8>, String> {
+        crate::envelope::ChangeEnvelope::new(&self.source, self.detector.name(), change.clone())
+            .to_json()
+            .map_err(|e| e.to_string())
+    }
+
+    /// The message body of `change`, which is its display form.
+    #[cfg(not(feature = "serde"))]
+    fn payload(&self, change: &StateChange<D::Key>) -> Result<Vec<u8>, String> {
+        Ok(change.to_string().into_bytes())
+    }
+}
+
+/// A change with its row hash and payload, as handed to the sink.
+type Message<Key> = (StateChange<Key>, Option<u64>, Vec<u8>);
+
+/// Sends the changes of `events` to `sender` with their row hashes until the detector finishes,
+/// and returns how it finished. Returns `None` if the stream ends without finishing, or if the
+/// receiver is dropped because publishing failed, in which case the rest of the changes are
+/// dropped.
+async fn forward<Key, Hash: std::hash::Hash>(
+    events: impl Stream<Item = DetectorEvent<Key, Hash>>,
+    sender: mpsc::Sender<(StateChange<Key>, Option<u64>)>,
+) -> Option<ChangeDetectorResult> {
+    let mut events = pin!(events);
+    while let Some(event) = events.next().await {
+        match event {
+            DetectorEvent::Change(change, hash) => {
+                let row_hash = hash.map(|hash| {
+                    let mut hasher = DefaultHasher::new();
+                    hash.hash(&mut hasher);
+                    hasher.finish()
+                });
+                sender.send((change, row_hash)).await.ok()?
+            }
+            DetectorEvent::Finished(result) => return Some(result),
+        }
+    }
+    None
+}
+
+/// Starts the future returned by `work` on the config's schedule until `stop_loop` is
+/// cancelled, or until the config's max runs have started. See `Scheduler::run_with`.
+///
+/// Once the loop stops, the work still running is waited for until `stop_work` is cancelled.
+/// Then the work is cancelled, so a run finishes the changes it has found, and it is aborted only
+/// if it does not finish within the config's graceful timeout. While the work is failing
+/// according to `status`, the ticks are spaced by the config's backoff instead of the interval.
+async fn loop_until_cancel<F>(
+    config: EngineConfig,
+    rng: impl Rng,
+    status: &StatusHandle,
+    stop_loop: CancellationToken,
+    stop_work: CancellationToken,
+    mut work: impl FnMut(CancellationToken) -> F,
+) where
+    F: Future<Output = Result<usize, String>> + 'static,
+{
+    let mut scheduler = Scheduler::new(config.schedule().clone());
+    scheduler
+        .with_grace_period(config.grace_period())
+        .with_stop_timeout(config.graceful_timeout());
+    if let Some(max_runs) = config.max_runs() {
+        scheduler.with_max_runs(max_runs);
+    }
+
+    status.update(|status| {
+        status.running = true;
+        status.started = Some(SystemTime::now());
+    });
+    let mut hooks = EngineHooks {
+        config: &config,
+        status,
+    };
+    let run = |token: CancellationToken| {
+        limit_run_time(
+            work(token.clone()),
             token,
-            Duration::from_secs(5),
+            config.max_run_time(),
+            config.grace_period(),
         )
+    };
+    scheduler
+        .run_with(rng, &mut hooks, stop_loop, stop_work, run)
         .await;
+
+    status.update(|status| status.running = false);
+    info!("Work stopped.");
+}
+
+/// Records the engine's finished runs in its status, and backs off while its runs are failing.
+struct EngineHooks<'a> {
+    config: &'a EngineConfig,
+    status: &'a StatusHandle,
+}
+
+impl ScheduleHooks<Result<usize, String>> for EngineHooks<'_> {
+    fn finished(&mut self, finished: Vec<Result<Result<usize, String>, JoinError>>) {
+        for result in finished {
+            match result {
+                Ok(Ok(published)) => self.status.record_tick(published, None),
+                Ok(Err(e)) => self.status.record_tick(0, Some(e)),
+                Err(e) if e.is_panic() => {
+                    let message = format!("The work panicked. {}", e);
+                    error!("{}", message);
+                    self.status.record_tick(0, Some(message));
+                }
+                // Work is only aborted once it is no longer waited for.
+                Err(_) => {}
+            }
+        }
+    }
+
+    fn backoff(&mut self) -> Option<Duration> {
+        // Failures are known by the next tick as long as the work finishes within the interval.
+        let failures = self.status.status().consecutive_failures;
+        (failures > 0).then(|| self.config.backoff(failures))
     }
+}
 
-    // Try to wait for the work to complete, unless the `stop_work` token is cancelled
-    _ = stop_work.run_until_cancelled(work.wait()).await;
+/// Runs `work` for up to `max_run_time`, then cancels `token` and drops the work if it does not
+/// finish within `grace_period`. Work that is dropped returns why instead.
+async fn limit_run_time(
+    work: impl Future<Output = Result<usize, String>>,
+    token: CancellationToken,
+    max_run_time: Option<Duration>,
+    grace_period: Duration,
+) -> Result<usize, String> {
+    let Some(max_run_time) = max_run_time else {
+        return work.await;
+    };
 
-    // Then try canceling it, and aborting if that does not work
-    _ = work.close_with_abort_after(Duration::from_secs(5)).await;
+    let deadline = tokio::time::Instant::now() + max_run_time;
+    let mut work = pin!(work);
+    match run_until_cancelled_or_timeout(&token, max_run_time, &mut work).await {
+        RaceOutcome::Completed(result) => return result,
+        // A run that was stopped or replaced is still held to its maximum run time.
+        RaceOutcome::Cancelled => {
+            if let Ok(result) = timeout_at(deadline, &mut work).await {
+                return result;
+            }
+        }
+        RaceOutcome::TimedOut => {}
+    }
 
-    println!("Work stopped.");
+    warn!("The run exceeded its maximum run time. Cancelling...");
+    token.cancel();
+    timeout(grace_period, &mut work).await.unwrap_or_else(|_| {
+        let message = "The run did not stop after it was cancelled and was aborted.";
+        error!("{}", message);
+        Err(message.to_string())
+    })
 }
 
-struct RenewableWorker {
-    handle: Option<(JoinHandle<()>, CancellationToken)>,
+/// Runs work on the current `LocalSet`, replacing or skipping work that is still running
+/// according to a `ScheduleOverlap`.
+pub(crate) struct RenewableWorker<T> {
+    /// The running work, oldest first.
+    handles: VecDeque<(JoinHandle<T>, CancellationToken)>,
+    /// The number of consecutive renewals skipped while previous work was running.
+    skipped: usize,
+    /// The results of finished work, kept until they are taken with `take_finished`.
+    finished: Vec<Result<T, JoinError>>,
 }
 
 async fn wait_or_abort<T>(handle: JoinHandle<T>) -> Result<T, JoinError> {
@@ -101,143 +881,1484 @@ impl<T> Drop for AbortOnDropJoinHandle<T> {
     }
 }
 
-impl RenewableWorker {
-    fn new() -> Self {
-        Self { handle: None }
+impl<T: 'static> RenewableWorker<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            handles: VecDeque::new(),
+            skipped: 0,
+            finished: Vec::new(),
+        }
+    }
+
+    /// Removes finished work, keeping its result.
+    pub(crate) fn reap(&mut self) {
+        let finished = &mut self.finished;
+        self.handles.retain_mut(|(handle, _)| {
+            if !handle.is_finished() {
+                return true;
+            }
+            if let Some(result) = handle.now_or_never() {
+                finished.push(result);
+            }
+            false
+        });
+    }
+
+    /// Whether any work is still running.
+    pub fn is_running(&self) -> bool {
+        self.pending_count() > 0
     }
 
-    /// Stops current work (natural 5s, graceful 5s, then aborts).
-    /// Then starts the new future `f`.
+    /// The number of runs of work that have not finished.
+    pub fn pending_count(&self) -> usize {
+        self.handles
+            .iter()
+            .filter(|(handle, _)| !handle.is_finished())
+            .count()
+    }
+
+    /// Waits for some work to finish, then returns the results not yet taken. Waits forever if
+    /// no work is running.
+    pub(crate) async fn next_finished(&mut self) -> Vec<Result<T, JoinError>> {
+        poll_fn(|cx| {
+            for i in 0..self.handles.len() {
+                if let Poll::Ready(result) = self.handles[i].0.poll_unpin(cx) {
+                    // A finished handle must not be polled again when the worker is closed.
+                    self.handles.remove(i);
+                    self.finished.push(result);
+                    return Poll::Ready(());
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        self.take_finished()
+    }
+
+    /// Takes the results of work that has finished since they were last taken, oldest first.
+    pub(crate) fn take_finished(&mut self) -> Vec<Result<T, JoinError>> {
+        std::mem::take(&mut self.finished)
+    }
+
+    /// Starts the new future `f` once room is made for it according to `overlap`, returning
+    /// whether it was started.
     ///
-    /// It will cancel the token immediately, then wait `grace_period` to see if the previous task
-    /// finishes gracefully. If not, the previous task will be aborted.
-    pub async fn finish_and_renew<F>(&mut self, f: F, t: CancellationToken, grace_period: Duration)
+    /// - `AbortPrevious` finishes all current work first.
+    /// - `SkipNew` drops `f` while previous work is running, until `max` renewals in a row have
+    ///   been skipped. The previous work is then finished.
+    /// - `Overlap` runs `f` alongside the current work, finishing the oldest work first when
+    ///   `max` are already running.
+    ///
+    /// Work is finished by cancelling its token, then waiting `grace_period` to see if it
+    /// finishes gracefully. If not, it will be aborted.
+    pub async fn finish_and_renew<F>(
+        &mut self,
+        f: F,
+        t: CancellationToken,
+        overlap: ScheduleOverlap,
+        grace_period: Duration,
+    ) -> bool
     where
-        F: Future<Output = ()> + Send + 'static,
+        F: Future<Output = T> + 'static,
     {
-        if let Some((mut handle, cancel)) = self.handle.take() {
+        self.reap();
+
+        match overlap {
+            ScheduleOverlap::AbortPrevious => self.finish_oldest(0, grace_period).await,
+            ScheduleOverlap::SkipNew { max } => {
+                if self.is_running() && self.skipped < max {
+                    self.skipped += 1;
+                    return false;
+                }
+                self.finish_oldest(0, grace_period).await;
+            }
+            ScheduleOverlap::Overlap { max } => {
+                self.finish_oldest(max.saturating_sub(1), grace_period)
+                    .await
+            }
+        }
+
+        let handle = spawn_local(f);
+        self.handles.push_back((handle, t));
+        self.skipped = 0;
+        true
+    }
+
+    /// Finishes the oldest work until no more than `keep` are running.
+    async fn finish_oldest(&mut self, keep: usize, grace_period: Duration) {
+        while self.handles.len() > keep {
+            let Some((mut handle, cancel)) = self.handles.pop_front() else {
+                break;
+            };
+
             cancel.cancel();
             select! {
                 _ = sleep(grace_period) => {
                     handle.abort()
                 }
-                _ = &mut handle => {
+                r = &mut handle => {
+                    self.finished.push(r);
                 }
             }
         }
-
-        let handle = spawn(f);
-        self.handle = Some((handle, t));
     }
 
-    pub async fn wait(&mut self) -> Result<(), JoinError> {
-        if let Some((handle, _)) = self.handle.as_mut() {
-            handle.await?;
+    /// Waits for all work to finish, returning the results not yet taken. If this is
+    /// cancelled, the results of work that did finish are kept for `take_finished`.
+    pub async fn wait(&mut self) -> Vec<Result<T, JoinError>> {
+        while let Some((handle, _)) = self.handles.front_mut() {
+            let result = handle.await;
+            // A finished handle must not be polled again when the worker is closed.
+            self.handles.pop_front();
+            self.finished.push(result);
         }
 
-        Ok(())
+        self.take_finished()
     }
 
+    /// Cancels all work and waits up to `abort_after` for it to finish, returning the results
+    /// not yet taken. If the work does not finish in time, it is aborted and `None` is
+    /// returned.
     pub async fn close_with_abort_after(
         mut self,
         abort_after: Duration,
-    ) -> Option<Result<(), JoinError>> {
-        if let Some((handle, cancel)) = self.handle.take() {
-            cancel.cancel();
+    ) -> Option<Vec<Result<T, JoinError>>> {
+        let mut finished = self.take_finished();
+        let waiters: Vec<_> = self
+            .handles
+            .drain(..)
+            .map(|(handle, cancel)| {
+                cancel.cancel();
+                AbortOnDropJoinHandle::new(handle)
+            })
+            .collect();
 
-            let waiter = AbortOnDropJoinHandle::new(handle);
-            select! {
-                r = waiter.wait() => Some(r),
-                _ = sleep(abort_after) => None
+        let wait_all = async {
+            for waiter in waiters {
+                finished.push(waiter.wait().await);
             }
-        } else {
-            Some(Ok(()))
+            finished
+        };
+        select! {
+            r = wait_all => Some(r),
+            _ = sleep(abort_after) => None
         }
     }
 }
 
-impl Drop for RenewableWorker {
+impl<T> Drop for RenewableWorker<T> {
     fn drop(&mut self) {
-        if let Some((handle, _)) = self.handle.take() {
+        for (handle, _) in self.handles.drain(..) {
             handle.abort();
         }
     }
 }
 
-pub struct AppLifetime {
-    handle: JoinHandle<()>,
-    abort: CancellationToken,
-    graceful: CancellationToken,
-    natural: CancellationToken,
-}
+#[cfg(test)]
+mod test_engine {
+    use super::{
+        Engine, EngineConfig, RenewableWorker, StatusHandle, ZeroIntervalError, limit_run_time,
+        loop_until_cancel,
+    };
+    use crate::{
+        sink::{ChangeSink, DedupSink, SinkError, VecSink},
+        state::{
+            ChangeDetector, ChangeDetectorResult, DefaultTableState, DetectorEvent,
+            InMemoryPersistence, StateChange, StatePersistence, TableState,
+        },
+        time::{ScheduleMode, ScheduleOverlap},
+    };
+    use futures::{Stream, StreamExt, future, stream};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::{
+        cell::{Cell, RefCell},
+        error::Error,
+        rc::Rc,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+    use tokio::{sync::Mutex, task::LocalSet, time::Instant};
+    use tokio_util::sync::CancellationToken;
+
+    /// Reports `a` with the run number as its hash, and `b` on the first run only.
+    #[derive(Clone, Default)]
+    struct CountingDetector {
+        runs: Rc<AtomicUsize>,
+    }
 
-impl AppLifetime {
-    fn start() -> Self {
-        let abort = CancellationToken::new();
-        let graceful = abort.child_token();
-        let natural = graceful.child_token();
+    impl ChangeDetector for CountingDetector {
+        type Key = String;
+        type Hash = usize;
 
-        let ctrlc_abort = abort.clone();
-        let ctrlc_graceful = graceful.clone();
-        let ctrlc_natural = natural.clone();
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            state.set_row("a".to_string(), run);
+            if run == 0 {
+                state.set_row("b".to_string(), run);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+    }
 
-        let handle = spawn(async move {
-            _ = ctrl_c().await;
-            let five_secs = Duration::from_secs(5);
+    /// Faults on its first `failures` runs, recording when each run started.
+    #[derive(Clone, Default)]
+    struct FlakyDetector {
+        failures: usize,
+        runs: Rc<RefCell<Vec<Instant>>>,
+    }
 
-            // Indicate natural stop, and wait 5s
-            eprintln!("Stopping. Attempting natural stop.");
-            ctrlc_natural.cancel();
-            ctrlc_graceful.run_until_cancelled(sleep(five_secs)).await;
+    impl ChangeDetector for FlakyDetector {
+        type Key = String;
+        type Hash = usize;
 
-            // Indicate graceful stop, and wait 5s
-            eprintln!("Stopping. Attempting graceful stop.");
-            ctrlc_graceful.cancel();
-            ctrlc_abort.run_until_cancelled(sleep(five_secs)).await;
+        async fn rowhash(
+            self,
+            _state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let mut runs = self.runs.borrow_mut();
+            runs.push(Instant::now());
+            if runs.len() <= self.failures {
+                ChangeDetectorResult::Faulted("the source is down".into())
+            } else {
+                ChangeDetectorResult::DeleteRemainder
+            }
+        }
+    }
 
-            // Indicate abort and end this task. Anything racing this task will be stopped.
-            eprintln!("Stopping. Aborting.");
-            ctrlc_abort.cancel();
-        });
+    /// Records the debug form of each published change.
+    #[derive(Default)]
+    struct RecordingSink {
+        changes: RefCell<Vec<String>>,
+    }
 
-        Self {
-            handle,
-            abort,
-            graceful,
-            natural,
+    impl ChangeSink<String> for RecordingSink {
+        async fn publish(
+            &self,
+            change: &StateChange<String>,
+            _row_hash: Option<u64>,
+            _payload: &[u8],
+        ) -> Result<(), SinkError> {
+            self.changes.borrow_mut().push(format!("{:?}", change));
+            Ok(())
         }
     }
 
-    /// Runs a future until this app lifetime abort token is cancelled.
-    async fn run_until_abort<F>(&self, future: F) -> Option<F::Output>
-    where
-        F: Future,
-    {
-        select! {
-            _ = self.abort.cancelled() => None,
-            v = future => Some(v),
+    /// Finds `a`, then waits to be cancelled before reporting what it found.
+    #[derive(Clone, Default)]
+    struct InterruptedDetector {
+        started: Rc<Cell<bool>>,
+    }
+
+    impl ChangeDetector for InterruptedDetector {
+        type Key = String;
+        type Hash = usize;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            state.set_row("a".to_string(), 1);
+            self.started.set(true);
+            cancel.cancelled().await;
+            ChangeDetectorResult::Cancelled
+        }
+    }
+
+    /// Records the debug form of each change after taking `delay` to publish it.
+    struct SlowSink {
+        delay: Duration,
+        changes: RefCell<Vec<String>>,
+    }
+
+    impl ChangeSink<String> for SlowSink {
+        async fn publish(
+            &self,
+            change: &StateChange<String>,
+            _row_hash: Option<u64>,
+            _payload: &[u8],
+        ) -> Result<(), SinkError> {
+            tokio::time::sleep(self.delay).await;
+            self.changes.borrow_mut().push(format!("{:?}", change));
+            Ok(())
+        }
+    }
+
+    /// Streams `rows` rows, draining each one as it is found and counting it.
+    #[derive(Clone, Default)]
+    struct StreamingDetector {
+        rows: usize,
+        found: Rc<Cell<usize>>,
+    }
+
+    impl ChangeDetector for StreamingDetector {
+        type Key = String;
+        type Hash = usize;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            for row in 0..self.rows {
+                state.set_row(row.to_string(), row);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+
+        fn stream_changes<'a, S>(
+            self,
+            state: &'a mut S,
+            _cancel: &'a CancellationToken,
+        ) -> impl Stream<Item = DetectorEvent<Self::Key, Self::Hash>> + 'a
+        where
+            Self: Sized + 'a,
+            S: TableState<Self::Key, Self::Hash>,
+        {
+            let found = self.found.clone();
+            stream::iter(0..self.rows)
+                .filter_map(move |row| {
+                    state.set_row(row.to_string(), row);
+                    found.set(found.get() + 1);
+                    let change = state.drain_hashed(false).next();
+                    future::ready(change.map(|(change, hash)| DetectorEvent::Change(change, hash)))
+                })
+                .chain(stream::once(async {
+                    DetectorEvent::Finished(ChangeDetectorResult::DeleteRemainder)
+                }))
+        }
+    }
+
+    /// Reports the same rows on every run.
+    #[derive(Clone, Default)]
+    struct FixedDetector {
+        rows: Vec<(String, usize)>,
+    }
+
+    impl ChangeDetector for FixedDetector {
+        type Key = String;
+        type Hash = usize;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            for (key, hash) in self.rows {
+                state.set_row(key, hash);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+    }
+
+    /// Records the debug form of each published change, failing to publish the changes of the
+    /// `rejected` key.
+    #[derive(Default)]
+    struct RejectingSink {
+        rejected: RefCell<Option<String>>,
+        changes: RefCell<Vec<String>>,
+    }
+
+    impl ChangeSink<String> for RejectingSink {
+        async fn publish(
+            &self,
+            change: &StateChange<String>,
+            _row_hash: Option<u64>,
+            _payload: &[u8],
+        ) -> Result<(), SinkError> {
+            if self.rejected.borrow().as_ref() == Some(change.key()) {
+                return Err(SinkError::Other("the change was rejected".into()));
+            }
+            self.changes.borrow_mut().push(format!("{:?}", change));
+            Ok(())
         }
     }
 
-    async fn run_until_abort_owned<F>(self, future: F) -> Result<F::Output, ()>
+    /// Takes a while to publish each change, recording the most changes the detector had found
+    /// ahead of those published.
+    struct PacedSink {
+        found: Rc<Cell<usize>>,
+        published: Cell<usize>,
+        most_ahead: Cell<usize>,
+    }
+
+    impl ChangeSink<String> for PacedSink {
+        async fn publish(
+            &self,
+            _change: &StateChange<String>,
+            _row_hash: Option<u64>,
+            _payload: &[u8],
+        ) -> Result<(), SinkError> {
+            let ahead = self.found.get() - self.published.get();
+            self.most_ahead.set(self.most_ahead.get().max(ahead));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.published.set(self.published.get() + 1);
+            Ok(())
+        }
+    }
+
+    /// An engine over `detector` and `sink` with in-memory persistence and the default settings.
+    fn engine<D, S>(
+        detector: D,
+        sink: S,
+    ) -> Engine<D, S, InMemoryPersistence<DefaultTableState<String, usize>>> {
+        engine_with(detector, sink, InMemoryPersistence::default())
+    }
+
+    /// An engine over `detector`, `sink`, and `persistence` with the default settings.
+    fn engine_with<D, S, P>(detector: D, sink: S, persistence: P) -> Engine<D, S, P>
     where
-        F: Future,
+        P: StatePersistence,
+        P::State: Default,
     {
-        select! {
-            _ = self.handle => Err(()),
-            v = future => Ok(v),
+        Engine {
+            detector,
+            sink,
+            persistence,
+            state: Mutex::new(P::State::default()),
+            status: StatusHandle::default(),
+            runs: Cell::new(0),
+            dry_run: false,
+            channel_capacity: 64,
+            #[cfg(feature = "serde")]
+            source: "test".to_string(),
+            #[cfg(feature = "metrics")]
+            metrics: crate::metrics::Metrics::new("test"),
+        }
+    }
+
+    #[test]
+    fn zero_interval_rejected() {
+        assert_eq!(
+            ZeroIntervalError,
+            EngineConfig::new(Duration::ZERO).unwrap_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_at_configured_interval() {
+        let config = EngineConfig::new(Duration::from_millis(50)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+
+        let counter = ticks.clone();
+        let status = StatusHandle::default();
+        let engine = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async { Ok(0) }
+            },
+        );
+
+        // The first tick is immediate, then one every 50ms: 0, 50, 100, 150, 200.
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(225)).await;
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(engine, stop) })
+            .await;
+
+        assert_eq!(5, ticks.load(Ordering::SeqCst));
+    }
+
+    /// Runs work that takes 60ms every 100ms in `mode` for 350ms, returning when each run
+    /// started.
+    async fn run_slow_work(mode: ScheduleMode) -> Vec<Duration> {
+        let config = EngineConfig::new(Duration::from_millis(100))
+            .unwrap()
+            .with_mode(mode)
+            .build();
+        let stop_loop = CancellationToken::new();
+        let start = Instant::now();
+        let starts = Rc::new(RefCell::new(vec![]));
+        let status = StatusHandle::default();
+
+        let engine = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let starts = starts.clone();
+                move |_| {
+                    starts.borrow_mut().push(start.elapsed());
+                    async {
+                        tokio::time::sleep(Duration::from_millis(60)).await;
+                        Ok(0)
+                    }
+                }
+            },
+        );
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(350)).await;
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(engine, stop) })
+            .await;
+
+        starts.take()
+    }
+
+    /// Runs work every 100ms until the first run starts, returning when it started.
+    async fn first_run(run_immediately: bool) -> Duration {
+        let config = EngineConfig::new(Duration::from_millis(100))
+            .unwrap()
+            .with_run_immediately(run_immediately)
+            .build();
+        let stop_loop = CancellationToken::new();
+        let start = Instant::now();
+        let first = Rc::new(Cell::new(None));
+        let status = StatusHandle::default();
+
+        let engine = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let first = first.clone();
+                let stop_loop = stop_loop.clone();
+                move |_| {
+                    first.set(Some(start.elapsed()));
+                    stop_loop.cancel();
+                    async { Ok(0) }
+                }
+            },
+        );
+        LocalSet::new().run_until(engine).await;
+
+        first.get().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_returns_after_max_runs() {
+        let detector = CountingDetector::default();
+        let config = EngineConfig::new(Duration::from_millis(10))
+            .unwrap()
+            .with_max_runs(3)
+            .build();
+        let (status, engine) = super::run(
+            detector.clone(),
+            VecSink::default(),
+            InMemoryPersistence::<DefaultTableState<String, usize>>::default(),
+            config,
+        );
+
+        engine.await.unwrap();
+        assert_eq!(3, detector.runs.load(Ordering::SeqCst));
+        assert!(!status.status().running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_run_is_immediate_unless_disabled() {
+        assert_eq!(Duration::ZERO, first_run(true).await);
+        assert_eq!(Duration::from_millis(100), first_run(false).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_rate_starts_each_interval() {
+        let starts = run_slow_work(ScheduleMode::FixedRate).await;
+        assert_eq!(
+            [0, 100, 200, 300].map(Duration::from_millis).to_vec(),
+            starts
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fixed_delay_waits_interval_after_work() {
+        let starts = run_slow_work(ScheduleMode::FixedDelay).await;
+        assert_eq!([0, 160, 320].map(Duration::from_millis).to_vec(), starts);
+    }
+
+    #[tokio::test]
+    async fn retained_state_publishes_differences() {
+        let config = EngineConfig::new(Duration::from_millis(20)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let engine = Rc::new(engine(
+            CountingDetector::default(),
+            RecordingSink::default(),
+        ));
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let engine = engine.clone();
+                move |token| engine.clone().tick(token)
+            },
+        );
+        let stop = async {
+            while engine.sink.changes.borrow().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        let changes = engine.sink.changes.borrow();
+        let mut first_run = changes[..2].to_vec();
+        first_run.sort();
+        assert_eq!(vec![r#"New("a")"#, r#"New("b")"#], first_run);
+        let mut second_run = changes[2..4].to_vec();
+        second_run.sort();
+        assert_eq!(vec![r#"Delete("b")"#, r#"Update("a")"#], second_run);
+    }
+
+    #[tokio::test]
+    async fn sink_receives_drained_changes() {
+        let engine = engine(CountingDetector::default(), VecSink::default());
+
+        let cancel = CancellationToken::new();
+        assert_eq!((2, None), engine.detect_and_publish(&cancel).await);
+        let mut first_run = engine.sink.take();
+        first_run.sort_by_key(|(change, _)| change.key().clone());
+        assert_payloads(&first_run);
+        assert_eq!(
+            vec![
+                StateChange::New("a".to_string()),
+                StateChange::New("b".to_string()),
+            ],
+            first_run
+                .into_iter()
+                .map(|(change, _)| change)
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!((2, None), engine.detect_and_publish(&cancel).await);
+        let mut second_run = engine.sink.take();
+        second_run.sort_by_key(|(change, _)| change.key().clone());
+        assert_payloads(&second_run);
+        assert_eq!(
+            vec![
+                StateChange::Update("a".to_string()),
+                StateChange::Delete("b".to_string()),
+            ],
+            second_run
+                .into_iter()
+                .map(|(change, _)| change)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Checks that each change was published with its envelope, or its display form without the
+    /// `serde` feature.
+    fn assert_payloads(published: &[(StateChange<String>, Vec<u8>)]) {
+        for (change, payload) in published {
+            #[cfg(feature = "serde")]
+            {
+                let envelope: crate::envelope::ChangeEnvelope<String> =
+                    serde_json::from_slice(payload).unwrap();
+                assert_eq!("test", envelope.source);
+                assert_eq!("CountingDetector", envelope.detector);
+                assert_eq!(change.change_type(), envelope.change_type);
+                assert_eq!(change.key(), &envelope.key);
+            }
+            #[cfg(not(feature = "serde"))]
+            assert_eq!(change.to_string().into_bytes(), *payload);
+        }
+    }
+
+    #[tokio::test]
+    #[cfg_attr(feature = "tracing", tracing_test::traced_test)]
+    async fn dry_run_logs_changes_without_publishing() {
+        let engine = Engine {
+            dry_run: true,
+            ..engine(CountingDetector::default(), VecSink::default())
+        };
+
+        let cancel = CancellationToken::new();
+        assert_eq!((2, None), engine.detect_and_publish(&cancel).await);
+        // The state from the first run was not kept, so `a` is still new.
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+        assert!(engine.sink.changes().is_empty());
+        #[cfg(feature = "tracing")]
+        {
+            assert!(logs_contain(r#"Dry run: New("a")"#));
+            assert!(logs_contain(r#"Dry run: New("b")"#));
+            assert!(!logs_contain("Update"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test(start_paused = true)]
+    #[tracing_test::traced_test]
+    async fn tick_span_records_fields() {
+        let config = EngineConfig::new(Duration::from_millis(100)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let detector = CountingDetector::default();
+        let runs = detector.runs.clone();
+        let engine = Rc::new(engine(detector, RecordingSink::default()));
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let engine = engine.clone();
+                move |token| engine.clone().tick(token)
+            },
+        );
+        let stop = async {
+            while runs.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        // New a and b, then update a and delete b.
+        for (run, changes) in [(1, 2), (2, 2)] {
+            let span = format!("tick{{run={} changes={} duration_ms=0}}", run, changes);
+            assert!(logs_contain(&span), "no event in {}", span);
+        }
+        assert!(logs_contain("The run finished. failed=false"));
+    }
+
+    static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts its loads in `RELOADS`, always loading an empty state.
+    #[derive(Default)]
+    struct ReloadingPersistence;
+
+    impl StatePersistence for ReloadingPersistence {
+        type State = DefaultTableState<String, usize>;
+
+        async fn load() -> Result<Self::State, Box<dyn Error>> {
+            RELOADS.fetch_add(1, Ordering::SeqCst);
+            Ok(DefaultTableState::default())
+        }
+
+        async fn save(&self, _state: &Self::State) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+
+        fn retain() -> bool {
+            false
+        }
+    }
+
+    /// Keeps the state in memory, failing to save it.
+    #[derive(Default)]
+    struct UnsavedPersistence;
+
+    impl StatePersistence for UnsavedPersistence {
+        type State = DefaultTableState<String, usize>;
+
+        async fn load() -> Result<Self::State, Box<dyn Error>> {
+            Ok(DefaultTableState::default())
+        }
+
+        async fn save(&self, _state: &Self::State) -> Result<(), Box<dyn Error>> {
+            Err("the disk is full".into())
+        }
+
+        fn retain() -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_save_fails_run() {
+        let engine = engine_with(
+            FixedDetector {
+                rows: vec![("a".to_string(), 1)],
+            },
+            RecordingSink::default(),
+            UnsavedPersistence,
+        );
+
+        let (published, error) = engine.detect_and_publish(&CancellationToken::new()).await;
+        assert_eq!(1, published);
+        assert_eq!(
+            Some("The state could not be saved. the disk is full".to_string()),
+            error
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unretained_state_reloads_each_run() {
+        let config = EngineConfig::new(Duration::from_millis(100)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let detector = CountingDetector::default();
+        let runs = detector.runs.clone();
+        let engine = Rc::new(engine_with(
+            detector,
+            RecordingSink::default(),
+            ReloadingPersistence,
+        ));
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let engine = engine.clone();
+                move |token| engine.clone().tick(token)
+            },
+        );
+        let stop = async {
+            while runs.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        // The second run starts from an empty state again, so `a` is new and `b` is not deleted.
+        let mut changes = engine.sink.changes.borrow().clone();
+        changes.sort();
+        assert_eq!(vec![r#"New("a")"#, r#"New("a")"#, r#"New("b")"#], changes);
+        assert_eq!(2, RELOADS.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_stays_within_window() {
+        let interval = Duration::from_millis(100);
+        let jitter = Duration::from_millis(30);
+        let config = EngineConfig::new(interval)
+            .unwrap()
+            .with_jitter(jitter)
+            .build();
+        let stop_loop = CancellationToken::new();
+        let ticks = Rc::new(RefCell::new(vec![]));
+
+        let start = Instant::now();
+        let recorded = ticks.clone();
+        let status = StatusHandle::default();
+        let engine = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(7),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            move |_| {
+                recorded.borrow_mut().push(Instant::now());
+                async { Ok(0) }
+            },
+        );
+        let stop = async {
+            while ticks.borrow().len() < 10 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(engine, stop) })
+            .await;
+
+        let mut offsets = vec![];
+        for (n, tick) in ticks.borrow().iter().enumerate() {
+            let offset = *tick - (start + interval * n as u32);
+            assert!(offset < jitter, "tick {} was {:?} late", n, offset);
+            offsets.push(offset);
+        }
+        offsets.dedup();
+        assert!(offsets.len() > 1, "the ticks were not jittered");
+    }
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let config = EngineConfig::new(Duration::from_secs(1))
+            .unwrap()
+            .with_backoff_factor(3.0)
+            .with_max_backoff(Duration::from_secs(20))
+            .build();
+
+        assert_eq!(Duration::from_secs(1), config.backoff(0));
+        assert_eq!(Duration::from_secs(3), config.backoff(1));
+        assert_eq!(Duration::from_secs(9), config.backoff(2));
+        assert_eq!(Duration::from_secs(20), config.backoff(3));
+        assert_eq!(Duration::from_secs(20), config.backoff(usize::MAX));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backoff_resets_after_success() {
+        let config = EngineConfig::new(Duration::from_millis(100))
+            .unwrap()
+            .with_backoff_factor(2.0)
+            .with_max_backoff(Duration::from_secs(1))
+            .build();
+        let stop_loop = CancellationToken::new();
+        let detector = FlakyDetector {
+            failures: 3,
+            ..Default::default()
+        };
+        let runs = detector.runs.clone();
+        let engine = Rc::new(engine(detector, RecordingSink::default()));
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let engine = engine.clone();
+                move |token| engine.clone().tick(token)
+            },
+        );
+        let stop = async {
+            while runs.borrow().len() < 6 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        let gaps: Vec<_> = runs
+            .borrow()
+            .windows(2)
+            .map(|runs| (runs[1] - runs[0]).as_millis())
+            .collect();
+        assert_eq!(vec![200, 400, 800, 100, 100], gaps);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn status_reports_ticks() {
+        let config = EngineConfig::new(Duration::from_millis(100)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let detector = FlakyDetector {
+            failures: 2,
+            ..Default::default()
+        };
+        let runs = detector.runs.clone();
+        let engine = Rc::new(engine(detector, RecordingSink::default()));
+        let status = engine.status.clone();
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            {
+                let engine = engine.clone();
+                move |token| engine.clone().tick(token)
+            },
+        );
+        let check = async {
+            while runs.borrow().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            // The handle is `Send`, so it can be read from a task on any thread.
+            let reader = status.clone();
+            let failing = tokio::spawn(async move { reader.status() }).await.unwrap();
+            assert!(failing.running);
+            assert_eq!(2, failing.consecutive_failures);
+            assert!(failing.last_tick.is_some());
+            assert_eq!(None, failing.last_success);
+            assert!(failing.last_error.unwrap().contains("the source is down"));
+
+            while runs.borrow().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            let recovered = status.status();
+            assert_eq!(0, recovered.consecutive_failures);
+            assert_eq!(recovered.last_tick, recovered.last_success);
+            assert!(recovered.last_error.is_some());
+            assert_eq!(0, recovered.last_change_count);
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, check) })
+            .await;
+
+        assert!(!status.status().running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn panicked_work_is_recorded_and_restarted() {
+        let config = EngineConfig::new(Duration::from_millis(100)).unwrap();
+        let stop_loop = CancellationToken::new();
+        let status = StatusHandle::default();
+        let runs = Rc::new(AtomicUsize::new(0));
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            |_| {
+                let runs = runs.clone();
+                async move {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    panic!("the work fell over");
+                }
+            },
+        );
+        let stop = async {
+            while runs.load(Ordering::SeqCst) < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        let status = status.status();
+        assert_eq!(3, runs.load(Ordering::SeqCst));
+        assert_eq!(3, status.consecutive_failures);
+        assert_eq!(None, status.last_success);
+        assert!(status.last_error.unwrap().contains("panicked"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_run_is_cancelled_after_max_run_time() {
+        let mut config = EngineConfig::new(Duration::from_millis(100)).unwrap();
+        config.with_max_run_time(Duration::from_millis(30));
+        let stop_loop = CancellationToken::new();
+        let status = StatusHandle::default();
+        let cancelled_after = Rc::new(RefCell::new(Vec::new()));
+
+        let run = loop_until_cancel(
+            config.build(),
+            StdRng::seed_from_u64(0),
+            &status,
+            stop_loop.clone(),
+            CancellationToken::new(),
+            |token| {
+                let cancelled_after = cancelled_after.clone();
+                async move {
+                    let start = Instant::now();
+                    token.cancelled().await;
+                    cancelled_after.borrow_mut().push(start.elapsed());
+                    Ok(0)
+                }
+            },
+        );
+        let stop = async {
+            while cancelled_after.borrow().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        // Each run is cancelled well before the next interval would have replaced it.
+        for elapsed in cancelled_after.borrow().iter() {
+            assert_eq!(Duration::from_millis(30), *elapsed);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_change_is_published_by_next_run() {
+        let engine = engine(
+            FixedDetector {
+                rows: vec![("a".to_string(), 1), ("b".to_string(), 1)],
+            },
+            RejectingSink {
+                rejected: RefCell::new(Some("b".to_string())),
+                ..Default::default()
+            },
+        );
+
+        let cancel = CancellationToken::new();
+        let (published, error) = engine.detect_and_publish(&cancel).await;
+        assert_eq!(1, published);
+        assert!(
+            error
+                .unwrap()
+                .contains("1 change(s) could not be published")
+        );
+        let first_run = engine.sink.changes.take();
+        assert!(first_run.contains(&r#"New("a")"#.to_string()));
+        assert!(!first_run.contains(&r#"New("b")"#.to_string()));
+
+        engine.sink.rejected.take();
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+        assert_eq!(vec![r#"New("b")"#], engine.sink.changes.take());
+
+        assert_eq!((0, None), engine.detect_and_publish(&cancel).await);
+        assert!(engine.sink.changes.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_change_of_earlier_drain_is_published_by_next_run() {
+        // Each row is drained on its own, so `0` is drained before `1` is found.
+        let engine = engine(
+            StreamingDetector {
+                rows: 2,
+                ..Default::default()
+            },
+            RejectingSink {
+                rejected: RefCell::new(Some("0".to_string())),
+                ..Default::default()
+            },
+        );
+
+        let cancel = CancellationToken::new();
+        let (published, error) = engine.detect_and_publish(&cancel).await;
+        assert_eq!(1, published);
+        assert!(error.is_some());
+        assert_eq!(vec![r#"New("1")"#], engine.sink.changes.take());
+
+        engine.sink.rejected.take();
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+        assert_eq!(vec![r#"New("0")"#], engine.sink.changes.take());
+    }
+
+    #[tokio::test]
+    async fn dedup_sink_drops_repeated_change() {
+        let engine = engine(
+            FixedDetector {
+                rows: vec![("a".to_string(), 1)],
+            },
+            DedupSink::new(VecSink::default(), 10, Duration::from_secs(60)),
+        );
+
+        let cancel = CancellationToken::new();
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+        // Forgetting the state finds the row as new again, as after a restart before it was saved.
+        engine.state.lock().await.clear();
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+
+        let published = engine.sink.inner().take();
+        assert_eq!(
+            vec![StateChange::New("a".to_string())],
+            published
+                .into_iter()
+                .map(|(change, _)| change)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn dedup_sink_delivers_each_update_of_a_row() {
+        let mut engine = engine(
+            FixedDetector {
+                rows: vec![("a".to_string(), 1)],
+            },
+            DedupSink::new(VecSink::default(), 10, Duration::from_secs(60)),
+        );
+
+        let cancel = CancellationToken::new();
+        assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
+        for hash in [2, 3] {
+            engine.detector.rows = vec![("a".to_string(), hash)];
+            assert_eq!((1, None), engine.detect_and_publish(&cancel).await);
         }
+
+        let published = engine.sink.inner().take();
+        assert_eq!(
+            vec![
+                StateChange::New("a".to_string()),
+                StateChange::Update("a".to_string()),
+                StateChange::Update("a".to_string()),
+            ],
+            published
+                .into_iter()
+                .map(|(change, _)| change)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_sink_holds_back_detection() {
+        let detector = StreamingDetector {
+            rows: 20,
+            ..Default::default()
+        };
+        let engine = Engine {
+            channel_capacity: 2,
+            ..engine(
+                detector.clone(),
+                PacedSink {
+                    found: detector.found.clone(),
+                    published: Cell::new(0),
+                    most_ahead: Cell::new(0),
+                },
+            )
+        };
+
+        let cancel = CancellationToken::new();
+        assert_eq!((20, None), engine.detect_and_publish(&cancel).await);
+        assert_eq!(20, engine.sink.published.get());
+        // At most the batch being published, a full channel, and the change waiting to be sent.
+        assert!(engine.sink.most_ahead.get() <= 5);
+        assert_eq!(20, engine.state.lock().await.len());
+    }
+
+    #[test]
+    fn channel_capacity_is_at_least_one() {
+        let config = EngineConfig::default().with_channel_capacity(0).build();
+        assert_eq!(1, config.channel_capacity());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_mid_run_publishes_found_changes() {
+        // Publishing takes longer than the grace period, but not the graceful window.
+        let config = EngineConfig::new(Duration::from_secs(60))
+            .unwrap()
+            .with_grace_period(Duration::from_millis(10))
+            .with_shutdown_timeouts(Duration::from_millis(100), Duration::from_secs(1))
+            .build();
+        let detector = InterruptedDetector::default();
+        let engine = Rc::new(engine(
+            detector.clone(),
+            SlowSink {
+                delay: Duration::from_millis(200),
+                changes: RefCell::default(),
+            },
+        ));
+        let stop_loop = CancellationToken::new();
+        let stop_work = CancellationToken::new();
+
+        let run = loop_until_cancel(
+            config,
+            StdRng::seed_from_u64(0),
+            &engine.status,
+            stop_loop.clone(),
+            stop_work.clone(),
+            |token| engine.clone().tick(token),
+        );
+        // Stop the way `AppLifetime` does while the run is detecting: natural, then graceful.
+        let stop = async {
+            while !detector.started.get() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            stop_loop.cancel();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            stop_work.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(run, stop) })
+            .await;
+
+        assert_eq!(vec![r#"New("a")"#], *engine.sink.changes.borrow());
+        let status = engine.status.status();
+        assert_eq!(1, status.last_change_count);
+        assert_eq!(0, status.consecutive_failures);
     }
 
-    fn abort(&self) -> CancellationToken {
-        self.abort.clone()
+    #[tokio::test(start_paused = true)]
+    async fn unresponsive_run_is_aborted_after_max_run_time() {
+        let token = CancellationToken::new();
+        let work = std::future::pending();
+
+        let result = limit_run_time(
+            work,
+            token.clone(),
+            Some(Duration::from_millis(30)),
+            Duration::from_millis(10),
+        )
+        .await;
+
+        assert!(token.is_cancelled());
+        assert!(result.unwrap_err().contains("aborted"));
+    }
+
+    /// Renews `worker` with work that counts its start in `started` and then never finishes
+    /// unless aborted.
+    async fn renew_slow(
+        worker: &mut RenewableWorker<()>,
+        started: &Rc<AtomicUsize>,
+        overlap: ScheduleOverlap,
+    ) -> bool {
+        let started = started.clone();
+        let work = async move {
+            started.fetch_add(1, Ordering::SeqCst);
+            std::future::pending::<()>().await
+        };
+        let renewed = worker
+            .finish_and_renew(
+                work,
+                CancellationToken::new(),
+                overlap,
+                Duration::from_millis(10),
+            )
+            .await;
+
+        // Let the work start.
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        renewed
+    }
+
+    #[tokio::test]
+    async fn abort_previous_replaces_work() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::AbortPrevious;
+                let mut worker = RenewableWorker::new();
+                let started = Rc::default();
+
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+
+                assert_eq!(2, started.load(Ordering::SeqCst));
+                assert_eq!(1, worker.handles.len());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn skip_new_aborts_after_max_skips() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::SkipNew { max: 2 };
+                let mut worker = RenewableWorker::new();
+                let started = Rc::default();
+
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert!(!renew_slow(&mut worker, &started, overlap).await);
+                assert!(!renew_slow(&mut worker, &started, overlap).await);
+                assert_eq!(1, started.load(Ordering::SeqCst));
+
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert_eq!(2, started.load(Ordering::SeqCst));
+                assert_eq!(1, worker.handles.len());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn overlap_runs_up_to_max() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::Overlap { max: 2 };
+                let mut worker = RenewableWorker::new();
+                let started = Rc::default();
+
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert_eq!(2, worker.handles.len());
+
+                assert!(renew_slow(&mut worker, &started, overlap).await);
+                assert_eq!(3, started.load(Ordering::SeqCst));
+                assert_eq!(2, worker.handles.len());
+            })
+            .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn is_running_until_work_finishes() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::Overlap { max: 2 };
+                let grace_period = Duration::from_millis(10);
+                let mut worker = RenewableWorker::new();
+                assert!(!worker.is_running());
+
+                let slow = tokio::time::sleep(Duration::from_millis(100));
+                worker
+                    .finish_and_renew(slow, CancellationToken::new(), overlap, grace_period)
+                    .await;
+                let slower = tokio::time::sleep(Duration::from_millis(200));
+                worker
+                    .finish_and_renew(slower, CancellationToken::new(), overlap, grace_period)
+                    .await;
+                assert!(worker.is_running());
+                assert_eq!(2, worker.pending_count());
+
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                assert!(worker.is_running());
+                assert_eq!(1, worker.pending_count());
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(!worker.is_running());
+                assert_eq!(0, worker.pending_count());
+            })
+            .await;
     }
 
-    fn graceful(&self) -> CancellationToken {
-        self.graceful.clone()
+    #[tokio::test]
+    async fn finished_work_returns_result() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::Overlap { max: 2 };
+                let grace_period = Duration::from_millis(10);
+                let mut worker = RenewableWorker::<Result<usize, String>>::new();
+
+                let failing = async { Err("the source is down".to_string()) };
+                worker
+                    .finish_and_renew(failing, CancellationToken::new(), overlap, grace_period)
+                    .await;
+                let succeeding = async { Ok(3) };
+                worker
+                    .finish_and_renew(succeeding, CancellationToken::new(), overlap, grace_period)
+                    .await;
+
+                let finished: Vec<_> = worker
+                    .wait()
+                    .await
+                    .into_iter()
+                    .map(Result::unwrap)
+                    .collect();
+                assert_eq!(vec![Err("the source is down".to_string()), Ok(3)], finished);
+                assert!(worker.handles.is_empty());
+            })
+            .await;
     }
 
-    fn natural(&self) -> CancellationToken {
-        self.natural.clone()
+    #[tokio::test]
+    async fn closed_work_returns_result() {
+        LocalSet::new()
+            .run_until(async {
+                let overlap = ScheduleOverlap::Overlap { max: 2 };
+                let grace_period = Duration::from_millis(10);
+                let mut worker = RenewableWorker::<Result<(), String>>::new();
+
+                let cancel = CancellationToken::new();
+                let work = {
+                    let cancel = cancel.clone();
+                    async move {
+                        cancel.cancelled().await;
+                        Err("the run was cancelled".to_string())
+                    }
+                };
+                worker
+                    .finish_and_renew(work, cancel, overlap, grace_period)
+                    .await;
+
+                let finished = worker
+                    .close_with_abort_after(Duration::from_secs(1))
+                    .await
+                    .unwrap();
+                assert_eq!(1, finished.len());
+                assert_eq!(
+                    Err("the run was cancelled".to_string()),
+                    *finished[0].as_ref().unwrap()
+                );
+            })
+            .await;
     }
 }
@@ -0,0 +1,135 @@
+//! The JSON body the engine publishes for each change, which is the same for every detector
+//! and sink:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "source": "filesystem",
+//!   "detector": "FileChangeDetector",
+//!   "emitted_at": "2026-01-31T12:00:00.123Z",
+//!   "change_type": "update",
+//!   "key": "/srv/share/report.csv",
+//!   "payload": null
+//! }
+//! ```
+
+use crate::state::StateChange;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The version of the envelope format. Fields may be added without changing it, but it changes
+/// if a field is removed or its meaning changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A change along with where and when it was found.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEnvelope<Key> {
+    /// The `SCHEMA_VERSION` the envelope was written with.
+    pub schema_version: u32,
+    /// The system the change comes from. See `EngineConfig::with_source`.
+    pub source: String,
+    /// The detector that found the change. See `ChangeDetector::name`.
+    pub detector: String,
+    pub emitted_at: DateTime<Utc>,
+    /// `new`, `update`, or `delete`.
+    pub change_type: String,
+    pub key: Key,
+    /// Details of the change the detector provides, if any.
+    pub payload: Option<serde_json::Value>,
+}
+
+impl<Key> ChangeEnvelope<Key> {
+    /// Wraps `change`, emitted now, without a payload.
+    pub fn new(
+        source: impl Into<String>,
+        detector: impl Into<String>,
+        change: StateChange<Key>,
+    ) -> Self {
+        let change_type = change.change_type().to_string();
+        let key = match change {
+            StateChange::New(key) | StateChange::Update(key) | StateChange::Delete(key) => key,
+        };
+        Self {
+            schema_version: SCHEMA_VERSION,
+            source: source.into(),
+            detector: detector.into(),
+            emitted_at: Utc::now(),
+            change_type,
+            key,
+            payload: None,
+        }
+    }
+
+    pub fn with_payload(&mut self, payload: serde_json::Value) -> &mut Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// The change the envelope holds, or `None` if its change type is unknown.
+    pub fn change(&self) -> Option<StateChange<&Key>> {
+        match self.change_type.as_str() {
+            "new" => Some(StateChange::New(&self.key)),
+            "update" => Some(StateChange::Update(&self.key)),
+            "delete" => Some(StateChange::Delete(&self.key)),
+            _ => None,
+        }
+    }
+}
+
+impl<Key: Serialize> ChangeEnvelope<Key> {
+    /// The envelope as a JSON message body. Fails only if the key cannot be written as JSON.
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+#[cfg(test)]
+mod test_envelope {
+    use super::{ChangeEnvelope, SCHEMA_VERSION};
+    use crate::state::StateChange;
+    use chrono::{TimeZone, Utc};
+    use serde_json::{Value, json};
+
+    #[test]
+    fn schema_version_is_stable() {
+        // Consumers rely on this number. Change it only with a breaking change to the format.
+        assert_eq!(1, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn serializes_expected_fields() {
+        let mut envelope = ChangeEnvelope::new(
+            "sales",
+            "SqlTableChangeDetector",
+            StateChange::Update(("orders".to_string(), 42i64)),
+        );
+        envelope.emitted_at = Utc.with_ymd_and_hms(2026, 1, 31, 12, 0, 0).unwrap();
+
+        let json: Value = serde_json::from_slice(&envelope.to_json().unwrap()).unwrap();
+        assert_eq!(
+            json!({
+                "schema_version": 1,
+                "source": "sales",
+                "detector": "SqlTableChangeDetector",
+                "emitted_at": "2026-01-31T12:00:00Z",
+                "change_type": "update",
+                "key": ["orders", 42],
+                "payload": null,
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn round_trips_with_payload() {
+        let mut envelope =
+            ChangeEnvelope::new("files", "FileChangeDetector", StateChange::New("a"));
+        envelope.with_payload(json!({ "size": 12 }));
+
+        let json = envelope.to_json().unwrap();
+        let back: ChangeEnvelope<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(Some(StateChange::New(&"a".to_string())), back.change());
+        assert_eq!(Some(json!({ "size": 12 })), back.payload);
+        assert_eq!(envelope.emitted_at, back.emitted_at);
+    }
+}
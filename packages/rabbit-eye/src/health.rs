@@ -0,0 +1,158 @@
+//! An HTTP server reporting whether the engine is alive and ready, for orchestrators such as
+//! Kubernetes.
+
+use crate::engine::{EngineConfig, EngineStatus, StatusHandle};
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use std::{
+    net::{AddrParseError, SocketAddr},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// Reads the server's bind address from `RABBIT_EYE_HEALTH_ADDR`, or `None` if it is not set.
+pub fn read_addr_from_env() -> Result<Option<SocketAddr>, AddrParseError> {
+    std::env::var("RABBIT_EYE_HEALTH_ADDR")
+        .ok()
+        .map(|addr| addr.parse())
+        .transpose()
+}
+
+/// Serves `/healthz` and `/readyz` on `listener` until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    status: StatusHandle,
+    config: EngineConfig,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state((status, config));
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn healthz(State((status, config)): State<(StatusHandle, EngineConfig)>) -> StatusCode {
+    status_code(is_live(&status.status(), &config, SystemTime::now()))
+}
+
+async fn readyz(State((status, _)): State<(StatusHandle, EngineConfig)>) -> StatusCode {
+    status_code(is_ready(&status.status()))
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Whether the loop is running and has ticked, or started, within twice the wait before its next
+/// tick. The wait includes the jitter and grows while the engine backs off from failures.
+pub fn is_live(status: &EngineStatus, config: &EngineConfig, now: SystemTime) -> bool {
+    let Some(since) = status.last_tick.or(status.started) else {
+        return false;
+    };
+
+    let wait = config
+        .backoff(status.consecutive_failures)
+        .saturating_add(config.schedule().jitter());
+    let elapsed = now.duration_since(since).unwrap_or(Duration::ZERO);
+    status.running && elapsed <= wait.saturating_mul(2)
+}
+
+/// Whether the loop is running, has finished its first run, and has a connected broker if the
+/// sink reports one.
+pub fn is_ready(status: &EngineStatus) -> bool {
+    status.running && status.last_tick.is_some() && status.broker_connected != Some(false)
+}
+
+#[cfg(test)]
+mod test_health {
+    use super::{is_live, serve};
+    use crate::engine::{EngineConfig, EngineStatus, StatusHandle};
+    use std::{
+        net::SocketAddr,
+        time::{Duration, SystemTime},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    /// Requests `path` from the server at `addr` and returns the response status code.
+    async fn get(addr: SocketAddr, path: &str) -> u16 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.split(' ').nth(1).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn live_within_twice_the_wait() {
+        let config = EngineConfig::new(Duration::from_secs(10)).unwrap();
+        let tick = SystemTime::UNIX_EPOCH;
+        let mut status = EngineStatus {
+            running: true,
+            last_tick: Some(tick),
+            ..Default::default()
+        };
+
+        assert!(is_live(&status, &config, tick + Duration::from_secs(20)));
+        assert!(!is_live(&status, &config, tick + Duration::from_secs(21)));
+
+        // Backing off from one failure doubles the wait.
+        status.consecutive_failures = 1;
+        assert!(is_live(&status, &config, tick + Duration::from_secs(40)));
+
+        status.running = false;
+        assert!(!is_live(&status, &config, tick));
+    }
+
+    #[tokio::test]
+    async fn endpoints_follow_engine_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = StatusHandle::default();
+        let config = EngineConfig::default();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve(listener, status.clone(), config, shutdown.clone()));
+
+        // Not started.
+        assert_eq!(503, get(addr, "/healthz").await);
+        assert_eq!(503, get(addr, "/readyz").await);
+
+        status.update(|status| {
+            status.running = true;
+            status.started = Some(SystemTime::now());
+        });
+        assert_eq!(200, get(addr, "/healthz").await);
+        assert_eq!(503, get(addr, "/readyz").await);
+
+        status.record_tick(0, None);
+        assert_eq!(200, get(addr, "/readyz").await);
+
+        status.set_broker_connected(false);
+        assert_eq!(503, get(addr, "/readyz").await);
+        status.set_broker_connected(true);
+        assert_eq!(200, get(addr, "/readyz").await);
+
+        status.update(|status| status.running = false);
+        assert_eq!(503, get(addr, "/healthz").await);
+        assert_eq!(503, get(addr, "/readyz").await);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Keys of the rows a change detector observes, and how they are written into messages.
+//!
+//! A key may be a single value such as a path or an id, or a tuple of them such as
+//! `(table, id)`. With the `serde` feature each provided key also serializes, so a
+//! `StateChange` of any of them can be written as a structured message body.
+
+use crate::state::StateChange;
+use std::fmt::{self, Display, Formatter};
+
+/// A key that can be published. It is displayed in message bodies and forms part of the routing
+/// key of messages that route by key.
+pub trait ChangeKey {
+    /// The key as a segment of a topic routing key. A composite key produces one word per part,
+    /// separated by `.`, so bindings may match any part. Each part is a single word, so any `.`
+    /// within a part is replaced by `_`.
+    fn to_routing_segment(&self) -> String;
+
+    /// Writes the key for people to read. Composite keys are written like `(orders, 42)`.
+    fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result;
+}
+
+impl ChangeKey for str {
+    fn to_routing_segment(&self) -> String {
+        self.replace('.', "_")
+    }
+
+    fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+impl ChangeKey for String {
+    fn to_routing_segment(&self) -> String {
+        self.as_str().to_routing_segment()
+    }
+
+    fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt_key(f)
+    }
+}
+
+impl<T: ChangeKey + ?Sized> ChangeKey for &T {
+    fn to_routing_segment(&self) -> String {
+        (**self).to_routing_segment()
+    }
+
+    fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        (**self).fmt_key(f)
+    }
+}
+
+macro_rules! integer_keys {
+    ($($t:ty),*) => {$(
+        impl ChangeKey for $t {
+            fn to_routing_segment(&self) -> String {
+                self.to_string()
+            }
+
+            fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                Display::fmt(self, f)
+            }
+        }
+    )*};
+}
+
+integer_keys!(
+    i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize
+);
+
+macro_rules! tuple_keys {
+    ($(($first:ident, $($rest:ident),+)),*) => {$(
+        impl<$first: ChangeKey, $($rest: ChangeKey),+> ChangeKey for ($first, $($rest),+) {
+            #[allow(non_snake_case)]
+            fn to_routing_segment(&self) -> String {
+                let ($first, $($rest),+) = self;
+                let mut segment = $first.to_routing_segment();
+                $(
+                    segment.push('.');
+                    segment.push_str(&$rest.to_routing_segment());
+                )+
+                segment
+            }
+
+            #[allow(non_snake_case)]
+            fn fmt_key(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                let ($first, $($rest),+) = self;
+                f.write_str("(")?;
+                $first.fmt_key(f)?;
+                $(
+                    f.write_str(", ")?;
+                    $rest.fmt_key(f)?;
+                )+
+                f.write_str(")")
+            }
+        }
+    )*};
+}
+
+tuple_keys!((A, B), (A, B, C), (A, B, C, D));
+
+/// A key the engine can publish. With the `serde` feature the key is written into each change's
+/// envelope, so it must also serialize.
+#[cfg(feature = "serde")]
+pub trait PublishKey: ChangeKey + Clone + serde::Serialize {}
+
+#[cfg(feature = "serde")]
+impl<T: ChangeKey + Clone + serde::Serialize> PublishKey for T {}
+
+/// A key the engine can publish. With the `serde` feature the key is written into each change's
+/// envelope, so it must also serialize.
+#[cfg(not(feature = "serde"))]
+pub trait PublishKey: ChangeKey {}
+
+#[cfg(not(feature = "serde"))]
+impl<T: ChangeKey> PublishKey for T {}
+
+impl<Key> StateChange<Key> {
+    pub fn key(&self) -> &Key {
+        match self {
+            Self::New(key) | Self::Update(key) | Self::Delete(key) => key,
+        }
+    }
+
+    pub fn into_key(self) -> Key {
+        match self {
+            Self::New(key) | Self::Update(key) | Self::Delete(key) => key,
+        }
+    }
+
+    /// The kind of change, as `new`, `update`, or `delete`.
+    pub fn change_type(&self) -> &'static str {
+        match self {
+            Self::New(_) => "new",
+            Self::Update(_) => "update",
+            Self::Delete(_) => "delete",
+        }
+    }
+}
+
+impl<Key: ChangeKey> StateChange<Key> {
+    /// The routing key of the change under `prefix`, as `prefix.change_type.key`.
+    pub fn routing_key(&self, prefix: &str) -> String {
+        format!(
+            "{}.{}.{}",
+            prefix,
+            self.change_type(),
+            self.key().to_routing_segment()
+        )
+    }
+}
+
+/// Writes the change type followed by the key, like `new (orders, 42)`, which is the body the
+/// engine publishes for each change without the `serde` feature.
+impl<Key: ChangeKey> Display for StateChange<Key> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.change_type())?;
+        self.key().fmt_key(f)
+    }
+}
+
+#[cfg(test)]
+mod test_key {
+    use super::ChangeKey;
+    use crate::{
+        sink::{ChangeSink, VecSink},
+        state::StateChange,
+    };
+
+    #[test]
+    fn routing_segments_are_words() {
+        assert_eq!("report_csv", "report.csv".to_routing_segment());
+        assert_eq!("42", 42u64.to_routing_segment());
+        assert_eq!("-7", (-7i32).to_routing_segment());
+        assert_eq!(
+            "sales.orders.42",
+            ("sales", "orders".to_string(), 42).to_routing_segment()
+        );
+    }
+
+    #[test]
+    fn changes_display_their_keys() {
+        assert_eq!("new a", StateChange::New("a").to_string());
+        assert_eq!(
+            "delete (orders, 42)",
+            StateChange::Delete(("orders", 42)).to_string()
+        );
+        assert_eq!(
+            "events.update.orders.42",
+            StateChange::Update(("orders", 42)).routing_key("events")
+        );
+    }
+
+    #[tokio::test]
+    async fn tuple_key_round_trips_through_sink() {
+        let sink = VecSink::default();
+        let change = StateChange::Update(("orders".to_string(), 42i64));
+        sink.publish(&change, None, change.to_string().as_bytes())
+            .await
+            .unwrap();
+
+        let published = sink.take();
+        assert_eq!(vec![(change, b"update (orders, 42)".to_vec())], published);
+        assert_eq!(&("orders".to_string(), 42), published[0].0.key());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn tuple_key_serializes() {
+        let change = StateChange::New(("orders".to_string(), 42i64));
+        let json = serde_json::to_string(&change).unwrap();
+        assert_eq!(r#"{"type":"New","key":["orders",42]}"#, json);
+
+        let back: StateChange<(String, i64)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(change, back);
+    }
+}
@@ -1,6 +1,22 @@
+#[macro_use]
+pub mod log;
+
+#[cfg(feature = "rabbitmq")]
+pub mod consume;
 pub mod engine;
+#[cfg(feature = "serde")]
+pub mod envelope;
+#[cfg(feature = "health")]
+pub mod health;
+pub mod key;
 pub mod lifetime;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "rabbitmq")]
 pub mod rabbit;
+pub mod sink;
+#[cfg(feature = "sqlx")]
+pub mod sql;
 pub mod state;
 pub mod sync;
 pub mod time;
@@ -1,44 +1,259 @@
-// This will have the application lifetime and functions for scheduling,
-// polling, etc.
+// The application lifetime: how the app is told to stop, and how work is stopped with it.
 
-use tokio::task::JoinHandle;
+use crate::sync::{RaceOutcome, run_until_cancelled_or_timeout};
+use std::{error::Error, fmt::Display, pin::Pin, sync::Arc, time::Duration};
+use tokio::{
+    select, spawn,
+    task::{JoinError, JoinHandle},
+    time::{sleep, sleep_until},
+};
+use tokio_util::sync::CancellationToken;
 
 /// Signal for Result of a race between an operation and Ctrl+C.
 pub struct CtrlC;
 
-// static CTRL_C: JoinHandle<()> = tokio::spawn(async {
-//     let sig = tokio::signal::ctrl_c()
-//         .await
-//         .expect("Failed to register Ctrl+C handler.");
-// });
+/// A callback run when the graceful stop begins.
+type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
 
+/// Stops the app in stages once the process is asked to stop. Each stage cancels its token,
+/// and the child tokens of the stages before it: natural, then graceful, then abort.
+///
+/// Work should finish what it is doing and stop at the natural stop, give up what it is doing
+/// at the graceful stop, and is dropped at the abort.
+///
+/// ```
+/// use rabbit_eye::lifetime::AppLifetime;
+/// use std::time::Duration;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let lifetime = AppLifetime::with_timeouts(Duration::from_secs(5), Duration::from_secs(5))?;
+/// let consume = async {
+///     // Handle messages until there are none left.
+///     3
+/// };
+///
+/// match lifetime.natural().run_until_cancelled(consume).await {
+///     Some(handled) => println!("Handled {} messages.", handled),
+///     None => println!("Stopping before every message was handled."),
+/// }
+/// # Ok(())
+/// # }
+/// ```
 pub struct AppLifetime {
-    ctrl_c: Option<JoinHandle<()>>,
+    handle: JoinHandle<()>,
+    abort: CancellationToken,
+    graceful: CancellationToken,
+    natural: CancellationToken,
+    hooks: Arc<std::sync::Mutex<Vec<ShutdownHook>>>,
 }
 
-impl AppLifetime {
-    pub fn new() -> Self {
-        Self { ctrl_c: None }
+/// The tokens of each stage of an `AppLifetime`, which are cancelled in this order.
+#[derive(Clone, Debug)]
+pub struct LifetimeTokens {
+    pub natural: CancellationToken,
+    pub graceful: CancellationToken,
+    pub abort: CancellationToken,
+}
+
+/// A shutdown timeout was zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZeroTimeoutError;
+
+impl Display for ZeroTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the shutdown timeouts must be greater than zero")
     }
 }
 
-fn init_lifetime_ctrl_c(lifetime: &mut AppLifetime) -> &mut JoinHandle<()> {
-    if let None = lifetime.ctrl_c {
-        let handle = tokio::spawn(async {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to register Ctrl+C handler.");
+impl Error for ZeroTimeoutError {}
+
+impl AppLifetime {
+    /// Starts stopping once the process is asked to stop, giving the natural stop `natural` and
+    /// the graceful stop `graceful` before escalating to the next stage.
+    pub fn with_timeouts(natural: Duration, graceful: Duration) -> Result<Self, ZeroTimeoutError> {
+        if natural.is_zero() || graceful.is_zero() {
+            return Err(ZeroTimeoutError);
+        }
+
+        Ok(Self::start_on(shutdown_signal(), natural, graceful))
+    }
+
+    /// Starts stopping once `signal` completes, escalating from natural to graceful after
+    /// `natural_timeout`, and from graceful to abort after `graceful_timeout`.
+    fn start_on<F>(signal: F, natural_timeout: Duration, graceful_timeout: Duration) -> Self
+    where
+        F: Future + Send + 'static,
+    {
+        let abort = CancellationToken::new();
+        let graceful = abort.child_token();
+        let natural = graceful.child_token();
+
+        let ctrlc_abort = abort.clone();
+        let ctrlc_graceful = graceful.clone();
+        let ctrlc_natural = natural.clone();
+        let hooks = Arc::new(std::sync::Mutex::new(Vec::<ShutdownHook>::new()));
+        let ctrlc_hooks = hooks.clone();
+
+        let handle = spawn(async move {
+            _ = signal.await;
+
+            // Indicate natural stop, and wait for the natural timeout
+            warn!("Stopping. Attempting natural stop.");
+            ctrlc_natural.cancel();
+            ctrlc_graceful
+                .run_until_cancelled(sleep(natural_timeout))
+                .await;
+
+            // Indicate graceful stop, and wait for the graceful timeout
+            warn!("Stopping. Attempting graceful stop.");
+            ctrlc_graceful.cancel();
+            let deadline = tokio::time::Instant::now() + graceful_timeout;
+            let hooks = std::mem::take(&mut *ctrlc_hooks.lock().unwrap());
+            let run_hooks = async {
+                for hook in hooks {
+                    hook().await;
+                }
+            };
+            if let RaceOutcome::TimedOut =
+                run_until_cancelled_or_timeout(&ctrlc_abort, graceful_timeout, run_hooks).await
+            {
+                warn!("The shutdown hooks did not finish before the graceful timeout.");
+            }
+            ctrlc_abort.run_until_cancelled(sleep_until(deadline)).await;
+
+            // Indicate abort and end this task. Anything racing this task will be stopped.
+            warn!("Stopping. Aborting.");
+            ctrlc_abort.cancel();
         });
-        lifetime.ctrl_c = Some(handle);
+
+        Self {
+            handle,
+            abort,
+            graceful,
+            natural,
+            hooks,
+        }
+    }
+
+    /// Registers `hook` to run when the graceful stop begins, after the natural stop. Hooks
+    /// run one at a time in the order they were registered, and any still running when the
+    /// graceful timeout ends are abandoned. Hooks registered after the graceful stop began
+    /// do not run.
+    pub fn on_graceful<F, Fut>(&self, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks
+            .lock()
+            .unwrap()
+            .push(Box::new(move || Box::pin(hook())));
+    }
+
+    /// Runs a future until this app lifetime abort token is cancelled.
+    pub async fn run_until_abort<F>(&self, future: F) -> Option<F::Output>
+    where
+        F: Future,
+    {
+        select! {
+            _ = self.abort.cancelled() => None,
+            v = future => Some(v),
+        }
+    }
+
+    async fn run_until_abort_owned<F>(self, future: F) -> Result<F::Output, ()>
+    where
+        F: Future,
+    {
+        select! {
+            _ = self.handle => Err(()),
+            v = future => Ok(v),
+        }
+    }
+
+    /// Cancelled when the app stops without waiting any longer for its work.
+    pub fn abort(&self) -> CancellationToken {
+        self.abort.clone()
+    }
+
+    /// Cancelled when the app asks its work to give up what it is doing.
+    pub fn graceful(&self) -> CancellationToken {
+        self.graceful.clone()
+    }
+
+    /// Cancelled as soon as the app is asked to stop, for work to finish what it is doing.
+    pub fn natural(&self) -> CancellationToken {
+        self.natural.clone()
+    }
+
+    /// The tokens of every stage, for work that reacts to each of them.
+    pub fn tokens(&self) -> LifetimeTokens {
+        LifetimeTokens {
+            natural: self.natural(),
+            graceful: self.graceful(),
+            abort: self.abort(),
+        }
     }
-    lifetime.ctrl_c.as_mut().unwrap()
 }
 
-/// Runs a future until and unless SIGTERM (Ctrl+C) is received by the process.
-pub async fn race_sigterm<F: Future>(f: F, lifetime: &mut AppLifetime) -> Result<F::Output, CtrlC> {
-    let mut ctrl_c = init_lifetime_ctrl_c(lifetime);
+/// Completes when the process is asked to stop, by Ctrl+C or SIGTERM.
+#[cfg(unix)]
+pub async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => _ = terminate.recv().await,
+            Err(e) => {
+                error!("Failed to register SIGTERM handler. {}", e);
+                std::future::pending().await
+            }
+        }
+    };
+
     tokio::select! {
-        _ = &mut ctrl_c => {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Completes when the process is asked to stop, by Ctrl+C, closing the console, or shutting
+/// down the system.
+#[cfg(windows)]
+pub async fn shutdown_signal() {
+    use tokio::signal::windows::{ctrl_close, ctrl_shutdown};
+
+    let close = async {
+        match ctrl_close() {
+            Ok(mut close) => _ = close.recv().await,
+            Err(e) => {
+                error!("Failed to register console close handler. {}", e);
+                std::future::pending().await
+            }
+        }
+    };
+    let shutdown = async {
+        match ctrl_shutdown() {
+            Ok(mut shutdown) => _ = shutdown.recv().await,
+            Err(e) => {
+                error!("Failed to register system shutdown handler. {}", e);
+                std::future::pending().await
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = close => {}
+        _ = shutdown => {}
+    }
+}
+
+/// Runs a future until and unless `lifetime` begins its natural stop.
+pub async fn race_sigterm<F: Future>(f: F, lifetime: &AppLifetime) -> Result<F::Output, CtrlC> {
+    select! {
+        _ = lifetime.natural.cancelled() => {
             Err(CtrlC)
         },
         result = f => {
@@ -47,34 +262,231 @@ pub async fn race_sigterm<F: Future>(f: F, lifetime: &mut AppLifetime) -> Result
     }
 }
 
-pub async fn try_graceful_shutdown<T>(
-    mut f: tokio::task::JoinHandle<T>,
-    cancel: &crate::sync::CancellationToken,
-) -> Result<T, ()> {
-    let five_secs = std::time::Duration::from_secs(5);
+/// How long `try_graceful_shutdown` waits at each stage before escalating to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GracefulShutdownConfig {
+    /// How long to wait for the task to stop on its own before cancelling it.
+    pub wait_for_self: Duration,
+    /// How long to wait for the task to stop after cancelling it before aborting it.
+    pub wait_after_cancel: Duration,
+}
 
-    // First try waiting 5s for it to stop on its own
-    tokio::select! {
+impl Default for GracefulShutdownConfig {
+    fn default() -> Self {
+        Self {
+            wait_for_self: Duration::from_secs(5),
+            wait_after_cancel: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How a task stopped in `try_graceful_shutdown`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShutdownOutcome<T> {
+    /// The task finished on its own.
+    Finished(T),
+    /// The task finished after it was cancelled.
+    FinishedAfterCancel(T),
+    /// The task did not finish after it was cancelled, so it was aborted.
+    Aborted,
+}
+
+/// Stops the task `f`: first waiting for it to finish, then cancelling `cancel`, and finally
+/// aborting it. Fails if the task panicked.
+pub async fn try_graceful_shutdown<T>(
+    mut f: JoinHandle<T>,
+    cancel: &CancellationToken,
+    config: GracefulShutdownConfig,
+) -> Result<ShutdownOutcome<T>, JoinError> {
+    // First try waiting for it to stop on its own
+    select! {
         result = &mut f => {
-            return Ok(result.map_err(|_| ())?)
+            return result.map(ShutdownOutcome::Finished)
         },
-        _ = tokio::time::sleep(five_secs) => {
-            eprintln!("Did not respond after five seconds. Trying cooperative cancellation.")
+        _ = sleep(config.wait_for_self) => {
+            warn!(
+                "Did not respond after {:?}. Trying cooperative cancellation.",
+                config.wait_for_self
+            )
         }
     }
 
     // Try cancelling via token and allowing the process to finish
     cancel.cancel();
-    tokio::select! {
+    select! {
         result = &mut f => {
-            return Ok(result.map_err(|_| ())?)
+            return result.map(ShutdownOutcome::FinishedAfterCancel)
         }
-        _ = tokio::time::sleep(five_secs) => {
-            eprintln!("Did not respond after five seconds. Aborting.");
+        _ = sleep(config.wait_after_cancel) => {
+            warn!("Did not respond after {:?}. Aborting.", config.wait_after_cancel);
         }
     }
 
     // And then abort
     f.abort();
-    Err(())
+    Ok(ShutdownOutcome::Aborted)
+}
+
+#[cfg(test)]
+mod test_lifetime {
+    use super::{
+        AppLifetime, CtrlC, GracefulShutdownConfig, ShutdownOutcome, ZeroTimeoutError,
+        race_sigterm, try_graceful_shutdown,
+    };
+    use std::{sync::Arc, time::Duration};
+    use tokio::time::Instant;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn zero_shutdown_timeouts_rejected() {
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            Some(ZeroTimeoutError),
+            AppLifetime::with_timeouts(Duration::ZERO, second).err()
+        );
+        assert_eq!(
+            Some(ZeroTimeoutError),
+            AppLifetime::with_timeouts(second, Duration::ZERO).err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_escalates_at_configured_timeouts() {
+        let signal = CancellationToken::new();
+        let life = AppLifetime::start_on(
+            signal.clone().cancelled_owned(),
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+        );
+        let start = Instant::now();
+        signal.cancel();
+
+        life.natural().cancelled().await;
+        assert_eq!(Duration::ZERO, start.elapsed());
+        assert!(!life.graceful().is_cancelled());
+
+        life.graceful().cancelled().await;
+        assert_eq!(Duration::from_millis(100), start.elapsed());
+        assert!(!life.abort().is_cancelled());
+
+        life.abort().cancelled().await;
+        assert_eq!(Duration::from_millis(300), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_follow_their_stages() {
+        let signal = CancellationToken::new();
+        let life = AppLifetime::start_on(
+            signal.clone().cancelled_owned(),
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+        );
+        let tokens = life.tokens();
+        signal.cancel();
+
+        tokens.natural.cancelled().await;
+        assert!(!tokens.graceful.is_cancelled());
+        tokens.graceful.cancelled().await;
+        assert!(!tokens.abort.is_cancelled());
+        tokens.abort.cancelled().await;
+        assert!(life.abort().is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn graceful_hooks_run_in_order() {
+        let signal = CancellationToken::new();
+        let life = AppLifetime::start_on(
+            signal.clone().cancelled_owned(),
+            Duration::from_millis(100),
+            Duration::from_millis(200),
+        );
+        let ran = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for hook in 0..2 {
+            let ran = ran.clone();
+            let graceful = life.graceful();
+            life.on_graceful(move || async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                ran.lock().unwrap().push((hook, graceful.is_cancelled()));
+            });
+        }
+        let start = Instant::now();
+        signal.cancel();
+
+        life.abort().cancelled().await;
+        assert_eq!(vec![(0, true), (1, true)], *ran.lock().unwrap());
+        // The hooks finishing early does not shorten the graceful stop.
+        assert_eq!(Duration::from_millis(300), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn race_sigterm_stops_at_natural_stop() {
+        let signal = CancellationToken::new();
+        let life = AppLifetime::start_on(
+            signal.clone().cancelled_owned(),
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        );
+
+        let finished = race_sigterm(async { 1 }, &life).await;
+        assert!(matches!(finished, Ok(1)));
+
+        signal.cancel();
+        let stopped = race_sigterm(std::future::pending::<()>(), &life).await;
+        assert!(matches!(stopped, Err(CtrlC)));
+    }
+
+    fn short_shutdown() -> GracefulShutdownConfig {
+        GracefulShutdownConfig {
+            wait_for_self: Duration::from_millis(100),
+            wait_after_cancel: Duration::from_millis(200),
+        }
+    }
+
+    /// Spawns a task that finishes with `1` after `run_for`, or with `2` once `cancel` is
+    /// cancelled if `responsive`.
+    fn spawn_task(
+        run_for: Duration,
+        cancel: &CancellationToken,
+        responsive: bool,
+    ) -> tokio::task::JoinHandle<usize> {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(run_for) => 1,
+                _ = cancel.cancelled(), if responsive => 2,
+            }
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_finishes_naturally() {
+        let cancel = CancellationToken::new();
+        let task = spawn_task(Duration::from_millis(50), &cancel, true);
+
+        let outcome = try_graceful_shutdown(task, &cancel, short_shutdown()).await;
+        assert_eq!(ShutdownOutcome::Finished(1), outcome.unwrap());
+        assert!(!cancel.is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_finishes_after_cancel() {
+        let cancel = CancellationToken::new();
+        let task = spawn_task(Duration::from_secs(60), &cancel, true);
+        let start = Instant::now();
+
+        let outcome = try_graceful_shutdown(task, &cancel, short_shutdown()).await;
+        assert_eq!(ShutdownOutcome::FinishedAfterCancel(2), outcome.unwrap());
+        assert_eq!(Duration::from_millis(100), start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_aborts_unresponsive_task() {
+        let cancel = CancellationToken::new();
+        let task = spawn_task(Duration::from_secs(60), &cancel, false);
+        let start = Instant::now();
+
+        let outcome = try_graceful_shutdown(task, &cancel, short_shutdown()).await;
+        assert_eq!(ShutdownOutcome::Aborted, outcome.unwrap());
+        assert_eq!(Duration::from_millis(300), start.elapsed());
+    }
 }
@@ -0,0 +1,51 @@
+//! Logging for the engine and its detectors. With the `tracing` feature, messages are `tracing`
+//! events at the matching level. Without it, they are printed to stdout, or stderr for warnings
+//! and errors.
+//!
+//! The macros are in scope for every module declared after this one.
+
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::debug!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)+);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::info!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        println!($($arg)+);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::warn!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)+);
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::error!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        eprintln!($($arg)+);
+    }};
+}
+
+/// Installs a global subscriber that writes events to stdout, filtered by `RUST_LOG` or at the
+/// `info` level if it is not set. Binaries should call this once at startup.
+#[cfg(feature = "tracing")]
+pub fn init_subscriber() {
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+}
@@ -0,0 +1,297 @@
+//! Prometheus metrics describing the engine's runs, served at `/metrics` for scraping.
+//!
+//! Every metric is prefixed with `rabbit_eye` and labeled with the `source` of the changes:
+//!
+//! - `rabbit_eye_changes_total{change_type}`: changes published, by `new`, `update`, or `delete`.
+//! - `rabbit_eye_detection_duration_seconds`: how long each run took.
+//! - `rabbit_eye_consecutive_failures`: the number of runs in a row that have failed.
+//! - `rabbit_eye_last_success_timestamp_seconds`: when the last successful run finished.
+//! - `rabbit_eye_publish_errors_total`: runs whose changes the sink could not publish.
+
+use crate::state::StateChange;
+use axum::{
+    Router,
+    extract::State,
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use prometheus_client::{
+    encoding::text::encode,
+    metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+    registry::Registry,
+};
+use std::{
+    net::{AddrParseError, SocketAddr},
+    sync::{Arc, atomic::AtomicU64},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// The content type of the OpenMetrics text format.
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Reads the server's bind address from `RABBIT_EYE_METRICS_ADDR`, or `None` if it is not set.
+pub fn read_addr_from_env() -> Result<Option<SocketAddr>, AddrParseError> {
+    std::env::var("RABBIT_EYE_METRICS_ADDR")
+        .ok()
+        .map(|addr| addr.parse())
+        .transpose()
+}
+
+/// The metrics of one engine. Clones share the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    changes: Family<[(&'static str, &'static str); 1], Counter>,
+    detection_duration: Histogram,
+    consecutive_failures: Gauge,
+    last_success: Gauge<f64, AtomicU64>,
+    publish_errors: Counter,
+}
+
+impl Metrics {
+    /// Creates the metrics of an engine whose changes come from `source`.
+    pub fn new(source: &str) -> Self {
+        let labels = [("source".into(), source.to_string().into())];
+        let mut registry = Registry::with_prefix_and_labels("rabbit_eye", labels.into_iter());
+
+        let changes = Family::default();
+        registry.register("changes", "Changes published", changes.clone());
+        let detection_duration = Histogram::new([0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0]);
+        registry.register(
+            "detection_duration_seconds",
+            "How long each run took",
+            detection_duration.clone(),
+        );
+        let consecutive_failures = Gauge::default();
+        registry.register(
+            "consecutive_failures",
+            "The number of runs in a row that have failed",
+            consecutive_failures.clone(),
+        );
+        let last_success = Gauge::default();
+        registry.register(
+            "last_success_timestamp_seconds",
+            "When the last successful run finished",
+            last_success.clone(),
+        );
+        let publish_errors = Counter::default();
+        registry.register(
+            "publish_errors",
+            "Runs whose changes the sink could not publish",
+            publish_errors.clone(),
+        );
+
+        Self {
+            registry: Arc::new(registry),
+            changes,
+            detection_duration,
+            consecutive_failures,
+            last_success,
+            publish_errors,
+        }
+    }
+
+    /// Counts a change that was published.
+    pub fn record_change<Key>(&self, change: &StateChange<Key>) {
+        self.changes
+            .get_or_create(&[("change_type", change.change_type())])
+            .inc();
+    }
+
+    /// Counts a run whose changes the sink could not publish.
+    pub fn record_publish_error(&self) {
+        self.publish_errors.inc();
+    }
+
+    /// Records a finished run that took `duration`, and published the changes counted by
+    /// `result` or failed with its error.
+    pub fn record_tick(&self, duration: Duration, result: &Result<usize, String>) {
+        self.detection_duration.observe(duration.as_secs_f64());
+        match result {
+            Ok(_) => {
+                self.consecutive_failures.set(0);
+                if let Ok(since_epoch) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                    self.last_success.set(since_epoch.as_secs_f64());
+                }
+            }
+            Err(_) => {
+                self.consecutive_failures.inc();
+            }
+        }
+    }
+
+    /// The metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> String {
+        let mut body = String::new();
+        encode(&mut body, &self.registry).expect("writing to a string does not fail");
+        body
+    }
+}
+
+/// Serves `/metrics` on `listener` until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Metrics,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+async fn scrape(State(metrics): State<Metrics>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        metrics.encode(),
+    )
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::Metrics;
+    use crate::{
+        engine::EngineConfig,
+        sink::StdoutSink,
+        state::{
+            ChangeDetector, ChangeDetectorResult, DefaultTableState, InMemoryPersistence,
+            StateChange, TableState,
+        },
+        sync::CancellationToken,
+    };
+    use std::{
+        net::SocketAddr,
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        select,
+        time::sleep,
+    };
+
+    /// Reports `a` with the run number as its hash, and `b` on the first run only.
+    #[derive(Clone, Default)]
+    struct CountingDetector {
+        runs: Rc<AtomicUsize>,
+    }
+
+    impl ChangeDetector for CountingDetector {
+        type Key = String;
+        type Hash = usize;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            state.set_row("a".to_string(), run);
+            if run == 0 {
+                state.set_row("b".to_string(), run);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+    }
+
+    /// Requests `/metrics` from the server at `addr` and returns the response body, or `None`
+    /// if the server is not listening yet.
+    async fn scrape(addr: SocketAddr) -> Option<String> {
+        let mut stream = TcpStream::connect(addr).await.ok()?;
+        let request = "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(request.as_bytes()).await.ok()?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+    }
+
+    #[test]
+    fn names_are_stable() {
+        let metrics = Metrics::new("files");
+        metrics.record_change(&StateChange::New("a"));
+        metrics.record_publish_error();
+        metrics.record_tick(Duration::from_millis(30), &Ok(1));
+        metrics.record_tick(Duration::from_millis(30), &Err("a".to_string()));
+        metrics.record_tick(Duration::from_millis(30), &Err("b".to_string()));
+
+        let body = metrics.encode();
+        for line in [
+            r#"rabbit_eye_changes_total{source="files",change_type="new"} 1"#,
+            r#"rabbit_eye_detection_duration_seconds_count{source="files"} 3"#,
+            r#"rabbit_eye_consecutive_failures{source="files"} 2"#,
+            r#"rabbit_eye_publish_errors_total{source="files"} 1"#,
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{} is missing from\n{}",
+                line,
+                body
+            );
+        }
+        let last_success = body
+            .lines()
+            .find_map(|l| {
+                l.strip_prefix(r#"rabbit_eye_last_success_timestamp_seconds{source="files"} "#)
+            })
+            .unwrap();
+        assert!(last_success.parse::<f64>().unwrap() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn endpoint_counts_changes_after_ticks() {
+        // Find a free port for the engine to bind.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = EngineConfig::new(Duration::from_millis(20))
+            .unwrap()
+            .with_metrics_addr(addr)
+            .with_source("counting")
+            .build();
+        let (_status, engine) = crate::engine::run(
+            CountingDetector::default(),
+            StdoutSink,
+            InMemoryPersistence::<DefaultTableState<String, usize>>::default(),
+            config,
+        );
+
+        let deleted = r#"rabbit_eye_changes_total{source="counting",change_type="delete"} 1"#;
+        let scraped = async {
+            loop {
+                if let Some(body) = scrape(addr).await
+                    && body.lines().any(|line| line == deleted)
+                {
+                    return body;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        let body = select! {
+            result = engine => panic!("the engine stopped early: {:?}", result.err()),
+            body = scraped => body,
+            _ = sleep(Duration::from_secs(10)) => panic!("the changes were never counted"),
+        };
+        assert!(
+            body.contains(r#"rabbit_eye_changes_total{source="counting",change_type="new"} 2"#)
+        );
+        assert!(
+            body.contains(r#"rabbit_eye_changes_total{source="counting",change_type="update"}"#)
+        );
+        assert!(body.contains(r#"rabbit_eye_consecutive_failures{source="counting"} 0"#));
+    }
+}
@@ -0,0 +1,665 @@
+use crate::state::StateChange;
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Receives the changes the engine drains from state as each run of the change detector finds
+/// them.
+pub trait ChangeSink<Key> {
+    /// Delivers a single change, with `payload` as the body of its message. `row_hash` is a
+    /// hash of the row's hash when the change was found, so it differs each time the row
+    /// changes, or `None` if the detector did not report it. If this fails, the engine keeps the
+    /// change out of the state it saves, so the next run publishes it again.
+    #[allow(async_fn_in_trait)]
+    async fn publish(
+        &self,
+        change: &StateChange<Key>,
+        row_hash: Option<u64>,
+        payload: &[u8],
+    ) -> Result<(), SinkError>;
+
+    /// Delivers a batch of a run's changes in order, each with its row hash and the body of its
+    /// message. Sinks that can deliver many changes at once override this, and otherwise each
+    /// change is published in turn. If this fails, some of the changes may have been delivered.
+    #[allow(async_fn_in_trait)]
+    async fn publish_batch(
+        &self,
+        changes: &[(StateChange<Key>, Option<u64>, Vec<u8>)],
+    ) -> Result<(), SinkError> {
+        for (change, row_hash, payload) in changes {
+            self.publish(change, *row_hash, payload).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a sink could not deliver a change.
+#[derive(Debug)]
+pub enum SinkError {
+    /// The message broker did not accept the change.
+    Broker(Box<dyn Error + Send + Sync>),
+    /// The change could not be written.
+    Io(io::Error),
+    /// Any other failure, described by the sink.
+    Other(Box<dyn Error + Send + Sync>),
+    /// Several sinks of a `TeeSink` failed, in the order they were added.
+    Multiple(Vec<SinkError>),
+}
+
+impl Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Broker(e) => write!(f, "the broker did not accept the change: {}", e),
+            Self::Io(e) => write!(f, "the change could not be written: {}", e),
+            Self::Other(e) => write!(f, "{}", e),
+            Self::Multiple(errors) => {
+                write!(f, "{} sinks failed", errors.len())?;
+                for e in errors {
+                    write!(f, "; {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for SinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Broker(e) | Self::Other(e) => Some(&**e),
+            Self::Io(e) => Some(e),
+            Self::Multiple(errors) => errors.first().map(|e| e as &(dyn Error + 'static)),
+        }
+    }
+}
+
+impl From<io::Error> for SinkError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Prints the payload of each change to stdout, decoded as UTF-8.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutSink;
+
+impl<Key> ChangeSink<Key> for StdoutSink {
+    async fn publish(
+        &self,
+        _change: &StateChange<Key>,
+        _row_hash: Option<u64>,
+        payload: &[u8],
+    ) -> Result<(), SinkError> {
+        println!("{}", String::from_utf8_lossy(payload));
+        Ok(())
+    }
+}
+
+/// Keeps each change and its payload in memory, for tests.
+#[derive(Debug, Default)]
+pub struct VecSink<Key> {
+    changes: Mutex<Vec<(StateChange<Key>, Vec<u8>)>>,
+}
+
+impl<Key: Clone> VecSink<Key> {
+    /// The changes published so far, oldest first.
+    pub fn changes(&self) -> Vec<(StateChange<Key>, Vec<u8>)> {
+        self.changes.lock().unwrap().clone()
+    }
+
+    /// Removes and returns the changes published so far, oldest first.
+    pub fn take(&self) -> Vec<(StateChange<Key>, Vec<u8>)> {
+        std::mem::take(&mut self.changes.lock().unwrap())
+    }
+}
+
+impl<Key: Clone> ChangeSink<Key> for VecSink<Key> {
+    async fn publish(
+        &self,
+        change: &StateChange<Key>,
+        _row_hash: Option<u64>,
+        payload: &[u8],
+    ) -> Result<(), SinkError> {
+        self.changes
+            .lock()
+            .unwrap()
+            .push((change.clone(), payload.to_vec()));
+        Ok(())
+    }
+}
+
+/// What a `TeeSink` does when some of its sinks fail to publish a change.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TeeFailurePolicy {
+    /// The publish fails if any sink fails.
+    #[default]
+    Fail,
+    /// Failures are logged and the publish succeeds, as long as one sink succeeds.
+    LogAndContinue,
+}
+
+/// An object-safe view of a `ChangeSink` so sinks of different types can be stored together.
+trait ErasedSink<Key> {
+    fn publish<'a>(
+        &'a self,
+        change: &'a StateChange<Key>,
+        row_hash: Option<u64>,
+        payload: &'a [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<(), SinkError>> + 'a>>;
+
+    fn publish_batch<'a>(
+        &'a self,
+        changes: &'a [(StateChange<Key>, Option<u64>, Vec<u8>)],
+    ) -> Pin<Box<dyn Future<Output = Result<(), SinkError>> + 'a>>;
+}
+
+impl<Key, S> ErasedSink<Key> for S
+where
+    S: ChangeSink<Key>,
+{
+    fn publish<'a>(
+        &'a self,
+        change: &'a StateChange<Key>,
+        row_hash: Option<u64>,
+        payload: &'a [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<(), SinkError>> + 'a>> {
+        Box::pin(ChangeSink::publish(self, change, row_hash, payload))
+    }
+
+    fn publish_batch<'a>(
+        &'a self,
+        changes: &'a [(StateChange<Key>, Option<u64>, Vec<u8>)],
+    ) -> Pin<Box<dyn Future<Output = Result<(), SinkError>> + 'a>> {
+        Box::pin(ChangeSink::publish_batch(self, changes))
+    }
+}
+
+/// Publishes each change to several sinks in the order they were added. Every sink is given
+/// each change even if an earlier one fails.
+pub struct TeeSink<Key> {
+    sinks: Vec<Box<dyn ErasedSink<Key>>>,
+    policy: TeeFailurePolicy,
+}
+
+impl<Key> TeeSink<Key> {
+    pub fn new() -> Self {
+        Self {
+            sinks: vec![],
+            policy: TeeFailurePolicy::default(),
+        }
+    }
+
+    pub fn with_sink(&mut self, sink: impl ChangeSink<Key> + 'static) -> &mut Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn with_policy(&mut self, policy: TeeFailurePolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn build(&mut self) -> Self {
+        Self {
+            sinks: std::mem::take(&mut self.sinks),
+            policy: self.policy,
+        }
+    }
+}
+
+impl<Key> Default for TeeSink<Key> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Key> TeeSink<Key> {
+    /// Applies the failure policy to the errors of the sinks that failed.
+    fn result(&self, mut errors: Vec<SinkError>) -> Result<(), SinkError> {
+        if errors.is_empty() {
+            return Ok(());
+        }
+        if self.policy == TeeFailurePolicy::LogAndContinue && errors.len() < self.sinks.len() {
+            for e in &errors {
+                warn!("A sink could not publish a change. {}", e);
+            }
+            return Ok(());
+        }
+        if errors.len() == 1 {
+            Err(errors.pop().unwrap())
+        } else {
+            Err(SinkError::Multiple(errors))
+        }
+    }
+}
+
+impl<Key> ChangeSink<Key> for TeeSink<Key> {
+    async fn publish(
+        &self,
+        change: &StateChange<Key>,
+        row_hash: Option<u64>,
+        payload: &[u8],
+    ) -> Result<(), SinkError> {
+        let mut errors = vec![];
+        for sink in &self.sinks {
+            if let Err(e) = sink.publish(change, row_hash, payload).await {
+                errors.push(e);
+            }
+        }
+        self.result(errors)
+    }
+
+    /// Gives the whole batch to each sink in turn, so each can deliver it at once.
+    async fn publish_batch(
+        &self,
+        changes: &[(StateChange<Key>, Option<u64>, Vec<u8>)],
+    ) -> Result<(), SinkError> {
+        let mut errors = vec![];
+        for sink in &self.sinks {
+            if let Err(e) = sink.publish_batch(changes).await {
+                errors.push(e);
+            }
+        }
+        self.result(errors)
+    }
+}
+
+/// The last change a `DedupSink` published for each key it remembers, by its change type, row
+/// hash, and when it was published.
+struct Seen<Key> {
+    published: HashMap<Key, (&'static str, u64, Instant)>,
+    /// The remembered keys, least recently published first.
+    order: VecDeque<Key>,
+}
+
+/// Drops a change that was already published within a window of time, and forwards the rest to
+/// the inner sink. This quiets the repeats an at-least-once pipeline can produce, such as from
+/// overlapping detectors or restarts before the state is saved.
+///
+/// A change is a repeat if the last change published for its key within the `ttl` has the same
+/// change type and row hash. The payload is not compared, since the engine's envelope holds the
+/// time it was emitted. A change without a row hash is never a repeat. Only the `capacity` most
+/// recently published keys are remembered, so a repeat of an older change is delivered again.
+/// Changes the inner sink fails to publish are not remembered.
+pub struct DedupSink<S, Key> {
+    inner: S,
+    capacity: usize,
+    ttl: Duration,
+    seen: Mutex<Seen<Key>>,
+}
+
+impl<S, Key> DedupSink<S, Key> {
+    pub fn new(inner: S, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            capacity,
+            ttl,
+            seen: Mutex::new(Seen {
+                published: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// The number of recently published keys remembered.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How long a published change suppresses its repeats.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, Key> ChangeSink<Key> for DedupSink<S, Key>
+where
+    S: ChangeSink<Key>,
+    Key: Clone + Eq + Hash,
+{
+    async fn publish(
+        &self,
+        change: &StateChange<Key>,
+        row_hash: Option<u64>,
+        payload: &[u8],
+    ) -> Result<(), SinkError> {
+        let Some(row_hash) = row_hash else {
+            return self.inner.publish(change, None, payload).await;
+        };
+        let key = change.key();
+        let change_type = change.change_type();
+
+        let now = Instant::now();
+        {
+            let seen = self.seen.lock().unwrap();
+            if seen
+                .published
+                .get(key)
+                .is_some_and(|(last_type, last_hash, at)| {
+                    *last_type == change_type
+                        && *last_hash == row_hash
+                        && now.duration_since(*at) < self.ttl
+                })
+            {
+                debug!("A repeated change was not published.");
+                return Ok(());
+            }
+        }
+
+        self.inner.publish(change, Some(row_hash), payload).await?;
+
+        let seen = &mut *self.seen.lock().unwrap();
+        if seen
+            .published
+            .insert(key.clone(), (change_type, row_hash, now))
+            .is_some()
+        {
+            // Published again, so the key is now the most recently published.
+            let position = seen.order.iter().position(|remembered| remembered == key);
+            if let Some(position) = position {
+                seen.order.remove(position);
+            }
+        }
+        seen.order.push_back(key.clone());
+        while seen.published.len() > self.capacity {
+            let Some(oldest) = seen.order.pop_front() else {
+                break;
+            };
+            seen.published.remove(&oldest);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_sink {
+    use super::{ChangeSink, DedupSink, SinkError, StdoutSink, TeeFailurePolicy, TeeSink, VecSink};
+    use crate::state::StateChange;
+    use std::{error::Error, io, rc::Rc, time::Duration};
+    use tokio::time::advance;
+
+    /// Fails every publish.
+    struct FailingSink;
+
+    impl ChangeSink<&'static str> for FailingSink {
+        async fn publish(
+            &self,
+            _change: &StateChange<&'static str>,
+            _row_hash: Option<u64>,
+            _payload: &[u8],
+        ) -> Result<(), SinkError> {
+            Err(io::Error::other("the disk is full").into())
+        }
+    }
+
+    impl<Key: Clone> ChangeSink<Key> for Rc<VecSink<Key>> {
+        async fn publish(
+            &self,
+            change: &StateChange<Key>,
+            row_hash: Option<u64>,
+            payload: &[u8],
+        ) -> Result<(), SinkError> {
+            self.as_ref().publish(change, row_hash, payload).await
+        }
+    }
+
+    #[tokio::test]
+    async fn vec_sink_keeps_changes_in_order() {
+        let sink = VecSink::default();
+        sink.publish(&StateChange::New("a"), None, b"new a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Delete("b"), None, b"delete b")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                (StateChange::New("a"), b"new a".to_vec()),
+                (StateChange::Delete("b"), b"delete b".to_vec()),
+            ],
+            sink.take()
+        );
+        assert!(sink.changes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stdout_sink_accepts_binary_payloads() {
+        let change = StateChange::Update(1);
+        StdoutSink
+            .publish(&change, None, &[0xff, b'a'])
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn sink_error_keeps_source() {
+        let e = SinkError::from(io::Error::other("the disk is full"));
+        assert!(e.to_string().contains("the disk is full"));
+        assert!(e.source().is_some());
+    }
+
+    #[tokio::test]
+    async fn tee_sink_publishes_to_every_sink() {
+        let first = Rc::new(VecSink::default());
+        let second = Rc::new(VecSink::default());
+        let tee = TeeSink::new()
+            .with_sink(first.clone())
+            .with_sink(second.clone())
+            .build();
+
+        tee.publish(&StateChange::New("a"), None, b"a")
+            .await
+            .unwrap();
+        tee.publish(&StateChange::Delete("b"), None, b"b")
+            .await
+            .unwrap();
+
+        let expected = vec![
+            (StateChange::New("a"), b"a".to_vec()),
+            (StateChange::Delete("b"), b"b".to_vec()),
+        ];
+        assert_eq!(expected, first.changes());
+        assert_eq!(expected, second.changes());
+    }
+
+    #[tokio::test]
+    async fn tee_sink_publishes_batches_to_every_sink() {
+        let first = Rc::new(VecSink::default());
+        let second = Rc::new(VecSink::default());
+        let tee = TeeSink::new()
+            .with_sink(first.clone())
+            .with_sink(FailingSink)
+            .with_sink(second.clone())
+            .with_policy(TeeFailurePolicy::LogAndContinue)
+            .build();
+
+        let batch: Vec<_> = (0..100)
+            .map(|i| {
+                (
+                    StateChange::New("a"),
+                    None,
+                    format!("new a {}", i).into_bytes(),
+                )
+            })
+            .collect();
+        tee.publish_batch(&batch).await.unwrap();
+        let expected: Vec<_> = batch
+            .into_iter()
+            .map(|(change, _, payload)| (change, payload))
+            .collect();
+        assert_eq!(expected, first.changes());
+        assert_eq!(expected, second.changes());
+    }
+
+    #[tokio::test]
+    async fn tee_sink_fails_after_publishing_to_the_rest() {
+        let after = Rc::new(VecSink::default());
+        let tee = TeeSink::new()
+            .with_sink(FailingSink)
+            .with_sink(after.clone())
+            .build();
+
+        let e = tee
+            .publish(&StateChange::New("a"), None, b"a")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, SinkError::Io(_)));
+        assert_eq!(1, after.changes().len());
+    }
+
+    #[tokio::test]
+    async fn tee_sink_continues_past_failures_by_policy() {
+        let after = Rc::new(VecSink::default());
+        let tee = TeeSink::new()
+            .with_sink(FailingSink)
+            .with_sink(after.clone())
+            .with_policy(TeeFailurePolicy::LogAndContinue)
+            .build();
+        tee.publish(&StateChange::New("a"), None, b"a")
+            .await
+            .unwrap();
+        assert_eq!(1, after.changes().len());
+
+        let all_failed = TeeSink::new()
+            .with_sink(FailingSink)
+            .with_sink(FailingSink)
+            .with_policy(TeeFailurePolicy::LogAndContinue)
+            .build();
+        let e = all_failed
+            .publish(&StateChange::New("a"), None, b"a")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, SinkError::Multiple(errors) if errors.len() == 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_sink_drops_repeats_within_ttl() {
+        let inner = Rc::new(VecSink::default());
+        let sink = DedupSink::new(inner.clone(), 10, Duration::from_secs(60));
+
+        sink.publish(&StateChange::Update("a"), Some(1), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Update("a"), Some(1), b"a")
+            .await
+            .unwrap();
+        assert_eq!(1, inner.take().len());
+
+        // A different type, row hash, or key is not a repeat, and neither is a change back to
+        // one published earlier for the key.
+        sink.publish(&StateChange::Delete("a"), Some(1), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Update("b"), Some(1), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Update("a"), Some(1), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Update("a"), Some(2), b"a")
+            .await
+            .unwrap();
+        assert_eq!(4, inner.take().len());
+
+        // The payload is not compared, as the engine's envelopes differ by when they were made.
+        sink.publish(&StateChange::Update("a"), Some(2), b"a2")
+            .await
+            .unwrap();
+        assert!(inner.take().is_empty());
+
+        advance(Duration::from_secs(60)).await;
+        sink.publish(&StateChange::Update("a"), Some(2), b"a")
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![(StateChange::Update("a"), b"a".to_vec())],
+            inner.take()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_sink_delivers_changes_without_row_hash() {
+        let inner = Rc::new(VecSink::default());
+        let sink = DedupSink::new(inner.clone(), 10, Duration::from_secs(60));
+
+        sink.publish(&StateChange::Update("a"), None, b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::Update("a"), None, b"a")
+            .await
+            .unwrap();
+        assert_eq!(2, inner.take().len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_sink_forgets_beyond_capacity() {
+        let inner = Rc::new(VecSink::default());
+        let sink = DedupSink::new(inner.clone(), 1, Duration::from_secs(60));
+
+        for key in ["a", "b", "b", "a"] {
+            sink.publish(&StateChange::New(key), Some(1), key.as_bytes())
+                .await
+                .unwrap();
+        }
+        assert_eq!(3, inner.take().len());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dedup_sink_forgets_least_recently_published() {
+        let inner = Rc::new(VecSink::default());
+        let sink = DedupSink::new(inner.clone(), 2, Duration::from_secs(60));
+
+        sink.publish(&StateChange::New("a"), Some(1), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::New("b"), Some(1), b"b")
+            .await
+            .unwrap();
+        // Publishing `a` again makes `b` the least recently published, so `c` evicts it.
+        sink.publish(&StateChange::Update("a"), Some(2), b"a")
+            .await
+            .unwrap();
+        sink.publish(&StateChange::New("c"), Some(1), b"c")
+            .await
+            .unwrap();
+        assert_eq!(4, inner.take().len());
+
+        sink.publish(&StateChange::Update("a"), Some(2), b"a")
+            .await
+            .unwrap();
+        assert!(inner.take().is_empty());
+        sink.publish(&StateChange::New("b"), Some(1), b"b")
+            .await
+            .unwrap();
+        assert_eq!(1, inner.take().len());
+    }
+
+    #[tokio::test]
+    async fn dedup_sink_retries_failed_changes() {
+        let sink = DedupSink::new(FailingSink, 10, Duration::from_secs(60));
+        assert!(
+            sink.publish(&StateChange::New("a"), Some(1), b"a")
+                .await
+                .is_err()
+        );
+        assert!(
+            sink.publish(&StateChange::New("a"), Some(1), b"a")
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{
+    state::{ChangeDetector, ChangeDetectorResult, TableState},
+    sync::CancellationToken,
+};
+use futures::TryStreamExt;
+use sqlx::{
+    AnyPool, Decode, Row, Type,
+    any::{Any, AnyRow},
+};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    marker::PhantomData,
+};
+
+/// Detects changes to the rows of a SQL table. The first column of the query is the key of each
+/// row and the remaining columns are hashed into its row hash, so inserts, updates, and deletes
+/// in the table become changes. Rows are streamed from the database rather than loaded at once.
+pub struct SqlTableChangeDetector<Key = i64> {
+    pool: AnyPool,
+    query: String,
+    tablehash_query: Option<String>,
+    key: PhantomData<fn() -> Key>,
+}
+
+impl<Key> SqlTableChangeDetector<Key> {
+    /// Selects `id_column` and `columns` from every row of `table`. The names are used as they
+    /// are, so they must be quoted by the caller if they need to be.
+    pub fn new(pool: AnyPool, table: &str, id_column: &str, columns: &[&str]) -> Self {
+        let mut selected = vec![id_column];
+        selected.extend_from_slice(columns);
+        Self::from_query(
+            pool,
+            format!("SELECT {} FROM {}", selected.join(", "), table),
+        )
+    }
+
+    /// Runs `query`, whose first column is the key of each row.
+    pub fn from_query(pool: AnyPool, query: impl Into<String>) -> Self {
+        Self {
+            pool,
+            query: query.into(),
+            tablehash_query: None,
+            key: PhantomData,
+        }
+    }
+
+    /// A query returning a single row that changes whenever the table does, such as
+    /// `SELECT MAX(updated_at) FROM table` or a row count and checksum. Its columns are hashed
+    /// into the table hash, so the rows are not queried while it is unchanged.
+    pub fn with_tablehash_query(&mut self, query: impl Into<String>) -> &mut Self {
+        self.tablehash_query = Some(query.into());
+        self
+    }
+
+    pub fn build(&self) -> Self {
+        self.clone()
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+}
+
+impl<Key> Clone for SqlTableChangeDetector<Key> {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            query: self.query.clone(),
+            tablehash_query: self.tablehash_query.clone(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<Key> ChangeDetector for SqlTableChangeDetector<Key>
+where
+    Key: for<'r> Decode<'r, Any> + Type<Any>,
+{
+    type Key = Key;
+    type Hash = u64;
+
+    async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+        let query = self.tablehash_query.as_deref()?;
+        let row = tokio::select! {
+            _ = cancel.cancelled() => return None,
+            row = sqlx::query(query).fetch_one(&self.pool) => row,
+        };
+
+        match row.and_then(|row| hash_columns(&row, 0)) {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                warn!("The table hash could not be queried. {}", e);
+                None
+            }
+        }
+    }
+
+    async fn rowhash(
+        self,
+        state: &mut impl TableState<Self::Key, Self::Hash>,
+        cancel: &CancellationToken,
+    ) -> ChangeDetectorResult {
+        // Dropping the stream when cancelled abandons the query.
+        let mut rows = sqlx::query(&self.query).fetch(&self.pool);
+        loop {
+            let row = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return ChangeDetectorResult::Cancelled,
+                row = rows.try_next() => row,
+            };
+
+            let row = match row {
+                Ok(Some(row)) => row,
+                Ok(None) => return ChangeDetectorResult::DeleteRemainder,
+                Err(e) => return ChangeDetectorResult::Faulted(e.into()),
+            };
+            let key = match row.try_get(0) {
+                Ok(key) => key,
+                Err(e) => return ChangeDetectorResult::Faulted(e.into()),
+            };
+            match hash_columns(&row, 1) {
+                Ok(hash) => state.set_row(key, hash),
+                Err(e) => return ChangeDetectorResult::Faulted(e.into()),
+            }
+        }
+    }
+}
+
+/// Hashes the columns of `row` from index `skip` on. Each value is decoded as the first of an
+/// integer, float, text, blob, or boolean that it can be, with nulls hashing alike.
+fn hash_columns(row: &AnyRow, skip: usize) -> Result<u64, sqlx::Error> {
+    let mut hasher = DefaultHasher::new();
+    for index in skip..row.len() {
+        if let Ok(value) = row.try_get::<Option<i64>, _>(index) {
+            value.hash(&mut hasher);
+        } else if let Ok(value) = row.try_get::<Option<f64>, _>(index) {
+            value.map(f64::to_bits).hash(&mut hasher);
+        } else if let Ok(value) = row.try_get::<Option<String>, _>(index) {
+            value.hash(&mut hasher);
+        } else if let Ok(value) = row.try_get::<Option<Vec<u8>>, _>(index) {
+            value.hash(&mut hasher);
+        } else {
+            row.try_get::<Option<bool>, _>(index)?.hash(&mut hasher);
+        }
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod test_sql {
+    use super::SqlTableChangeDetector;
+    use crate::{
+        state::{ChangeDetector, ChangeDetectorResult, DefaultTableState, StateChange, TableState},
+        sync::CancellationToken,
+    };
+    use sqlx::AnyPool;
+
+    /// Opens a new SQLite database holding a `people` table.
+    async fn people(name: &str) -> AnyPool {
+        sqlx::any::install_default_drivers();
+        let path =
+            std::env::temp_dir().join(format!("rabbit-eye-{}-{}.sqlite", name, std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let pool = AnyPool::connect(&format!("sqlite://{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT, age INTEGER, updated_at INTEGER)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO people VALUES (1, 'Ada', 36, 1), (2, 'Alan', 41, 1), (3, 'Grace', NULL, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+
+    async fn snapshot(
+        detector: &SqlTableChangeDetector,
+        state: &mut DefaultTableState<i64, u64>,
+    ) -> Vec<StateChange<i64>> {
+        let result = detector
+            .build()
+            .rowhash(&mut *state, &CancellationToken::new())
+            .await;
+        assert!(matches!(result, ChangeDetectorResult::DeleteRemainder));
+
+        let mut changes: Vec<_> = state.drain(true).collect();
+        changes.sort_by_key(|change| match change {
+            StateChange::New(key) | StateChange::Update(key) | StateChange::Delete(key) => *key,
+        });
+        changes
+    }
+
+    #[tokio::test]
+    async fn detects_changes_between_snapshots() {
+        let pool = people("snapshots").await;
+        let detector = SqlTableChangeDetector::new(pool.clone(), "people", "id", &["name", "age"]);
+        let mut state = DefaultTableState::default();
+
+        assert_eq!(
+            vec![
+                StateChange::New(1),
+                StateChange::New(2),
+                StateChange::New(3)
+            ],
+            snapshot(&detector, &mut state).await
+        );
+        assert!(snapshot(&detector, &mut state).await.is_empty());
+
+        sqlx::query("UPDATE people SET age = 37 WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM people WHERE id = 2")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO people VALUES (4, 'Linus', 28, 2)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                StateChange::Update(1),
+                StateChange::Delete(2),
+                StateChange::New(4)
+            ],
+            snapshot(&detector, &mut state).await
+        );
+    }
+
+    #[tokio::test]
+    async fn tablehash_follows_the_query() {
+        let pool = people("tablehash").await;
+        let cancel = CancellationToken::new();
+        let mut detector =
+            SqlTableChangeDetector::<i64>::new(pool.clone(), "people", "id", &["name"]);
+        assert_eq!(None, detector.tablehash(&cancel).await);
+
+        detector.with_tablehash_query("SELECT MAX(updated_at), COUNT(*) FROM people");
+        let before = detector.tablehash(&cancel).await;
+        assert!(before.is_some());
+        assert_eq!(before, detector.tablehash(&cancel).await);
+
+        sqlx::query("UPDATE people SET name = 'Ada Lovelace', updated_at = 2 WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_ne!(before, detector.tablehash(&cancel).await);
+    }
+
+    #[tokio::test]
+    async fn cancellation_stops_the_query() {
+        let pool = people("cancel").await;
+        let detector =
+            SqlTableChangeDetector::<i64>::from_query(pool, "SELECT id, name FROM people");
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let mut state = DefaultTableState::default();
+        let result = detector.rowhash(&mut state, &cancel).await;
+        assert!(matches!(result, ChangeDetectorResult::Cancelled));
+        assert_eq!(0, state.drain(false).count());
+    }
+
+    #[tokio::test]
+    async fn bad_query_faults() {
+        let pool = people("fault").await;
+        let detector = SqlTableChangeDetector::<i64>::from_query(pool, "SELECT id FROM missing");
+
+        let mut state = DefaultTableState::default();
+        let result = detector
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+        assert!(matches!(result, ChangeDetectorResult::Faulted(_)));
+    }
+}
@@ -100,7 +100,9 @@ pub use persist::*;
 mod state_change {
     use std::collections::{HashMap, HashSet};
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    #[cfg_attr(feature = "serde", serde(tag = "type", content = "key"))]
     pub enum StateChange<Key> {
         New(Key),
         Update(Key),
@@ -108,13 +110,54 @@ mod state_change {
     }
 
     #[derive(Debug)]
-    enum NotifiedState<Key> {
+    enum NotifiedState<Key, Hash> {
         None(Key),
         New(Key),
-        Update(Key),
-        Delete(Key),
+        /// The row's hash changed, along with the hash it had before.
+        Update(Key, Hash),
+        /// The row was removed, along with the hash it had before it was removed.
+        Delete(Key, Hash),
+    }
+
+    /// Counts of each kind of change produced by a single drain.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ChangeSummary {
+        pub new: usize,
+        pub updated: usize,
+        pub deleted: usize,
+        /// Rows that were seen but whose hash did not change.
+        pub unchanged: usize,
+    }
+
+    impl std::fmt::Display for ChangeSummary {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "{} new, {} updated, {} deleted, {} unchanged",
+                self.new, self.updated, self.deleted, self.unchanged
+            )
+        }
     }
 
+    /// The deletes in a change set exceeded the allowed fraction of known rows.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ChangeGuardError {
+        pub attempted_deletes: usize,
+        pub total: usize,
+    }
+
+    impl std::fmt::Display for ChangeGuardError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(
+                f,
+                "refusing to delete {} of {} known row(s)",
+                self.attempted_deletes, self.total
+            )
+        }
+    }
+
+    impl std::error::Error for ChangeGuardError {}
+
     pub trait TableState<Key, Hash>: Default {
         fn tablehash(&self) -> Option<u64>;
 
@@ -123,19 +166,50 @@ mod state_change {
         /// state of the key.
         fn set_row(&mut self, key: Key, hash: Hash);
 
+        /// Notifies the state that the key is no longer present. Detectors that learn of
+        /// individual deletes (e.g. from events) use this rather than `delete_remainder`. The
+        /// default implementation ignores it, so the delete is found by the next drain that
+        /// deletes the remainder instead.
+        fn remove_row(&mut self, _key: Key) {}
+
         /// Consumes the change queue and produces the change set. This change set should be merged into
         /// persistence and notified to the message bus.
         /// `delete_remainder` determines if anything not passed to `set_presence` should be
         /// considered deleted. This flag should be set if the state was able to be updated
         /// fully, and should be `false` if the change detector was stopped prematurely.
         fn drain(&mut self, delete_remainder: bool) -> impl Iterator<Item = StateChange<Key>>;
+
+        /// Consumes the change queue like `drain`, pairing each change with the row's hash: the
+        /// current hash of new and updated rows, and the last known hash of deleted rows. The
+        /// hash is `None` if the row is no longer known, e.g. it was removed after being set.
+        /// The default implementation pairs every change from `drain` with `None`.
+        fn drain_hashed(
+            &mut self,
+            delete_remainder: bool,
+        ) -> impl Iterator<Item = (StateChange<Key>, Option<Hash>)> {
+            self.drain(delete_remainder).map(|change| (change, None))
+        }
+
+        /// Settles the changes drained since it was last called, once they have been published.
+        /// The rows of the `failed` keys go back to how they were before they changed, so the
+        /// next drain reports them again, and the rest of the changes are kept. The engine settles
+        /// the state after each run; anything else that drains a state should settle it too. The
+        /// default implementation keeps every change, so failed changes are not reported again.
+        fn settle(&mut self, _failed: impl IntoIterator<Item = Key>) {}
     }
 
+    /// Tracks rows in memory. Its drains emit the changes of `set_row` and `remove_row` in the
+    /// order those were called, so a row created and then modified is `New` before it is
+    /// `Update`d. The deletes of rows that were not seen follow them in no particular order,
+    /// unless drained with `drain_sorted`.
     #[derive(Debug)]
     pub struct DefaultTableState<Key, Hash> {
         tablehash: Option<u64>,
         rows: HashMap<Key, Hash>,
-        changes: Vec<NotifiedState<Key>>,
+        changes: Vec<NotifiedState<Key, Hash>>,
+        /// The hash each row drained since the last `settle` had before it changed, or `None`
+        /// if the row was new.
+        drained: HashMap<Key, Option<Hash>>,
     }
 
     impl<Key, Hash> DefaultTableState<Key, Hash> {
@@ -144,7 +218,235 @@ mod state_change {
                 tablehash,
                 rows,
                 changes: vec![],
+                drained: HashMap::new(),
+            }
+        }
+
+        /// Forgets every row, the pending changes, and the table hash, as if the state were new.
+        /// Every row the detector sees afterwards is new, e.g. to force a full resync.
+        pub fn clear(&mut self) {
+            self.tablehash = None;
+            self.rows.clear();
+            self.changes.clear();
+            self.drained.clear();
+        }
+
+        /// Discards the pending changes but keeps the rows with the hashes they were last set
+        /// to, so those rows are the baseline the next changes are found against.
+        pub fn reset_changes(&mut self) {
+            self.changes.clear();
+        }
+
+        /// The hash the row of `key` was last set to, or `None` if the row is not known. A
+        /// detector may use it to skip hashing a row it can tell has not changed.
+        pub fn get(&self, key: &Key) -> Option<&Hash>
+        where
+            Key: Eq + std::hash::Hash,
+        {
+            self.rows.get(key)
+        }
+
+        pub fn contains_key(&self, key: &Key) -> bool
+        where
+            Key: Eq + std::hash::Hash,
+        {
+            self.rows.contains_key(key)
+        }
+
+        /// The known rows and their hashes, in no particular order, without consuming them.
+        pub fn iter(&self) -> impl Iterator<Item = (&Key, &Hash)> {
+            self.rows.iter()
+        }
+
+        /// The number of known rows.
+        pub fn len(&self) -> usize {
+            self.rows.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.rows.is_empty()
+        }
+
+        /// Computes the change set that `drain` would produce for the same `delete_remainder`
+        /// without consuming the pending changes, so it can be used for dry runs and diagnostics.
+        pub fn preview_changes(&self, delete_remainder: bool) -> Vec<StateChange<Key>>
+        where
+            Key: Eq + std::hash::Hash + Clone,
+        {
+            self.summarize(delete_remainder).1
+        }
+
+        /// Drains the change queue like `drain`, and also counts each kind of change so that
+        /// the caller does not need to re-count them while iterating.
+        pub fn drain_summary(
+            &mut self,
+            delete_remainder: bool,
+        ) -> (ChangeSummary, impl Iterator<Item = StateChange<Key>>)
+        where
+            Key: Eq + std::hash::Hash + Clone,
+        {
+            let (summary, changes, unseen) = self.summarize(delete_remainder);
+            self.commit(unseen);
+            (summary, changes.into_iter())
+        }
+
+        /// Drains the change queue like `drain`, unless the number of deletes would exceed
+        /// `max_delete_ratio` of the rows known before the changes. This guards against a faulty
+        /// detector wiping out the entire table. When the guard trips, the pending changes are
+        /// left in place so the caller may decide what to do with them (e.g. `drain(false)` to
+        /// keep everything but the deletes).
+        ///
+        /// Panics if `max_delete_ratio` is not between `0.0` and `1.0`.
+        pub fn drain_guarded(
+            &mut self,
+            delete_remainder: bool,
+            max_delete_ratio: f64,
+        ) -> Result<impl Iterator<Item = StateChange<Key>>, ChangeGuardError>
+        where
+            Key: Eq + std::hash::Hash + Clone,
+        {
+            assert!(
+                (0.0..=1.0).contains(&max_delete_ratio),
+                "the max delete ratio must be between 0 and 1, not {}",
+                max_delete_ratio
+            );
+            let (summary, changes, unseen) = self.summarize(delete_remainder);
+            let total = self.len_before_changes();
+
+            if summary.deleted as f64 > total as f64 * max_delete_ratio {
+                return Err(ChangeGuardError {
+                    attempted_deletes: summary.deleted,
+                    total,
+                });
+            }
+
+            self.commit(unseen);
+            Ok(changes.into_iter())
+        }
+
+        /// Drains the change queue like `drain`, but emits the deletes of unseen rows sorted by
+        /// key so the output is reproducible. New and updated rows keep the order in which
+        /// they were passed to `set_row`.
+        pub fn drain_sorted(
+            &mut self,
+            delete_remainder: bool,
+        ) -> impl Iterator<Item = StateChange<Key>>
+        where
+            Key: Ord + std::hash::Hash + Clone,
+        {
+            let (_, mut changes, mut unseen) = self.summarize_seen(delete_remainder);
+            unseen.sort();
+            changes.extend(unseen.iter().cloned().map(StateChange::Delete));
+            self.commit(unseen);
+            changes.into_iter()
+        }
+
+        /// The number of rows known before the pending changes, which added the new rows and
+        /// took away the rows removed with `remove_row`.
+        fn len_before_changes(&self) -> usize {
+            let (new, removed) = self
+                .changes
+                .iter()
+                .fold((0, 0), |(new, removed), notified| match notified {
+                    NotifiedState::New(_) => (new + 1, removed),
+                    NotifiedState::Delete(..) => (new, removed + 1),
+                    _ => (new, removed),
+                });
+            self.rows.len() + removed - new
+        }
+
+        /// Produces the change set, along with the keys of the unseen rows it deletes.
+        fn summarize(
+            &self,
+            delete_remainder: bool,
+        ) -> (ChangeSummary, Vec<StateChange<Key>>, Vec<Key>)
+        where
+            Key: Eq + std::hash::Hash + Clone,
+        {
+            let (summary, mut changes, unseen) = self.summarize_seen(delete_remainder);
+            changes.extend(unseen.iter().cloned().map(StateChange::Delete));
+            (summary, changes, unseen)
+        }
+
+        /// Clears the pending changes and forgets the `unseen` rows, which were drained as
+        /// deleted, so they are not deleted again by the next drain. How each drained row was
+        /// before is kept until the changes are settled.
+        fn commit(&mut self, unseen: Vec<Key>)
+        where
+            Key: Eq + std::hash::Hash,
+        {
+            for notified in self.changes.drain(..) {
+                let (key, before) = match notified {
+                    NotifiedState::None(_) => continue,
+                    NotifiedState::New(k) => (k, None),
+                    NotifiedState::Update(k, hash) | NotifiedState::Delete(k, hash) => {
+                        (k, Some(hash))
+                    }
+                };
+                self.drained.entry(key).or_insert(before);
+            }
+            for key in unseen {
+                if let Some(hash) = self.rows.remove(&key) {
+                    self.drained.entry(key).or_insert(Some(hash));
+                }
+            }
+        }
+
+        /// Produces the changes recorded by `set_row`, and separately the keys of rows that were
+        /// never seen and are therefore deleted (when `delete_remainder` is set).
+        fn summarize_seen(
+            &self,
+            delete_remainder: bool,
+        ) -> (ChangeSummary, Vec<StateChange<Key>>, Vec<Key>)
+        where
+            Key: Eq + std::hash::Hash + Clone,
+        {
+            // For each item in self.rows, check for a change in self.changes.
+            // If there is no change and delete_remainder = true, produce a Delete
+            // If there is a change, map it to the proper change type
+            // Then drain the rest of the changes (which should all be inserts at this point)
+            // and publish them also
+
+            let mut summary = ChangeSummary::default();
+            let mut changes = Vec::new();
+            let mut seen = HashSet::new();
+
+            for notified in &self.changes {
+                match notified {
+                    NotifiedState::Delete(k, _) => {
+                        seen.insert(k);
+                        summary.deleted += 1;
+                        changes.push(StateChange::Delete(k.clone()))
+                    }
+                    NotifiedState::New(k) => {
+                        seen.insert(k);
+                        summary.new += 1;
+                        changes.push(StateChange::New(k.clone()));
+                    }
+                    NotifiedState::Update(k, _) => {
+                        seen.insert(k);
+                        summary.updated += 1;
+                        changes.push(StateChange::Update(k.clone()));
+                    }
+                    NotifiedState::None(k) => {
+                        seen.insert(k);
+                        summary.unchanged += 1;
+                    }
+                }
             }
+
+            let unseen: Vec<_> = if delete_remainder {
+                self.rows
+                    .keys()
+                    .filter(|key| !seen.contains(key))
+                    .cloned()
+                    .collect()
+            } else {
+                vec![]
+            };
+            summary.deleted += unseen.len();
+
+            (summary, changes, unseen)
         }
     }
 
@@ -157,7 +459,7 @@ mod state_change {
     impl<Key, Hash> TableState<Key, Hash> for DefaultTableState<Key, Hash>
     where
         Key: Eq + std::hash::Hash + Clone,
-        Hash: Eq,
+        Hash: Eq + Clone,
     {
         fn tablehash(&self) -> Option<u64> {
             self.tablehash
@@ -168,8 +470,8 @@ mod state_change {
                 if value == &hash {
                     self.changes.push(NotifiedState::None(key));
                 } else {
-                    *value = hash;
-                    self.changes.push(NotifiedState::Update(key));
+                    let before = std::mem::replace(value, hash);
+                    self.changes.push(NotifiedState::Update(key, before));
                 }
             } else {
                 self.changes.push(NotifiedState::New(key.clone()));
@@ -177,48 +479,64 @@ mod state_change {
             }
         }
 
+        fn remove_row(&mut self, key: Key) {
+            if let Some(hash) = self.rows.remove(&key) {
+                self.changes.push(NotifiedState::Delete(key, hash));
+            }
+        }
+
         fn drain(&mut self, delete_remainder: bool) -> impl Iterator<Item = StateChange<Key>> {
-            // For each item in self.rows, check for a change in self.changes.
-            // If there is no change and delete_remainder = true, produce a Delete
-            // If there is a change, map it to the proper change type
-            // Then drain the rest of the changes (which should all be inserts at this point)
-            // and publish them also
+            let (_, changes, unseen) = self.summarize(delete_remainder);
+            self.commit(unseen);
+            changes.into_iter()
+        }
 
-            let mut changes = Vec::new();
-            let mut unseen = HashSet::new();
+        fn drain_hashed(
+            &mut self,
+            delete_remainder: bool,
+        ) -> impl Iterator<Item = (StateChange<Key>, Option<Hash>)> {
+            let (_, changes, unseen) = self.summarize(delete_remainder);
 
-            if delete_remainder {
-                for key in self.rows.keys() {
-                    unseen.insert(key);
-                }
-            }
+            // Rows removed with `remove_row` are no longer in `rows`, so their last hash is
+            // kept in the queue instead.
+            let mut removed: HashMap<_, _> = self
+                .changes
+                .iter()
+                .filter_map(|seen| match seen {
+                    NotifiedState::Delete(k, hash) => Some((k.clone(), hash.clone())),
+                    _ => None,
+                })
+                .collect();
 
-            let was_changes = std::mem::replace(&mut self.changes, vec![]);
-            for seen in was_changes {
-                match seen {
-                    NotifiedState::Delete(k) => {
-                        unseen.remove(&k);
-                        changes.push(StateChange::Delete(k))
-                    }
-                    NotifiedState::New(k) => {
-                        unseen.remove(&k);
-                        changes.push(StateChange::New(k));
-                    }
-                    NotifiedState::Update(k) => {
-                        unseen.remove(&k);
-                        changes.push(StateChange::Update(k));
+            let hashed: Vec<_> = changes
+                .into_iter()
+                .map(|change| {
+                    let hash = match &change {
+                        StateChange::Delete(k) => {
+                            removed.remove(k).or_else(|| self.rows.get(k).cloned())
+                        }
+                        StateChange::New(k) | StateChange::Update(k) => self.rows.get(k).cloned(),
+                    };
+                    (change, hash)
+                })
+                .collect();
+            self.commit(unseen);
+            hashed.into_iter()
+        }
+
+        fn settle(&mut self, failed: impl IntoIterator<Item = Key>) {
+            for key in failed {
+                match self.drained.remove(&key) {
+                    Some(Some(hash)) => {
+                        self.rows.insert(key, hash);
                     }
-                    NotifiedState::None(k) => {
-                        unseen.remove(&k);
+                    Some(None) => {
+                        self.rows.remove(&key);
                     }
+                    None => {}
                 }
             }
-
-            for rem in unseen {
-                changes.push(StateChange::Delete(rem.to_owned()));
-            }
-
-            changes.into_iter()
+            self.drained.clear();
         }
     }
 }
@@ -269,6 +587,98 @@ mod test_state_change {
         }
     }
 
+    #[test]
+    fn drain_remainder_forgets_deleted_rows() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(2, 32);
+
+        let drain: Vec<_> = ts.drain(true).collect();
+        assert_eq!(vec![StateChange::Delete(1)], drain);
+        assert!(!ts.contains_key(&1));
+
+        // The deleted row is not deleted again, and is new if it comes back.
+        ts.set_row(2, 32);
+        assert_eq!(0, ts.drain(true).count());
+        ts.set_row(1, 31);
+        ts.set_row(2, 32);
+        let drain: Vec<_> = ts.drain_sorted(true).collect();
+        assert_eq!(vec![StateChange::New(1)], drain);
+    }
+
+    #[test]
+    fn get_returns_stored_hash() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 41);
+        ts.set_row(3, 33);
+        ts.remove_row(2);
+
+        assert_eq!(Some(&41), ts.get(&1));
+        assert_eq!(Some(&33), ts.get(&3));
+        assert_eq!(None, ts.get(&2));
+        assert_eq!(None, ts.get(&4));
+        assert!(ts.contains_key(&1));
+        assert!(!ts.contains_key(&2));
+    }
+
+    #[test]
+    fn iter_yields_all_rows() {
+        let mut ts = DefaultTableState::<i32, i32>::default();
+        assert!(ts.is_empty());
+
+        ts.set_row(1, 11);
+        ts.set_row(2, 12);
+        ts.set_row(3, 13);
+
+        let mut rows: Vec<_> = ts.iter().map(|(k, h)| (*k, *h)).collect();
+        rows.sort();
+        assert_eq!(vec![(1, 11), (2, 12), (3, 13)], rows);
+        assert_eq!(3, ts.len());
+        assert!(!ts.is_empty());
+
+        // Iterating does not consume the rows or the pending changes.
+        assert_eq!(3, ts.drain(false).count());
+        ts.remove_row(2);
+        assert_eq!(2, ts.iter().count());
+        assert_eq!(2, ts.len());
+    }
+
+    #[test]
+    fn drain_keeps_set_row_order() {
+        let mut hash = HashMap::new();
+        for key in [1, 2, 3, 4] {
+            hash.insert(key, key * 10);
+        }
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(9, 90);
+        ts.set_row(3, 31);
+        ts.set_row(7, 70);
+        ts.set_row(1, 10);
+        ts.remove_row(2);
+        ts.set_row(9, 91);
+        ts.set_row(5, 50);
+
+        let drain: Vec<_> = ts.drain(true).collect();
+
+        assert_eq!(
+            vec![
+                StateChange::New(9),
+                StateChange::Update(3),
+                StateChange::New(7),
+                StateChange::Delete(2),
+                StateChange::Update(9),
+                StateChange::New(5),
+                StateChange::Delete(4),
+            ],
+            drain
+        );
+    }
+
     #[test]
     fn drain_update() {
         let mut hash = HashMap::new();
@@ -285,6 +695,47 @@ mod test_state_change {
         }
     }
 
+    #[test]
+    fn drain_removed_row() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.remove_row(1);
+        ts.remove_row(3);
+
+        let drain: Vec<_> = ts.drain(false).collect();
+        assert_eq!(vec![StateChange::Delete(1)], drain);
+
+        // The removed row is forgotten, so setting it again is new.
+        ts.set_row(1, 31);
+        let drain: Vec<_> = ts.drain(false).collect();
+        assert_eq!(vec![StateChange::New(1)], drain);
+    }
+
+    #[test]
+    fn drain_hashed_keeps_deleted_hash() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        hash.insert(3, 33);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 41);
+        ts.remove_row(2);
+
+        let mut drain: Vec<_> = ts.drain_hashed(true).collect();
+        drain.sort_by_key(|(_, hash)| *hash);
+
+        assert_eq!(
+            vec![
+                (StateChange::Delete(2), Some(32)),
+                (StateChange::Delete(3), Some(33)),
+                (StateChange::Update(1), Some(41)),
+            ],
+            drain
+        );
+    }
+
     #[test]
     fn drain_set_to_same_value() {
         let mut hash = HashMap::new();
@@ -296,48 +747,1429 @@ mod test_state_change {
 
         assert_eq!(0, drain.len());
     }
-}
 
-pub use state_change::*;
+    #[test]
+    fn preview_matches_drain() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        hash.insert(3, 33);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 31);
+        ts.set_row(2, 90);
+        ts.set_row(4, 34);
 
-mod change {
-    use super::state_change::TableState;
-    use crate::sync::CancellationToken;
+        let preview = ts.preview_changes(true);
+        let drain: Vec<_> = ts.drain(true).collect();
 
-    /// This is the core logic that needs implemented per-application. The change detector resolves
-    /// a change set by mutating `state` via the `rowhash` function.
-    pub trait ChangeDetector {
-        type Key;
-        type Hash;
+        assert_eq!(3, preview.len());
+        assert_eq!(preview, drain);
+    }
 
-        /// Produces a hash of the entire observed set. If the change detector cannot reasonably
-        /// hash the entire set, it should return None.
-        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64>;
+    #[test]
+    fn preview_does_not_consume() {
+        let mut ts = DefaultTableState::<i32, i32>::default();
+        ts.set_row(1, 11);
 
-        /// Produces the change set from state. It does not need to modify `State` as the engine will
-        /// handle updating each row. The returned `Vec` must consist of (rowid, hash, messagebody).
-        /// If `cancel` is triggered during this call, return early with the changes that are known.
-        async fn rowhash(
-            self,
-            state: &mut impl TableState<Self::Key, Self::Hash>,
-            cancel: &CancellationToken,
-        ) -> ChangeDetectorResult;
+        let first = ts.preview_changes(true);
+        let second = ts.preview_changes(true);
+
+        assert_eq!(first, second);
+        assert_eq!(1, ts.drain(true).count());
     }
 
-    pub enum ChangeDetectorResult {
-        /// It canceled early. Save the changes to `state` but do not delete the unidentified rows.
-        Cancelled,
-        /// It finished. Rows not identified in `state` must have been deleted.
-        DeleteRemainder,
-        /// It was abruptly terminated. Do not publish any notifications or change `state`.
-        Aborted,
-        /// A temporal error was encountered. The system expects that the error will not be
-        /// permanent, such that re-attempting the operation in the future is expected to succeed.
-        /// The fault should contain an error code that can be cross-ref'd against the documentation
-        /// and possibly be used as an application exit code if the error occurs too many times in
-        /// a row.
-        Faulted(u8),
+    #[test]
+    fn drain_summary_counts() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        hash.insert(3, 33);
+        hash.insert(4, 34);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 31);
+        ts.set_row(2, 90);
+        ts.set_row(3, 91);
+        ts.set_row(5, 35);
+
+        let (summary, drain) = ts.drain_summary(true);
+        let drain: Vec<_> = drain.collect();
+
+        assert_eq!(
+            ChangeSummary {
+                new: 1,
+                updated: 2,
+                deleted: 1,
+                unchanged: 1,
+            },
+            summary
+        );
+        assert_eq!(4, drain.len());
+        assert_eq!(
+            "1 new, 2 updated, 1 deleted, 1 unchanged",
+            summary.to_string()
+        );
     }
-}
 
-pub use change::*;
+    #[test]
+    fn drain_sorted_deletes_by_key() {
+        let mut hash = HashMap::new();
+        for key in [5, 3, 9, 1, 7, 2, 8] {
+            hash.insert(key, key * 10);
+        }
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(20, 200);
+        ts.set_row(8, 0);
+        ts.set_row(10, 100);
+
+        let drain: Vec<_> = ts.drain_sorted(true).collect();
+
+        assert_eq!(
+            vec![
+                StateChange::New(20),
+                StateChange::Update(8),
+                StateChange::New(10),
+                StateChange::Delete(1),
+                StateChange::Delete(2),
+                StateChange::Delete(3),
+                StateChange::Delete(5),
+                StateChange::Delete(7),
+                StateChange::Delete(9),
+            ],
+            drain
+        );
+    }
+
+    #[test]
+    fn drain_guarded_trips() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        hash.insert(3, 33);
+        hash.insert(4, 34);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 90);
+
+        match ts.drain_guarded(true, 0.5) {
+            Err(e) => assert_eq!(
+                ChangeGuardError {
+                    attempted_deletes: 3,
+                    total: 4,
+                },
+                e
+            ),
+            Ok(_) => panic!("Expected the delete guard to trip."),
+        }
+
+        // The pending changes are kept so the caller can still drain without deletes
+        let drain: Vec<_> = ts.drain(false).collect();
+        assert_eq!(vec![StateChange::Update(1)], drain);
+    }
+
+    #[test]
+    fn settle_restores_failed_rows() {
+        let mut ts = DefaultTableState::new(None, [(1, 10), (2, 20), (3, 30)].into());
+        ts.set_row(1, 11);
+        ts.set_row(3, 30);
+        ts.set_row(4, 40);
+        ts.set_row(5, 50);
+        assert_eq!(4, ts.drain(true).count());
+
+        ts.settle([1, 2, 4]);
+
+        assert_eq!(Some(&10), ts.get(&1));
+        assert_eq!(Some(&20), ts.get(&2));
+        assert!(!ts.contains_key(&4));
+        ts.set_row(1, 11);
+        ts.set_row(3, 30);
+        ts.set_row(4, 40);
+        ts.set_row(5, 50);
+        assert_eq!(
+            vec![
+                StateChange::Update(1),
+                StateChange::New(4),
+                StateChange::Delete(2)
+            ],
+            ts.drain(true).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn settle_restores_every_drain() {
+        let mut ts = DefaultTableState::new(None, [(1, 10), (2, 20)].into());
+        ts.set_row(1, 11);
+        assert_eq!(1, ts.drain(false).count());
+        ts.set_row(2, 21);
+        assert_eq!(1, ts.drain(false).count());
+
+        ts.settle([1, 2]);
+
+        assert_eq!(Some(&10), ts.get(&1));
+        assert_eq!(Some(&20), ts.get(&2));
+    }
+
+    #[test]
+    fn drain_after_clear_is_empty() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        let mut ts = DefaultTableState::new(Some(7), hash);
+        ts.set_row(2, 32);
+
+        ts.clear();
+
+        assert_eq!(None, ts.tablehash());
+        assert_eq!(0, ts.drain(true).count());
+        ts.set_row(1, 31);
+        assert_eq!(
+            vec![StateChange::New(1)],
+            ts.drain(true).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reset_changes_keeps_rows_as_baseline() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 41);
+        ts.set_row(2, 32);
+
+        ts.reset_changes();
+
+        assert_eq!(0, ts.drain(false).count());
+        ts.set_row(1, 41);
+        ts.set_row(2, 32);
+        assert_eq!(0, ts.drain(true).count());
+    }
+
+    #[test]
+    fn drain_guarded_counts_rows_known_before_changes() {
+        let mut ts = DefaultTableState::new(None, [(1, 31), (2, 32), (3, 33), (4, 34)].into());
+        ts.remove_row(1);
+        ts.set_row(2, 32);
+        ts.set_row(5, 35);
+        ts.set_row(6, 36);
+
+        // 3 of the 4 rows known before are deleted, and the new rows do not dilute the ratio.
+        match ts.drain_guarded(true, 0.5) {
+            Err(e) => assert_eq!(
+                ChangeGuardError {
+                    attempted_deletes: 3,
+                    total: 4,
+                },
+                e
+            ),
+            Ok(_) => panic!("Expected the delete guard to trip."),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "the max delete ratio must be between 0 and 1")]
+    fn drain_guarded_rejects_nan_ratio() {
+        let mut ts = DefaultTableState::<i32, i32>::default();
+        _ = ts.drain_guarded(true, f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "the max delete ratio must be between 0 and 1")]
+    fn drain_guarded_rejects_negative_ratio() {
+        let mut ts = DefaultTableState::<i32, i32>::default();
+        _ = ts.drain_guarded(true, -0.5);
+    }
+
+    #[test]
+    fn drain_guarded_below_threshold() {
+        let mut hash = HashMap::new();
+        hash.insert(1, 31);
+        hash.insert(2, 32);
+        hash.insert(3, 33);
+        hash.insert(4, 34);
+        let mut ts = DefaultTableState::new(None, hash);
+        ts.set_row(1, 31);
+        ts.set_row(2, 32);
+        ts.set_row(3, 90);
+
+        let drain: Vec<_> = ts.drain_guarded(true, 0.5).unwrap().collect();
+
+        assert_eq!(2, drain.len());
+        assert!(drain.contains(&StateChange::Update(3)));
+        assert!(drain.contains(&StateChange::Delete(4)));
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test_state_change_serde {
+    use super::state_change::*;
+
+    fn round_trip(change: StateChange<String>, expected: &str) {
+        let json = serde_json::to_string(&change).unwrap();
+        assert_eq!(expected, json);
+
+        let back: StateChange<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(change, back);
+    }
+
+    #[test]
+    fn round_trip_new() {
+        round_trip(
+            StateChange::New("a".to_string()),
+            r#"{"type":"New","key":"a"}"#,
+        );
+    }
+
+    #[test]
+    fn round_trip_update() {
+        round_trip(
+            StateChange::Update("a".to_string()),
+            r#"{"type":"Update","key":"a"}"#,
+        );
+    }
+
+    #[test]
+    fn round_trip_delete() {
+        round_trip(
+            StateChange::Delete("a".to_string()),
+            r#"{"type":"Delete","key":"a"}"#,
+        );
+    }
+}
+
+pub use state_change::*;
+
+mod change {
+    use super::state_change::{StateChange, TableState};
+    use crate::sync::CancellationToken;
+    use futures::{Stream, StreamExt, stream};
+
+    /// This is the core logic that needs implemented per-application. The change detector resolves
+    /// a change set by mutating `state` via the `rowhash` function.
+    pub trait ChangeDetector {
+        type Key;
+        type Hash;
+
+        /// The name of the detector, which is the `detector` of each change's envelope. The
+        /// default is the name of the type without its path or parameters, like
+        /// `SqlTableChangeDetector`.
+        fn name(&self) -> &str {
+            let name = std::any::type_name::<Self>();
+            let name = name.split('<').next().unwrap_or(name);
+            name.rsplit("::").next().unwrap_or(name)
+        }
+
+        /// Produces a hash of the entire observed set. If the change detector cannot reasonably
+        /// hash the entire set, it should return None, which is the default. Detectors that can
+        /// enumerate their row hashes cheaply may use `fold_table_hash` to produce this value.
+        async fn tablehash(&mut self, _cancel: &CancellationToken) -> Option<u64> {
+            None
+        }
+
+        /// Produces the change set from state. It does not need to modify `State` as the engine will
+        /// handle updating each row. The returned `Vec` must consist of (rowid, hash, messagebody).
+        /// If `cancel` is triggered during this call, return early with the changes that are known.
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult;
+
+        /// Produces the change set as a stream so changes can be published as they are
+        /// discovered, ending with how the detector finished. The changes it yields must already
+        /// be drained from `state`. The default implementation runs `rowhash` to completion and
+        /// then streams the drained changes; detectors over very large sets should override it
+        /// to yield changes incrementally. The engine stops polling the stream while its sink
+        /// falls behind, so an override should find rows as it is polled rather than ahead of it.
+        /// Every drain of a run is settled together once the run's changes are published.
+        fn stream_changes<'a, S>(
+            self,
+            state: &'a mut S,
+            cancel: &'a CancellationToken,
+        ) -> impl Stream<Item = DetectorEvent<Self::Key, Self::Hash>> + 'a
+        where
+            Self: Sized + 'a,
+            S: TableState<Self::Key, Self::Hash>,
+        {
+            stream::once(async move {
+                let result = self.rowhash(&mut *state, cancel).await;
+                let changes: Vec<_> = match result.delete_remainder() {
+                    Some(delete_remainder) => state.drain_hashed(delete_remainder).collect(),
+                    None => vec![],
+                };
+                let finished = std::iter::once(DetectorEvent::Finished(result));
+                stream::iter(
+                    changes
+                        .into_iter()
+                        .map(|(change, hash)| DetectorEvent::Change(change, hash))
+                        .chain(finished),
+                )
+            })
+            .flatten()
+        }
+    }
+
+    /// An item of `ChangeDetector::stream_changes`.
+    pub enum DetectorEvent<Key, Hash> {
+        /// A change the detector found, with the row's hash as drained by `drain_hashed`.
+        Change(StateChange<Key>, Option<Hash>),
+        /// How the detector finished. This is the last item of the stream.
+        Finished(ChangeDetectorResult),
+    }
+
+    pub enum ChangeDetectorResult {
+        /// It canceled early. Save the changes to `state` but do not delete the unidentified rows.
+        Cancelled,
+        /// It finished. Rows not identified in `state` must have been deleted.
+        DeleteRemainder,
+        /// It was abruptly terminated. Do not publish any notifications or change `state`.
+        Aborted,
+        /// A temporal error was encountered. The system expects that the error will not be
+        /// permanent, such that re-attempting the operation in the future is expected to succeed.
+        /// The fault carries the underlying error so that it can be logged, and rows identified
+        /// before the error are saved to `state` without deleting the unidentified rows.
+        Faulted(Box<dyn std::error::Error + Send + Sync>),
+    }
+
+    impl ChangeDetectorResult {
+        /// Whether rows not identified in `state` should be deleted when draining, or `None` if
+        /// the state must not be drained at all.
+        pub fn delete_remainder(&self) -> Option<bool> {
+            match self {
+                ChangeDetectorResult::Aborted => None,
+                ChangeDetectorResult::Cancelled => Some(false),
+                ChangeDetectorResult::DeleteRemainder => Some(true),
+                ChangeDetectorResult::Faulted(_) => Some(false),
+            }
+        }
+    }
+
+    /// Combines row hashes into a single table hash. Each `(key, hash)` pair is hashed on its
+    /// own and the results are combined with XOR, so the order of the rows does not matter.
+    pub fn fold_table_hash<Key, Hash>(rows: impl IntoIterator<Item = (Key, Hash)>) -> u64
+    where
+        Key: std::hash::Hash,
+        Hash: std::hash::Hash,
+    {
+        use std::hash::{DefaultHasher, Hasher};
+
+        rows.into_iter().fold(0, |acc, (key, hash)| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hash.hash(&mut hasher);
+            acc ^ hasher.finish()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_change {
+    use super::change::*;
+    use super::state_change::{DefaultTableState, StateChange, TableState};
+    use crate::sync::CancellationToken;
+    use futures::StreamExt;
+
+    struct MockDetector {
+        rows: Vec<(i32, i32)>,
+    }
+
+    impl ChangeDetector for MockDetector {
+        type Key = i32;
+        type Hash = i32;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            for (key, hash) in self.rows {
+                state.set_row(key, hash);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_changes_yields_drained_changes() {
+        let mut state = DefaultTableState::new(None, [(1, 10), (2, 20)].into());
+        let detector = MockDetector {
+            rows: vec![(1, 11), (3, 30)],
+        };
+        let cancel = CancellationToken::new();
+
+        let mut events: Vec<_> = detector.stream_changes(&mut state, &cancel).collect().await;
+
+        assert!(matches!(
+            events.pop(),
+            Some(DetectorEvent::Finished(
+                ChangeDetectorResult::DeleteRemainder
+            ))
+        ));
+        let changes: Vec<_> = events
+            .into_iter()
+            .map(|event| match event {
+                DetectorEvent::Change(change, hash) => (change, hash),
+                DetectorEvent::Finished(_) => panic!("the stream finished more than once"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (StateChange::Update(1), Some(11)),
+                (StateChange::New(3), Some(30)),
+                (StateChange::Delete(2), Some(20)),
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn fold_table_hash_order_independent() {
+        let a = fold_table_hash([("a", 1), ("b", 2), ("c", 3)]);
+        let b = fold_table_hash([("c", 3), ("a", 1), ("b", 2)]);
+
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn fold_table_hash_row_changed() {
+        let a = fold_table_hash([("a", 1), ("b", 2), ("c", 3)]);
+        let b = fold_table_hash([("a", 1), ("b", 4), ("c", 3)]);
+
+        assert_ne!(a, b);
+    }
+}
+
+pub use change::*;
+
+mod composite {
+    use super::change::{ChangeDetector, ChangeDetectorResult, fold_table_hash};
+    use super::dynamic::DynChangeDetector;
+    use super::state_change::{StateChange, TableState};
+    use crate::sync::CancellationToken;
+
+    /// Records each row passed to `set_row` so it can be replayed into another state.
+    pub(super) struct RowRecorder<Key, Hash> {
+        /// The rows in the order they were reported, where `None` is a removed row.
+        pub(super) rows: Vec<(Key, Option<Hash>)>,
+    }
+
+    impl<Key, Hash> RowRecorder<Key, Hash> {
+        /// Replays the recorded rows into `state`, mapping each key with `key`.
+        pub(super) fn replay<K>(
+            self,
+            state: &mut impl TableState<K, Hash>,
+            mut key: impl FnMut(Key) -> K,
+        ) {
+            for (k, hash) in self.rows {
+                match hash {
+                    Some(hash) => state.set_row(key(k), hash),
+                    None => state.remove_row(key(k)),
+                }
+            }
+        }
+    }
+
+    impl<Key, Hash> Default for RowRecorder<Key, Hash> {
+        fn default() -> Self {
+            Self { rows: vec![] }
+        }
+    }
+
+    impl<Key, Hash> TableState<Key, Hash> for RowRecorder<Key, Hash> {
+        fn tablehash(&self) -> Option<u64> {
+            None
+        }
+
+        fn set_row(&mut self, key: Key, hash: Hash) {
+            self.rows.push((key, Some(hash)));
+        }
+
+        fn remove_row(&mut self, key: Key) {
+            self.rows.push((key, None));
+        }
+
+        fn drain(&mut self, _delete_remainder: bool) -> impl Iterator<Item = StateChange<Key>> {
+            std::iter::empty()
+        }
+    }
+
+    /// Runs several change detectors as one. Each row is keyed by the name of the source that
+    /// produced it along with the row's own key, so sources cannot collide.
+    pub struct CompositeChangeDetector<Key, Hash> {
+        children: Vec<(String, Box<dyn DynChangeDetector<Key, Hash>>)>,
+    }
+
+    impl<Key, Hash> CompositeChangeDetector<Key, Hash> {
+        pub fn new() -> Self {
+            Self { children: vec![] }
+        }
+
+        /// Adds a detector whose rows will be tagged with `source`.
+        pub fn with_detector<D>(&mut self, source: impl Into<String>, detector: D) -> &mut Self
+        where
+            D: ChangeDetector<Key = Key, Hash = Hash> + Clone + 'static,
+        {
+            self.children.push((source.into(), Box::new(detector)));
+            self
+        }
+    }
+
+    impl<Key, Hash> Default for CompositeChangeDetector<Key, Hash> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<Key: 'static, Hash: 'static> Clone for CompositeChangeDetector<Key, Hash> {
+        fn clone(&self) -> Self {
+            Self {
+                children: self.children.clone(),
+            }
+        }
+    }
+
+    impl<Key: 'static, Hash: 'static> ChangeDetector for CompositeChangeDetector<Key, Hash> {
+        type Key = (String, Key);
+        type Hash = Hash;
+
+        /// Combines the table hash of every child, or `None` if any child cannot produce one.
+        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+            let mut hashes = vec![];
+            for (source, child) in self.children.iter_mut() {
+                hashes.push((source.as_str(), child.dyn_tablehash(cancel).await?));
+            }
+            Some(fold_table_hash(hashes))
+        }
+
+        /// Runs each child in turn. The rows are only applied to `state` if no child aborted,
+        /// and the most severe child result is returned.
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let mut result = ChangeDetectorResult::DeleteRemainder;
+            let mut recorded = vec![];
+
+            for (source, child) in self.children {
+                let (rows, child_result) = child.dyn_rowhash(cancel).await;
+
+                result = match (result, child_result) {
+                    (ChangeDetectorResult::Aborted, _) | (_, ChangeDetectorResult::Aborted) => {
+                        return ChangeDetectorResult::Aborted;
+                    }
+                    (ChangeDetectorResult::Faulted(e), _)
+                    | (_, ChangeDetectorResult::Faulted(e)) => ChangeDetectorResult::Faulted(e),
+                    (ChangeDetectorResult::Cancelled, _) | (_, ChangeDetectorResult::Cancelled) => {
+                        ChangeDetectorResult::Cancelled
+                    }
+                    _ => ChangeDetectorResult::DeleteRemainder,
+                };
+
+                recorded.push((source, RowRecorder { rows }));
+            }
+
+            for (source, rows) in recorded {
+                rows.replay(state, |key| (source.clone(), key));
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_composite {
+    use super::change::*;
+    use super::composite::*;
+    use super::state_change::{DefaultTableState, StateChange, TableState};
+    use crate::sync::CancellationToken;
+
+    #[derive(Clone)]
+    struct MockDetector {
+        tablehash: Option<u64>,
+        rows: Vec<(i32, i32)>,
+        result: fn() -> ChangeDetectorResult,
+    }
+
+    impl ChangeDetector for MockDetector {
+        type Key = i32;
+        type Hash = i32;
+
+        async fn tablehash(&mut self, _cancel: &CancellationToken) -> Option<u64> {
+            self.tablehash
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            for (key, hash) in self.rows {
+                state.set_row(key, hash);
+            }
+            (self.result)()
+        }
+    }
+
+    fn mock(rows: Vec<(i32, i32)>, result: fn() -> ChangeDetectorResult) -> MockDetector {
+        MockDetector {
+            tablehash: Some(rows.len() as u64),
+            rows,
+            result,
+        }
+    }
+
+    #[tokio::test]
+    async fn rowhash_merges_sources() {
+        let mut composite = CompositeChangeDetector::new();
+        composite
+            .with_detector(
+                "fs",
+                mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+            )
+            .with_detector(
+                "http",
+                mock(vec![(1, 20)], || ChangeDetectorResult::DeleteRemainder),
+            );
+        let mut state = DefaultTableState::default();
+
+        let result = composite
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        assert_eq!(Some(true), result.delete_remainder());
+        let drain: Vec<_> = state.drain_sorted(true).collect();
+        assert_eq!(
+            vec![
+                StateChange::New(("fs".to_string(), 1)),
+                StateChange::New(("http".to_string(), 1)),
+            ],
+            drain
+        );
+    }
+
+    #[tokio::test]
+    async fn rowhash_cancelled_child_prevents_deletes() {
+        let mut composite = CompositeChangeDetector::new();
+        composite
+            .with_detector(
+                "fs",
+                mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+            )
+            .with_detector("http", mock(vec![], || ChangeDetectorResult::Cancelled));
+        let mut state = DefaultTableState::default();
+
+        let result = composite
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        assert_eq!(Some(false), result.delete_remainder());
+    }
+
+    #[tokio::test]
+    async fn rowhash_aborted_child_leaves_state() {
+        let mut composite = CompositeChangeDetector::new();
+        composite
+            .with_detector(
+                "fs",
+                mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+            )
+            .with_detector(
+                "http",
+                mock(vec![(2, 20)], || ChangeDetectorResult::Aborted),
+            );
+        let mut state = DefaultTableState::default();
+
+        let result = composite
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        assert_eq!(None, result.delete_remainder());
+        assert_eq!(0, state.drain(true).count());
+    }
+
+    #[tokio::test]
+    async fn tablehash_combines_children() {
+        let cancel = CancellationToken::new();
+        let mut a = CompositeChangeDetector::new();
+        a.with_detector(
+            "fs",
+            mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+        )
+        .with_detector(
+            "http",
+            mock(vec![], || ChangeDetectorResult::DeleteRemainder),
+        );
+        let mut b = CompositeChangeDetector::new();
+        b.with_detector(
+            "fs",
+            mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+        )
+        .with_detector(
+            "http",
+            mock(vec![(2, 20)], || ChangeDetectorResult::DeleteRemainder),
+        );
+
+        let a = a.tablehash(&cancel).await;
+        let b = b.tablehash(&cancel).await;
+
+        assert!(a.is_some());
+        assert!(b.is_some());
+        assert_ne!(a, b);
+    }
+
+    #[tokio::test]
+    async fn tablehash_none_if_any_child_none() {
+        let mut unknown = mock(vec![], || ChangeDetectorResult::DeleteRemainder);
+        unknown.tablehash = None;
+        let mut composite = CompositeChangeDetector::new();
+        composite
+            .with_detector(
+                "fs",
+                mock(vec![(1, 10)], || ChangeDetectorResult::DeleteRemainder),
+            )
+            .with_detector("http", unknown);
+
+        assert_eq!(None, composite.tablehash(&CancellationToken::new()).await);
+    }
+}
+
+pub use composite::*;
+
+mod retry {
+    use super::change::{ChangeDetector, ChangeDetectorResult};
+    use super::composite::RowRecorder;
+    use super::state_change::TableState;
+    use crate::sync::CancellationToken;
+    use std::time::Duration;
+
+    /// Retries a detector whose `rowhash` reports `Faulted`, waiting with exponential backoff
+    /// between attempts. Rows from a failed attempt are discarded unless it was the last one.
+    ///
+    /// `tablehash` is not retried because `None` also means the detector cannot produce one.
+    #[derive(Clone)]
+    pub struct RetryingDetector<D> {
+        inner: D,
+        max_retries: usize,
+        backoff: Duration,
+    }
+
+    impl<D> RetryingDetector<D>
+    where
+        D: Clone,
+    {
+        pub fn new(inner: D) -> Self {
+            Self {
+                inner,
+                max_retries: 3,
+                backoff: Duration::from_secs(1),
+            }
+        }
+
+        /// The number of attempts made after the first one faults.
+        pub fn with_max_retries(&mut self, max_retries: usize) -> &mut Self {
+            self.max_retries = max_retries;
+            self
+        }
+
+        /// The wait before the first retry. Each subsequent retry waits twice as long.
+        pub fn with_backoff(&mut self, backoff: Duration) -> &mut Self {
+            self.backoff = backoff;
+            self
+        }
+
+        pub fn build(&self) -> Self {
+            self.clone()
+        }
+    }
+
+    impl<D> ChangeDetector for RetryingDetector<D>
+    where
+        D: ChangeDetector + Clone,
+    {
+        type Key = D::Key;
+        type Hash = D::Hash;
+
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+            self.inner.tablehash(cancel).await
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let mut backoff = self.backoff;
+            let mut attempt = 0;
+
+            loop {
+                let mut rows = RowRecorder::default();
+                let result = self.inner.clone().rowhash(&mut rows, cancel).await;
+
+                let retry = matches!(result, ChangeDetectorResult::Faulted(_))
+                    && attempt < self.max_retries
+                    && cancel
+                        .run_until_cancelled(tokio::time::sleep(backoff))
+                        .await
+                        .is_some();
+
+                if !retry {
+                    if result.delete_remainder().is_some() {
+                        rows.replay(state, |key| key);
+                    }
+                    return result;
+                }
+
+                attempt += 1;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_retry {
+    use super::change::*;
+    use super::retry::*;
+    use super::state_change::{DefaultTableState, StateChange, TableState};
+    use crate::sync::CancellationToken;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+    use std::time::Duration;
+
+    /// Faults (after setting a row) until it has been called `failures` times.
+    #[derive(Clone)]
+    struct FlakyDetector {
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+    }
+
+    impl ChangeDetector for FlakyDetector {
+        type Key = i32;
+        type Hash = i32;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            if call < self.failures {
+                state.set_row(-1, call as i32);
+                ChangeDetectorResult::Faulted("source unavailable".into())
+            } else {
+                state.set_row(1, 10);
+                ChangeDetectorResult::DeleteRemainder
+            }
+        }
+    }
+
+    fn flaky(failures: usize) -> (RetryingDetector<FlakyDetector>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut retry = RetryingDetector::new(FlakyDetector {
+            calls: calls.clone(),
+            failures,
+        });
+        retry
+            .with_max_retries(3)
+            .with_backoff(Duration::from_millis(1));
+        (retry, calls)
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let (retry, calls) = flaky(2);
+        let mut state = DefaultTableState::default();
+
+        let result = retry.rowhash(&mut state, &CancellationToken::new()).await;
+
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+        assert_eq!(Some(true), result.delete_remainder());
+        // Rows from the failed attempts are discarded
+        let drain: Vec<_> = state.drain(true).collect();
+        assert_eq!(vec![StateChange::New(1)], drain);
+    }
+
+    #[tokio::test]
+    async fn faults_after_max_retries() {
+        let (retry, calls) = flaky(10);
+        let mut state = DefaultTableState::default();
+
+        let result = retry.rowhash(&mut state, &CancellationToken::new()).await;
+
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+        match result {
+            ChangeDetectorResult::Faulted(_) => {}
+            _ => panic!("Expected the detector to fault."),
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_retrying() {
+        let (retry, calls) = flaky(10);
+        let mut state = DefaultTableState::default();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        retry.rowhash(&mut state, &cancel).await;
+
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
+
+pub use retry::*;
+
+mod timeout {
+    use super::change::{ChangeDetector, ChangeDetectorResult};
+    use super::state_change::TableState;
+    use crate::sync::CancellationToken;
+    use std::{pin::pin, time::Duration};
+    use tokio::{
+        select,
+        time::{sleep, timeout},
+    };
+
+    /// Bounds how long a detector may run. When the deadline elapses, the token given to the
+    /// inner detector is cancelled and the inner detector is given the grace period to stop
+    /// cleanly, after which it is dropped. `rowhash` then reports `Cancelled`, so rows found so
+    /// far are kept but nothing is deleted.
+    #[derive(Clone)]
+    pub struct TimeoutDetector<D> {
+        inner: D,
+        timeout: Duration,
+        grace_period: Duration,
+    }
+
+    impl<D> TimeoutDetector<D>
+    where
+        D: Clone,
+    {
+        pub fn new(inner: D) -> Self {
+            Self {
+                inner,
+                timeout: Duration::from_secs(60),
+                grace_period: Duration::from_secs(5),
+            }
+        }
+
+        pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// How long the inner detector may take to stop once it is cancelled, such as a source
+        /// that stalls without checking its token.
+        pub fn with_grace_period(&mut self, grace_period: Duration) -> &mut Self {
+            self.grace_period = grace_period;
+            self
+        }
+
+        pub fn build(&self) -> Self {
+            self.clone()
+        }
+    }
+
+    impl<D> ChangeDetector for TimeoutDetector<D>
+    where
+        D: ChangeDetector,
+    {
+        type Key = D::Key;
+        type Hash = D::Hash;
+
+        fn name(&self) -> &str {
+            self.inner.name()
+        }
+
+        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+            let (deadline, grace_period) = (self.timeout, self.grace_period);
+            let token = cancel.child_token();
+            let mut tablehash = pin!(self.inner.tablehash(&token));
+
+            select! {
+                hash = &mut tablehash => hash,
+                _ = sleep(deadline) => {
+                    warn!("The table hash timed out after {:?}.", deadline);
+                    token.cancel();
+                    // A hash of part of the table is not a hash of the table.
+                    _ = timeout(grace_period, tablehash).await;
+                    None
+                }
+            }
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let token = cancel.child_token();
+            let mut rowhash = pin!(self.inner.rowhash(state, &token));
+
+            select! {
+                result = &mut rowhash => result,
+                _ = sleep(self.timeout) => {
+                    warn!("The row hash timed out after {:?}.", self.timeout);
+                    token.cancel();
+                    if timeout(self.grace_period, rowhash).await.is_err() {
+                        warn!("The row hash did not stop after it was cancelled and was dropped.");
+                    }
+                    ChangeDetectorResult::Cancelled
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_timeout {
+    use super::change::*;
+    use super::state_change::{DefaultTableState, StateChange, TableState};
+    use super::timeout::*;
+    use crate::sync::CancellationToken;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+    use std::time::Duration;
+    use tokio::{
+        select,
+        time::{Instant, sleep},
+    };
+
+    /// Sets one row and then sleeps for `delay`, stopping early if it is cancelled.
+    #[derive(Clone)]
+    struct SlowDetector {
+        delay: Duration,
+        cancelled: Arc<AtomicBool>,
+    }
+
+    impl ChangeDetector for SlowDetector {
+        type Key = i32;
+        type Hash = i32;
+
+        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+            select! {
+                _ = sleep(self.delay) => Some(1),
+                _ = cancel.cancelled() => {
+                    self.cancelled.store(true, Ordering::SeqCst);
+                    // A partial hash, which the timeout must not report
+                    Some(2)
+                }
+            }
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            state.set_row(1, 10);
+            select! {
+                _ = sleep(self.delay) => ChangeDetectorResult::DeleteRemainder,
+                _ = cancel.cancelled() => {
+                    self.cancelled.store(true, Ordering::SeqCst);
+                    ChangeDetectorResult::Faulted("Cancelled by the timeout.".into())
+                }
+            }
+        }
+    }
+
+    fn slow(delay: Duration) -> (TimeoutDetector<SlowDetector>, Arc<AtomicBool>) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let mut detector = TimeoutDetector::new(SlowDetector {
+            delay,
+            cancelled: cancelled.clone(),
+        });
+        detector.with_timeout(Duration::from_millis(20));
+        (detector, cancelled)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rowhash_past_deadline_cancels_inner() {
+        let mut state = DefaultTableState::default();
+        let (detector, cancelled) = slow(Duration::from_secs(10));
+
+        let result = detector
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        // The inner detector saw its token cancelled, and its own result is not reported
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert!(matches!(result, ChangeDetectorResult::Cancelled));
+        // The row found before the deadline is kept
+        let drain: Vec<_> = state.drain(false).collect();
+        assert_eq!(vec![StateChange::New(1)], drain);
+    }
+
+    /// Sets one row and then never finishes, ignoring its token.
+    #[derive(Clone)]
+    struct StuckDetector;
+
+    impl ChangeDetector for StuckDetector {
+        type Key = i32;
+        type Hash = i32;
+
+        async fn tablehash(&mut self, _cancel: &CancellationToken) -> Option<u64> {
+            std::future::pending().await
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            state.set_row(1, 10);
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stuck_inner_cancelled_after_grace_period() {
+        let mut state = DefaultTableState::default();
+        let mut detector = TimeoutDetector::new(StuckDetector);
+        detector
+            .with_timeout(Duration::from_millis(20))
+            .with_grace_period(Duration::from_millis(30));
+        let started = Instant::now();
+
+        let result = detector
+            .build()
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        assert!(matches!(result, ChangeDetectorResult::Cancelled));
+        assert_eq!(Duration::from_millis(50), started.elapsed());
+        let drain: Vec<_> = state.drain(false).collect();
+        assert_eq!(vec![StateChange::New(1)], drain);
+
+        let started = Instant::now();
+        assert_eq!(None, detector.tablehash(&CancellationToken::new()).await);
+        assert_eq!(Duration::from_millis(50), started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn rowhash_within_deadline() {
+        let mut state = DefaultTableState::default();
+
+        let (detector, cancelled) = slow(Duration::ZERO);
+
+        let result = detector
+            .rowhash(&mut state, &CancellationToken::new())
+            .await;
+
+        assert_eq!(Some(true), result.delete_remainder());
+        assert!(!cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tablehash_past_deadline_none() {
+        let cancel = CancellationToken::new();
+
+        let (mut detector, cancelled) = slow(Duration::from_secs(10));
+        assert_eq!(None, detector.tablehash(&cancel).await);
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        let (mut detector, _) = slow(Duration::ZERO);
+        assert_eq!(Some(1), detector.tablehash(&cancel).await);
+    }
+}
+
+pub use timeout::*;
+
+mod dynamic {
+    use super::change::{ChangeDetector, ChangeDetectorResult};
+    use super::composite::RowRecorder;
+    use super::state_change::TableState;
+    use crate::sync::CancellationToken;
+    use std::{future::Future, pin::Pin};
+
+    /// An object-safe form of `ChangeDetector`, so a detector can be chosen at runtime and
+    /// stored as a `Box<dyn DynChangeDetector<Key, Hash>>`. Every `ChangeDetector` that is
+    /// `Clone` implements it, and the box is itself a `ChangeDetector` that can be given to the
+    /// engine.
+    pub trait DynChangeDetector<Key, Hash> {
+        /// See `ChangeDetector::tablehash`.
+        fn dyn_tablehash<'a>(
+            &'a mut self,
+            cancel: &'a CancellationToken,
+        ) -> Pin<Box<dyn Future<Output = Option<u64>> + 'a>>;
+
+        /// Runs `ChangeDetector::rowhash`, returning the rows it reported in order along with
+        /// its result. A row without a hash was removed.
+        #[allow(clippy::type_complexity)]
+        fn dyn_rowhash<'a>(
+            self: Box<Self>,
+            cancel: &'a CancellationToken,
+        ) -> Pin<Box<dyn Future<Output = (Vec<(Key, Option<Hash>)>, ChangeDetectorResult)> + 'a>>;
+
+        fn dyn_clone(&self) -> Box<dyn DynChangeDetector<Key, Hash>>;
+
+        /// See `ChangeDetector::name`.
+        fn dyn_name(&self) -> &str;
+    }
+
+    impl<D> DynChangeDetector<D::Key, D::Hash> for D
+    where
+        D: ChangeDetector + Clone + 'static,
+    {
+        fn dyn_tablehash<'a>(
+            &'a mut self,
+            cancel: &'a CancellationToken,
+        ) -> Pin<Box<dyn Future<Output = Option<u64>> + 'a>> {
+            Box::pin(ChangeDetector::tablehash(self, cancel))
+        }
+
+        fn dyn_rowhash<'a>(
+            self: Box<Self>,
+            cancel: &'a CancellationToken,
+        ) -> Pin<
+            Box<dyn Future<Output = (Vec<(D::Key, Option<D::Hash>)>, ChangeDetectorResult)> + 'a>,
+        > {
+            Box::pin(async move {
+                let mut rows = RowRecorder::default();
+                let result = ChangeDetector::rowhash(*self, &mut rows, cancel).await;
+                (rows.rows, result)
+            })
+        }
+
+        fn dyn_clone(&self) -> Box<dyn DynChangeDetector<D::Key, D::Hash>> {
+            Box::new(self.clone())
+        }
+
+        fn dyn_name(&self) -> &str {
+            ChangeDetector::name(self)
+        }
+    }
+
+    impl<Key: 'static, Hash: 'static> Clone for Box<dyn DynChangeDetector<Key, Hash>> {
+        fn clone(&self) -> Self {
+            // The box is itself a `DynChangeDetector`, so this must call through to its contents.
+            (**self).dyn_clone()
+        }
+    }
+
+    impl<Key: 'static, Hash: 'static> ChangeDetector for Box<dyn DynChangeDetector<Key, Hash>> {
+        type Key = Key;
+        type Hash = Hash;
+
+        fn name(&self) -> &str {
+            (**self).dyn_name()
+        }
+
+        async fn tablehash(&mut self, cancel: &CancellationToken) -> Option<u64> {
+            (**self).dyn_tablehash(cancel).await
+        }
+
+        /// Applies the rows the detector reported to `state` once it finishes, so a detector
+        /// that is cancelled or faults still records the rows it found.
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            let (rows, result) = self.dyn_rowhash(cancel).await;
+            RowRecorder { rows }.replay(state, |key| key);
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_dynamic {
+    use super::change::*;
+    use super::dynamic::*;
+    use super::state_change::{DefaultTableState, StateChange, TableState};
+    use crate::sync::CancellationToken;
+
+    /// Reports the same rows every run.
+    #[derive(Clone)]
+    struct FixedDetector(Vec<(String, u64)>);
+
+    impl ChangeDetector for FixedDetector {
+        type Key = String;
+        type Hash = u64;
+
+        async fn tablehash(&mut self, _cancel: &CancellationToken) -> Option<u64> {
+            Some(fold_table_hash(self.0.clone()))
+        }
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            for (key, hash) in self.0 {
+                state.set_row(key, hash);
+            }
+            ChangeDetectorResult::DeleteRemainder
+        }
+    }
+
+    /// Removes a single row, then stops as if it were cancelled.
+    #[derive(Clone)]
+    struct RemovingDetector(&'static str);
+
+    impl ChangeDetector for RemovingDetector {
+        type Key = String;
+        type Hash = u64;
+
+        async fn rowhash(
+            self,
+            state: &mut impl TableState<Self::Key, Self::Hash>,
+            _cancel: &CancellationToken,
+        ) -> ChangeDetectorResult {
+            state.remove_row(self.0.to_string());
+            ChangeDetectorResult::Cancelled
+        }
+    }
+
+    /// Chooses a detector by name, as an app would from its configuration.
+    fn detector(name: &str) -> Box<dyn DynChangeDetector<String, u64>> {
+        match name {
+            "fixed" => Box::new(FixedDetector(vec![
+                ("a".to_string(), 1),
+                ("b".to_string(), 2),
+            ])),
+            _ => Box::new(RemovingDetector("a")),
+        }
+    }
+
+    #[tokio::test]
+    async fn boxed_detectors_are_driven_alike() {
+        let cancel = CancellationToken::new();
+        let mut detectors = vec![detector("fixed"), detector("removing")];
+        assert!(detectors[0].tablehash(&cancel).await.is_some());
+        assert_eq!(None, detectors[1].tablehash(&cancel).await);
+        assert_eq!("FixedDetector", detectors[0].name());
+        assert_eq!("RemovingDetector", detectors[1].name());
+
+        let mut state = DefaultTableState::default();
+        let mut drains = vec![];
+        for detector in detectors.clone() {
+            let result = detector.rowhash(&mut state, &cancel).await;
+            let delete_remainder = result.delete_remainder().unwrap();
+            drains.push(state.drain_sorted(delete_remainder).collect::<Vec<_>>());
+        }
+        assert_eq!(
+            vec![
+                vec![
+                    StateChange::New("a".to_string()),
+                    StateChange::New("b".to_string())
+                ],
+                vec![StateChange::Delete("a".to_string())],
+            ],
+            drains
+        );
+
+        // The boxes were cloned, so they can be run again.
+        let result = detectors.remove(0).rowhash(&mut state, &cancel).await;
+        assert_eq!(Some(true), result.delete_remainder());
+        let drain: Vec<_> = state.drain_sorted(true).collect();
+        assert_eq!(vec![StateChange::New("a".to_string())], drain);
+    }
+}
+
+pub use dynamic::*;
@@ -1,2 +1,79 @@
 // This will have cooperative cancellation logic
+use std::time::Duration;
 pub use tokio_util::sync::CancellationToken;
+
+/// How a future raced by `run_until_cancelled_or_timeout` ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RaceOutcome<T> {
+    /// The future finished with its output.
+    Completed(T),
+    /// The token was cancelled before the future finished.
+    Cancelled,
+    /// The duration elapsed before the future finished.
+    TimedOut,
+}
+
+/// Runs `future` until it finishes, `token` is cancelled, or `duration` elapses, whichever
+/// comes first. The future is dropped if it does not finish. Cancellation wins over a future
+/// that finishes at the same time.
+pub async fn run_until_cancelled_or_timeout<F: Future>(
+    token: &CancellationToken,
+    duration: Duration,
+    future: F,
+) -> RaceOutcome<F::Output> {
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => RaceOutcome::Cancelled,
+        output = future => RaceOutcome::Completed(output),
+        _ = tokio::time::sleep(duration) => RaceOutcome::TimedOut,
+    }
+}
+
+#[cfg(test)]
+mod test_sync {
+    use super::{CancellationToken, RaceOutcome, run_until_cancelled_or_timeout};
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    #[tokio::test(start_paused = true)]
+    async fn completes_before_deadline() {
+        let token = CancellationToken::new();
+        let outcome = run_until_cancelled_or_timeout(&token, Duration::from_secs(1), async {
+            sleep(Duration::from_millis(10)).await;
+            7
+        })
+        .await;
+        assert_eq!(RaceOutcome::Completed(7), outcome);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_before_deadline() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(10)).await;
+            canceller.cancel();
+        });
+
+        let outcome = run_until_cancelled_or_timeout(
+            &token,
+            Duration::from_secs(1),
+            sleep(Duration::from_secs(60)),
+        )
+        .await;
+        assert_eq!(RaceOutcome::Cancelled, outcome);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out() {
+        let token = CancellationToken::new();
+        let outcome = run_until_cancelled_or_timeout(
+            &token,
+            Duration::from_millis(10),
+            sleep(Duration::from_secs(60)),
+        )
+        .await;
+        assert_eq!(RaceOutcome::TimedOut, outcome);
+        assert!(!token.is_cancelled());
+    }
+}
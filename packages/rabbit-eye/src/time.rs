@@ -1,19 +1,171 @@
-#[derive(Clone, Copy, Debug)]
+use crate::engine::RenewableWorker;
+use rand::Rng;
+use std::{error::Error, fmt::Display, pin::pin, time::Duration};
+use tokio::{
+    select,
+    task::JoinError,
+    time::{Instant, Interval, interval, interval_at, sleep_until},
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Debug)]
 pub struct ScheduleOptions {
-    interval: std::time::Duration,
+    kind: ScheduleKind,
     overlap_behavior: ScheduleOverlap,
+    jitter: std::time::Duration,
+    mode: ScheduleMode,
+    run_immediately: bool,
+}
+
+/// When scheduled work runs.
+#[derive(Clone, Debug)]
+pub enum ScheduleKind {
+    /// Once per interval.
+    Interval(Duration),
+    /// At each time matched by a cron expression, in the local time zone. Times skipped by a
+    /// daylight saving change do not run. See the `cron` crate for the expression syntax.
+    #[cfg(feature = "cron")]
+    Cron(Box<cron::Schedule>),
 }
 
 impl ScheduleOptions {
+    pub fn builder() -> ScheduleOptionsBuilder {
+        ScheduleOptionsBuilder::default()
+    }
+
     pub fn new(interval: std::time::Duration, overlap_behavior: ScheduleOverlap) -> Self {
         Self {
-            interval,
+            kind: ScheduleKind::Interval(interval),
             overlap_behavior,
+            jitter: std::time::Duration::ZERO,
+            mode: ScheduleMode::default(),
+            run_immediately: true,
         }
     }
 
+    /// Runs at each time matched by `expression`, such as `0 0 9 * * Mon-Fri` for 09:00 on
+    /// weekdays. The expression starts with a seconds field.
+    #[cfg(feature = "cron")]
+    pub fn cron(
+        expression: &str,
+        overlap_behavior: ScheduleOverlap,
+    ) -> Result<Self, cron::error::Error> {
+        Ok(Self {
+            kind: ScheduleKind::Cron(Box::new(expression.parse()?)),
+            overlap_behavior,
+            jitter: std::time::Duration::ZERO,
+            mode: ScheduleMode::default(),
+            run_immediately: true,
+        })
+    }
+
+    pub fn with_overlap(&mut self, overlap_behavior: ScheduleOverlap) -> &mut Self {
+        self.overlap_behavior = overlap_behavior;
+        self
+    }
+
+    /// Delays each tick by a random offset in `[0, jitter)` from its place on the interval, so
+    /// instances started together do not all poll at once. The offset does not carry over to
+    /// later ticks. It should be less than the interval.
+    pub fn with_jitter(&mut self, jitter: std::time::Duration) -> &mut Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Whether the interval is measured from the start of each run or the end of it.
+    pub fn with_mode(&mut self, mode: ScheduleMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> ScheduleMode {
+        self.mode
+    }
+
+    /// Whether the first run starts as soon as the schedule starts, rather than after the
+    /// first interval or at the first time a cron schedule matches. This is the default.
+    pub fn with_run_immediately(&mut self, run_immediately: bool) -> &mut Self {
+        self.run_immediately = run_immediately;
+        self
+    }
+
+    pub fn run_immediately(&self) -> bool {
+        self.run_immediately
+    }
+
+    /// Fails if the interval is zero, which would run the work back to back, or if the overlap
+    /// behavior has a `max` of zero.
+    pub fn validate(&self) -> Result<(), ScheduleOptionsError> {
+        if let ScheduleKind::Interval(interval) = self.kind
+            && interval.is_zero()
+        {
+            return Err(ScheduleOptionsError::ZeroInterval);
+        }
+        match self.overlap_behavior {
+            ScheduleOverlap::SkipNew { max: 0 } | ScheduleOverlap::Overlap { max: 0 } => {
+                Err(ScheduleOptionsError::ZeroOverlapMax(self.overlap_behavior))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn kind(&self) -> &ScheduleKind {
+        &self.kind
+    }
+
+    /// The time between runs. For a cron schedule, this is the time between its next two runs,
+    /// or zero if it will not run twice more.
     pub fn interval(&self) -> std::time::Duration {
-        self.interval
+        match &self.kind {
+            ScheduleKind::Interval(interval) => *interval,
+            #[cfg(feature = "cron")]
+            ScheduleKind::Cron(schedule) => {
+                let mut upcoming = schedule.upcoming(chrono::Local);
+                match (upcoming.next(), upcoming.next()) {
+                    (Some(next), Some(after)) => (after - next).to_std().unwrap_or_default(),
+                    _ => Duration::ZERO,
+                }
+            }
+        }
+    }
+
+    /// The next time a cron schedule runs after `now`, or `None` for an interval schedule or a
+    /// cron schedule that will not run again. The time is computed from `now`, so runs missed
+    /// while the work was busy are skipped rather than run late.
+    #[cfg(feature = "cron")]
+    pub fn next_run_after<Tz: chrono::TimeZone>(
+        &self,
+        now: &chrono::DateTime<Tz>,
+    ) -> Option<chrono::DateTime<Tz>> {
+        match &self.kind {
+            ScheduleKind::Interval(_) => None,
+            ScheduleKind::Cron(schedule) => schedule.after(now).next(),
+        }
+    }
+
+    /// Starts the ticks of this schedule.
+    pub(crate) fn ticker(&self) -> Ticker {
+        match &self.kind {
+            ScheduleKind::Interval(period) if self.run_immediately => {
+                Ticker::Interval(interval(*period))
+            }
+            ScheduleKind::Interval(period) => {
+                Ticker::Interval(interval_at(Instant::now() + *period, *period))
+            }
+            #[cfg(feature = "cron")]
+            ScheduleKind::Cron(_) => Ticker::Cron {
+                schedule: self.clone(),
+                immediate: self.run_immediately,
+            },
+        }
+    }
+
+    pub fn overlap_behavior(&self) -> ScheduleOverlap {
+        self.overlap_behavior
+    }
+
+    pub fn jitter(&self) -> std::time::Duration {
+        self.jitter
     }
 }
 
@@ -25,6 +177,137 @@ impl Default for ScheduleOptions {
     }
 }
 
+/// Builds a fixed interval `ScheduleOptions`, validated when it is built.
+#[derive(Clone, Debug, Default)]
+pub struct ScheduleOptionsBuilder {
+    interval: Option<Duration>,
+    overlap: ScheduleOverlap,
+    jitter: Duration,
+    mode: ScheduleMode,
+}
+
+impl ScheduleOptionsBuilder {
+    /// The time between runs, which is 5 seconds unless set.
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn overlap(&mut self, overlap: ScheduleOverlap) -> &mut Self {
+        self.overlap = overlap;
+        self
+    }
+
+    /// See `ScheduleOptions::with_jitter`.
+    pub fn jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn mode(&mut self, mode: ScheduleMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Fails if the options are invalid. See `ScheduleOptions::validate`.
+    pub fn build(&self) -> Result<ScheduleOptions, ScheduleOptionsError> {
+        let mut options = ScheduleOptions::default();
+        if let Some(interval) = self.interval {
+            options.kind = ScheduleKind::Interval(interval);
+        }
+        options
+            .with_overlap(self.overlap)
+            .with_jitter(self.jitter)
+            .with_mode(self.mode)
+            .validate()?;
+        Ok(options)
+    }
+}
+
+/// Why `ScheduleOptions` are invalid.
+#[derive(Clone, Copy, Debug)]
+pub enum ScheduleOptionsError {
+    /// The interval was zero.
+    ZeroInterval,
+    /// The overlap behavior allows at most zero skipped or overlapping runs.
+    ZeroOverlapMax(ScheduleOverlap),
+}
+
+impl Display for ScheduleOptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroInterval => write!(f, "the schedule interval must be greater than zero"),
+            Self::ZeroOverlapMax(overlap) => {
+                write!(f, "the max of {:?} must be greater than zero", overlap)
+            }
+        }
+    }
+}
+
+impl Error for ScheduleOptionsError {}
+
+/// How the time until the next run is measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ScheduleMode {
+    /// Runs start an interval apart, however long each run takes. A run that starts late is
+    /// followed by the next as soon as it is due, to catch up.
+    #[default]
+    FixedRate,
+    /// The next run starts an interval after the previous run finishes, so runs never overlap.
+    /// Cron schedules run at the first matching time after the previous run finishes.
+    FixedDelay,
+}
+
+/// The ticks of a `ScheduleOptions`.
+pub(crate) enum Ticker {
+    Interval(Interval),
+    #[cfg(feature = "cron")]
+    Cron {
+        schedule: ScheduleOptions,
+        /// Whether the next tick is due now, before the schedule's first match.
+        immediate: bool,
+    },
+}
+
+impl Ticker {
+    /// Waits for the next tick, returning when it was due.
+    pub(crate) async fn tick(&mut self) -> Instant {
+        match self {
+            Self::Interval(interval) => interval.tick().await,
+            #[cfg(feature = "cron")]
+            Self::Cron {
+                immediate: immediate @ true,
+                ..
+            } => {
+                *immediate = false;
+                Instant::now()
+            }
+            #[cfg(feature = "cron")]
+            Self::Cron { schedule, .. } => {
+                let now = chrono::Local::now();
+                let Some(next) = schedule.next_run_after(&now) else {
+                    warn!("The cron schedule will not run again.");
+                    return std::future::pending().await;
+                };
+                let tick = Instant::now() + (next - now).to_std().unwrap_or_default();
+                sleep_until(tick).await;
+                tick
+            }
+        }
+    }
+
+    /// Starts the interval over from now. A cron schedule is not affected.
+    pub(crate) fn reset(&mut self) {
+        match self {
+            Self::Interval(interval) => interval.reset(),
+            #[cfg(feature = "cron")]
+            Self::Cron { .. } => {}
+        }
+    }
+}
+
 /// Describes how to handle a schedule when work is still ongoing from a previous interval.
 #[derive(Clone, Copy, Debug)]
 pub enum ScheduleOverlap {
@@ -50,3 +333,437 @@ impl Default for ScheduleOverlap {
         ScheduleOverlap::AbortPrevious
     }
 }
+
+/// Runs work once per interval of a `ScheduleOptions`, replacing or skipping work that is still
+/// running according to its `ScheduleOverlap`. The work runs on the current `LocalSet`.
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    options: ScheduleOptions,
+    grace_period: Duration,
+    stop_timeout: Option<Duration>,
+    max_runs: Option<usize>,
+}
+
+impl Scheduler {
+    pub fn new(options: ScheduleOptions) -> Self {
+        Self {
+            options,
+            grace_period: Duration::from_secs(5),
+            stop_timeout: None,
+            max_runs: None,
+        }
+    }
+
+    /// How long work is given to stop after its token is cancelled before it is aborted.
+    pub fn with_grace_period(&mut self, grace_period: Duration) -> &mut Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// How long work still running when the scheduler stops is given to finish after it is
+    /// cancelled, before it is aborted. This is the grace period unless set.
+    pub fn with_stop_timeout(&mut self, stop_timeout: Duration) -> &mut Self {
+        self.stop_timeout = Some(stop_timeout);
+        self
+    }
+
+    /// Stops the scheduler once the work has been started `max_runs` times. Skipped runs do not
+    /// count.
+    pub fn with_max_runs(&mut self, max_runs: usize) -> &mut Self {
+        self.max_runs = Some(max_runs);
+        self
+    }
+
+    pub fn build(&self) -> Self {
+        self.clone()
+    }
+
+    pub fn options(&self) -> &ScheduleOptions {
+        &self.options
+    }
+
+    pub fn stop_timeout(&self) -> Duration {
+        self.stop_timeout.unwrap_or(self.grace_period)
+    }
+
+    /// Starts `work` at each interval until `stop` is cancelled. Each run is given a token that
+    /// is cancelled when the run is replaced or the scheduler stops. Work still running when
+    /// the scheduler stops is cancelled, and aborted after the stop timeout. If the scheduler
+    /// stops at its max runs instead, the work is waited for until `stop` is cancelled.
+    pub async fn run<F>(&self, stop: CancellationToken, work: impl FnMut(CancellationToken) -> F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        self.run_with(rand::rng(), &mut LogPanics, stop.clone(), stop, work)
+            .await
+    }
+
+    /// Starts `work` at each interval until `stop_loop` is cancelled or the max runs have
+    /// started. Each run's token is a child of `stop_work`.
+    ///
+    /// Once the loop stops, the work still running is waited for until `stop_work` is
+    /// cancelled. Then the work is cancelled, and aborted if it does not finish within the stop
+    /// timeout.
+    ///
+    /// Each tick is delayed by a random offset within the schedule's jitter, drawn from `rng`.
+    /// While `hooks` asks for a backoff, the ticks are spaced by it instead of the interval.
+    pub(crate) async fn run_with<F, T>(
+        &self,
+        mut rng: impl Rng,
+        hooks: &mut impl ScheduleHooks<T>,
+        stop_loop: CancellationToken,
+        stop_work: CancellationToken,
+        mut work: impl FnMut(CancellationToken) -> F,
+    ) where
+        F: Future<Output = T> + 'static,
+        T: 'static,
+    {
+        let options = &self.options;
+        let mut ticker = options.ticker();
+        let mut worker = RenewableWorker::new();
+        let mut last_tick = None;
+        let mut started = 0;
+
+        while !stop_loop.is_cancelled() && self.max_runs.is_none_or(|max| started < max) {
+            debug!("Waiting for next interval...");
+            let Some(mut tick) =
+                wait_reporting(&stop_loop, &mut worker, hooks, ticker.tick()).await
+            else {
+                break;
+            };
+
+            worker.reap();
+            hooks.finished(worker.take_finished());
+
+            if let Some(last_tick) = last_tick
+                && let Some(backoff) = hooks.backoff()
+            {
+                tick = last_tick + backoff;
+                if wait_reporting(&stop_loop, &mut worker, hooks, sleep_until(tick))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+                // Once the backoff ends, the next run is an interval after this one.
+                ticker.reset();
+            }
+            last_tick = Some(tick);
+
+            // The offset is from the tick's place on the interval, so it does not accumulate.
+            if !options.jitter().is_zero() {
+                let offset = rng.random_range(Duration::ZERO..options.jitter());
+                if wait_reporting(&stop_loop, &mut worker, hooks, sleep_until(tick + offset))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+            }
+
+            let token = stop_work.child_token();
+            let overlap = options.overlap_behavior();
+            if worker
+                .finish_and_renew(work(token.clone()), token, overlap, self.grace_period)
+                .await
+            {
+                debug!("Next interval reached. Work is running.");
+                started += 1;
+            } else {
+                warn!("Next interval reached. The previous work is still running.");
+            }
+
+            // The next interval starts once the work finishes.
+            if options.mode() == ScheduleMode::FixedDelay {
+                let Some(finished) = stop_loop.run_until_cancelled(worker.wait()).await else {
+                    break;
+                };
+                hooks.finished(finished);
+                ticker.reset();
+            }
+        }
+
+        // Try to wait for the work to complete, unless the `stop_work` token is cancelled
+        if let Some(finished) = stop_work.run_until_cancelled(worker.wait()).await {
+            hooks.finished(finished);
+        }
+
+        // Then cancel it, giving it the stop timeout to finish before aborting it
+        if let Some(finished) = worker.close_with_abort_after(self.stop_timeout()).await {
+            hooks.finished(finished);
+        }
+    }
+}
+
+/// Waits for `until` unless `stop` is cancelled first, handing the results of work to `hooks` as
+/// it finishes.
+async fn wait_reporting<T: 'static, O>(
+    stop: &CancellationToken,
+    worker: &mut RenewableWorker<T>,
+    hooks: &mut impl ScheduleHooks<T>,
+    until: impl Future<Output = O>,
+) -> Option<O> {
+    let mut until = pin!(until);
+    loop {
+        select! {
+            biased;
+            _ = stop.cancelled() => return None,
+            output = &mut until => return Some(output),
+            finished = worker.next_finished() => hooks.finished(finished),
+        }
+    }
+}
+
+/// Observes and steers the runs of `Scheduler::run_with`, whose work returns `T`.
+pub(crate) trait ScheduleHooks<T> {
+    /// Receives the results of runs that have finished, oldest first.
+    fn finished(&mut self, finished: Vec<Result<T, JoinError>>);
+
+    /// How long after the previous tick the next run waits instead of the interval, or `None`
+    /// to keep to the schedule.
+    fn backoff(&mut self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Logs the runs that panicked.
+struct LogPanics;
+
+impl<T> ScheduleHooks<T> for LogPanics {
+    fn finished(&mut self, finished: Vec<Result<T, JoinError>>) {
+        for e in finished.into_iter().filter_map(Result::err) {
+            if e.is_panic() {
+                error!("The scheduled work panicked. {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_time {
+    use super::{
+        ScheduleHooks, ScheduleKind, ScheduleMode, ScheduleOptions, ScheduleOptionsError,
+        ScheduleOverlap, Scheduler,
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+    use tokio::{
+        task::{JoinError, LocalSet},
+        time::{Instant, sleep},
+    };
+    use tokio_util::sync::CancellationToken;
+
+    /// What happened to each run, by the milliseconds since the scheduler started.
+    #[derive(Default)]
+    struct Runs {
+        start: Option<Instant>,
+        running: usize,
+        most_running: usize,
+        started: Vec<u64>,
+        cancelled: Vec<u64>,
+    }
+
+    impl Runs {
+        fn elapsed(&self) -> u64 {
+            self.start.unwrap().elapsed().as_millis() as u64
+        }
+    }
+
+    /// Runs work that never finishes unless cancelled every 100ms for 450ms, with `overlap`.
+    async fn schedule(overlap: ScheduleOverlap) -> Runs {
+        let options = ScheduleOptions::new(Duration::from_millis(100), overlap);
+        let scheduler = Scheduler::new(options)
+            .with_grace_period(Duration::from_millis(10))
+            .build();
+        let runs = Rc::new(RefCell::new(Runs {
+            start: Some(Instant::now()),
+            ..Default::default()
+        }));
+        let stop = CancellationToken::new();
+
+        let work = |token: CancellationToken| {
+            let runs = runs.clone();
+            async move {
+                {
+                    let mut runs = runs.borrow_mut();
+                    let elapsed = runs.elapsed();
+                    runs.started.push(elapsed);
+                    runs.running += 1;
+                    runs.most_running = runs.most_running.max(runs.running);
+                }
+                token.cancelled().await;
+                let mut runs = runs.borrow_mut();
+                let elapsed = runs.elapsed();
+                runs.cancelled.push(elapsed);
+                runs.running -= 1;
+            }
+        };
+        let stop_later = async {
+            sleep(Duration::from_millis(450)).await;
+            stop.cancel();
+        };
+        LocalSet::new()
+            .run_until(async { tokio::join!(scheduler.run(stop.clone(), work), stop_later) })
+            .await;
+
+        Rc::try_unwrap(runs).ok().unwrap().into_inner()
+    }
+
+    #[test]
+    fn builder_sets_options() {
+        let options = ScheduleOptions::builder()
+            .interval(Duration::from_secs(30))
+            .overlap(ScheduleOverlap::SkipNew { max: 2 })
+            .jitter(Duration::from_secs(1))
+            .mode(ScheduleMode::FixedDelay)
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            options.kind(),
+            ScheduleKind::Interval(interval) if *interval == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            options.overlap_behavior(),
+            ScheduleOverlap::SkipNew { max: 2 }
+        ));
+        assert_eq!(Duration::from_secs(1), options.jitter());
+        assert_eq!(ScheduleMode::FixedDelay, options.mode());
+    }
+
+    #[test]
+    fn builder_rejects_zero_interval() {
+        let e = ScheduleOptions::builder()
+            .interval(Duration::ZERO)
+            .build()
+            .unwrap_err();
+        assert!(matches!(e, ScheduleOptionsError::ZeroInterval));
+    }
+
+    #[test]
+    fn builder_rejects_zero_overlap_max() {
+        for overlap in [
+            ScheduleOverlap::SkipNew { max: 0 },
+            ScheduleOverlap::Overlap { max: 0 },
+        ] {
+            let e = ScheduleOptions::builder()
+                .overlap(overlap)
+                .build()
+                .unwrap_err();
+            assert!(matches!(e, ScheduleOptionsError::ZeroOverlapMax(_)));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn abort_previous_replaces_each_run() {
+        let runs = schedule(ScheduleOverlap::AbortPrevious).await;
+        assert_eq!(vec![0, 100, 200, 300, 400], runs.started);
+        assert_eq!(vec![100, 200, 300, 400, 450], runs.cancelled);
+        assert_eq!(1, runs.most_running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_new_replaces_run_after_max_skips() {
+        let runs = schedule(ScheduleOverlap::SkipNew { max: 2 }).await;
+        assert_eq!(vec![0, 300], runs.started);
+        assert_eq!(vec![300, 450], runs.cancelled);
+        assert_eq!(1, runs.most_running);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overlap_cancels_oldest_beyond_max() {
+        let runs = schedule(ScheduleOverlap::Overlap { max: 2 }).await;
+        assert_eq!(vec![0, 100, 200, 300, 400], runs.started);
+        assert_eq!(vec![200, 300, 400, 450, 450], runs.cancelled);
+        assert_eq!(2, runs.most_running);
+    }
+
+    /// Records when each result reached the hooks, by the milliseconds since they were created.
+    struct Received {
+        start: Instant,
+        results: Vec<(u64, usize)>,
+    }
+
+    impl ScheduleHooks<usize> for Received {
+        fn finished(&mut self, finished: Vec<Result<usize, JoinError>>) {
+            let elapsed = self.start.elapsed().as_millis() as u64;
+            for result in finished {
+                self.results.push((elapsed, result.unwrap()));
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hooks_receive_results_when_runs_finish() {
+        let options = ScheduleOptions::new(Duration::from_millis(100), ScheduleOverlap::default());
+        let scheduler = Scheduler::new(options).with_max_runs(2).build();
+        let mut hooks = Received {
+            start: Instant::now(),
+            results: vec![],
+        };
+        let stop = CancellationToken::new();
+        let mut run = 0;
+
+        let work = |_| {
+            run += 1;
+            let run = run;
+            async move {
+                sleep(Duration::from_millis(30)).await;
+                run
+            }
+        };
+        LocalSet::new()
+            .run_until(scheduler.run_with(
+                StdRng::seed_from_u64(0),
+                &mut hooks,
+                stop.clone(),
+                stop,
+                work,
+            ))
+            .await;
+
+        assert_eq!(vec![(30, 1), (130, 2)], hooks.results);
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn cron_next_run() {
+        use chrono::{TimeZone, Utc};
+
+        let next = |expression: &str, now| {
+            ScheduleOptions::cron(expression, ScheduleOverlap::default())
+                .unwrap()
+                .next_run_after(&now)
+                .unwrap()
+        };
+
+        // Wednesday, October 15th 2025.
+        let wednesday = Utc.with_ymd_and_hms(2025, 10, 15, 9, 30, 0).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2025, 10, 15, 10, 0, 0).unwrap(),
+            next("0 0 * * * *", wednesday)
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2025, 10, 16, 9, 0, 0).unwrap(),
+            next("0 0 9 * * Mon-Fri", wednesday)
+        );
+
+        // A time that has passed today runs next on the following matching day.
+        let friday = Utc.with_ymd_and_hms(2025, 10, 17, 9, 0, 0).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2025, 10, 20, 9, 0, 0).unwrap(),
+            next("0 0 9 * * Mon-Fri", friday)
+        );
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn cron_without_future_runs() {
+        let options =
+            ScheduleOptions::cron("0 0 0 1 1 * 2020", ScheduleOverlap::default()).unwrap();
+        assert_eq!(None, options.next_run_after(&chrono::Utc::now()));
+        assert_eq!(Duration::ZERO, options.interval());
+
+        assert!(ScheduleOptions::cron("every monday", ScheduleOverlap::default()).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Sends a real SIGTERM to the process, so it runs in its own test binary where no other test
+//! is listening for signals.
+#![cfg(unix)]
+
+use rabbit_eye::lifetime::AppLifetime;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+
+#[tokio::test]
+async fn sigterm_begins_natural_stop() {
+    // Listening for SIGTERM here replaces its default action, so the signal cannot end the test
+    // process even before the lifetime's own handler is registered.
+    let _terminate = signal(SignalKind::terminate()).unwrap();
+    let life = AppLifetime::with_timeouts(Duration::from_secs(5), Duration::from_secs(5)).unwrap();
+
+    // A signal sent before the lifetime registers its handler is missed, so keep sending it
+    // until the natural stop begins.
+    let natural = life.natural();
+    let stopped = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let status = std::process::Command::new("kill")
+                .args(["-TERM", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            if natural
+                .run_until_cancelled(tokio::time::sleep(Duration::from_millis(10)))
+                .await
+                .is_none()
+            {
+                break;
+            }
+        }
+    })
+    .await;
+
+    stopped.expect("SIGTERM did not begin the natural stop");
+    assert!(!life.graceful().is_cancelled());
+}